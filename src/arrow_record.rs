@@ -0,0 +1,267 @@
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_ticker::BybitTicker;
+use crate::bybit_trades::BybitTrades;
+use crate::parser::Decimal128;
+use anyhow::Result;
+use arrow_array::builder::{Decimal128Builder, ListBuilder, StringBuilder};
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, TimestampMillisecondArray,
+    UInt64Array,
+};
+use arrow_schema::DataType;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+fn ts(values: impl Iterator<Item = OffsetDateTime>) -> ArrayRef {
+    let millis: Vec<i64> = values
+        .map(|t| (t.unix_timestamp_nanos() / 1_000_000) as i64)
+        .collect();
+    Arc::new(TimestampMillisecondArray::from(millis).with_timezone("UTC"))
+}
+
+fn opt_ts(values: impl Iterator<Item = Option<OffsetDateTime>>) -> ArrayRef {
+    let millis: Vec<Option<i64>> = values
+        .map(|t| t.map(|t| (t.unix_timestamp_nanos() / 1_000_000) as i64))
+        .collect();
+    Arc::new(TimestampMillisecondArray::from(millis).with_timezone("UTC"))
+}
+
+/// `Decimal128(18)` maps to Arrow `Decimal128(38, 18)`.
+fn dec(values: impl Iterator<Item = Decimal128>) -> ArrayRef {
+    opt_dec(values.map(Some))
+}
+
+fn opt_dec(values: impl Iterator<Item = Option<Decimal128>>) -> ArrayRef {
+    let bits: Decimal128Array = values.map(|v| v.map(|v| v.into_bits())).collect();
+    Arc::new(
+        bits.with_precision_and_scale(38, 18)
+            .expect("valid decimal precision"),
+    )
+}
+
+fn dec_list<'a>(values: impl Iterator<Item = &'a [Decimal128]>) -> ArrayRef {
+    let mut builder =
+        ListBuilder::new(Decimal128Builder::new().with_data_type(DataType::Decimal128(38, 18)));
+    for list in values {
+        for value in list {
+            builder.values().append_value(value.into_bits());
+        }
+        builder.append(true);
+    }
+    Arc::new(builder.finish())
+}
+
+fn string<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+    Arc::new(values.map(Some).collect::<StringArray>())
+}
+
+fn opt_string<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+    Arc::new(values.collect::<StringArray>())
+}
+
+fn string_list<'a>(values: impl Iterator<Item = &'a [String]>) -> ArrayRef {
+    let mut builder = ListBuilder::new(StringBuilder::new());
+    for list in values {
+        for value in list {
+            builder.values().append_value(value);
+        }
+        builder.append(true);
+    }
+    Arc::new(builder.finish())
+}
+
+/// A record type that can be encoded as an Arrow batch, with the columns of
+/// its ClickHouse table.
+pub trait ArrowRecord: Clone {
+    const TABLE: &'static str;
+    fn symbol(&self) -> &str;
+    fn timestamp(&self) -> OffsetDateTime;
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch>;
+}
+
+impl ArrowRecord for BybitTrades {
+    const TABLE: &'static str = "trades_raw_ml";
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn timestamp(&self) -> OffsetDateTime {
+        self.server_timestamp
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch> {
+        let r = || rows.iter();
+        Ok(RecordBatch::try_from_iter([
+            ("server_timestamp", ts(r().map(|t| t.server_timestamp))),
+            ("received_timestamp", ts(r().map(|t| t.received_timestamp))),
+            ("trade_timestamp", ts(r().map(|t| t.trade_timestamp))),
+            ("symbol", string(r().map(|t| t.symbol.as_ref()))),
+            ("trade_id", string(r().map(|t| t.trade_id.as_str()))),
+            ("side", string(r().map(|t| t.side.as_ref()))),
+            ("price", dec(r().map(|t| t.price))),
+            ("volume", dec(r().map(|t| t.volume))),
+            (
+                "tick_direction",
+                string(r().map(|t| t.tick_direction.as_ref())),
+            ),
+            (
+                "is_block_trade",
+                Arc::new(
+                    r().map(|t| Some(t.is_block_trade))
+                        .collect::<BooleanArray>(),
+                ) as ArrayRef,
+            ),
+            (
+                "is_rpi",
+                Arc::new(r().map(|t| Some(t.is_rpi)).collect::<BooleanArray>()),
+            ),
+            (
+                "seq",
+                Arc::new(UInt64Array::from_iter_values(r().map(|t| t.seq))),
+            ),
+            ("exchange", string(r().map(|t| t.exchange.as_ref()))),
+        ])?)
+    }
+}
+
+impl ArrowRecord for BybitOrderbook {
+    const TABLE: &'static str = "orderbook_raw_ml";
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn timestamp(&self) -> OffsetDateTime {
+        self.server_timestamp
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch> {
+        let r = || rows.iter();
+        Ok(RecordBatch::try_from_iter([
+            ("server_timestamp", ts(r().map(|o| o.server_timestamp))),
+            ("received_timestamp", ts(r().map(|o| o.received_timestamp))),
+            ("client_timestamp", ts(r().map(|o| o.client_timestamp))),
+            ("symbol", string(r().map(|o| o.symbol.as_ref()))),
+            ("side", string(r().map(|o| o.side.as_ref()))),
+            ("price", dec(r().map(|o| o.price))),
+            ("volume", dec(r().map(|o| o.volume))),
+            (
+                "update",
+                Arc::new(UInt64Array::from_iter_values(r().map(|o| o.update))) as ArrayRef,
+            ),
+            ("exchange", string(r().map(|o| o.exchange.as_ref()))),
+        ])?)
+    }
+}
+
+impl ArrowRecord for BybitOrderbookLevels {
+    const TABLE: &'static str = "orderbook_levels_raw_ml";
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn timestamp(&self) -> OffsetDateTime {
+        self.server_timestamp
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch> {
+        let r = || rows.iter();
+        Ok(RecordBatch::try_from_iter([
+            ("server_timestamp", ts(r().map(|o| o.server_timestamp))),
+            ("received_timestamp", ts(r().map(|o| o.received_timestamp))),
+            ("client_timestamp", ts(r().map(|o| o.client_timestamp))),
+            ("symbol", string(r().map(|o| o.symbol.as_ref()))),
+            (
+                "update",
+                Arc::new(UInt64Array::from_iter_values(r().map(|o| o.update))) as ArrayRef,
+            ),
+            ("bid_prices", dec_list(r().map(|o| o.bid_prices.as_slice()))),
+            ("bid_sizes", dec_list(r().map(|o| o.bid_sizes.as_slice()))),
+            ("ask_prices", dec_list(r().map(|o| o.ask_prices.as_slice()))),
+            ("ask_sizes", dec_list(r().map(|o| o.ask_sizes.as_slice()))),
+            ("exchange", string(r().map(|o| o.exchange.as_ref()))),
+        ])?)
+    }
+}
+
+impl ArrowRecord for BybitTicker {
+    const TABLE: &'static str = "ticker_raw_ml";
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn timestamp(&self) -> OffsetDateTime {
+        self.server_timestamp
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch> {
+        let r = || rows.iter();
+        Ok(RecordBatch::try_from_iter([
+            ("server_timestamp", ts(r().map(|t| t.server_timestamp))),
+            ("received_timestamp", ts(r().map(|t| t.received_timestamp))),
+            (
+                "cross_sequence",
+                Arc::new(UInt64Array::from_iter_values(r().map(|t| t.cross_sequence))) as ArrayRef,
+            ),
+            ("symbol", string(r().map(|t| t.symbol.as_ref()))),
+            (
+                "tick_direction",
+                string(r().map(|t| t.tick_direction.as_ref())),
+            ),
+            ("price_24h_pcnt", dec(r().map(|t| t.price_24h_pcnt))),
+            ("last_price", dec(r().map(|t| t.last_price))),
+            ("prev_price_24h", dec(r().map(|t| t.prev_price_24h))),
+            ("high_price_24h", dec(r().map(|t| t.high_price_24h))),
+            ("low_price_24h", dec(r().map(|t| t.low_price_24h))),
+            ("prev_price_1h", dec(r().map(|t| t.prev_price_1h))),
+            ("mark_price", dec(r().map(|t| t.mark_price))),
+            ("index_price", dec(r().map(|t| t.index_price))),
+            ("open_interest", dec(r().map(|t| t.open_interest))),
+            (
+                "open_interest_value",
+                dec(r().map(|t| t.open_interest_value)),
+            ),
+            ("turnover_24h", dec(r().map(|t| t.turnover_24h))),
+            ("volume_24h", dec(r().map(|t| t.volume_24h))),
+            ("next_funding_time", ts(r().map(|t| t.next_funding_time))),
+            ("funding_rate", dec(r().map(|t| t.funding_rate))),
+            ("bid1_price", dec(r().map(|t| t.bid1_price))),
+            ("bid1_size", dec(r().map(|t| t.bid1_size))),
+            ("ask1_price", dec(r().map(|t| t.ask1_price))),
+            ("ask1_size", dec(r().map(|t| t.ask1_size))),
+            ("delivery_time", opt_ts(r().map(|t| t.delivery_time))),
+            ("basis_rate", opt_dec(r().map(|t| t.basis_rate))),
+            (
+                "delivery_fee_rate",
+                opt_dec(r().map(|t| t.delivery_fee_rate)),
+            ),
+            (
+                "predicted_delivery_price",
+                opt_dec(r().map(|t| t.predicted_delivery_price)),
+            ),
+            ("pre_open_price", opt_dec(r().map(|t| t.pre_open_price))),
+            ("pre_qty", opt_dec(r().map(|t| t.pre_qty))),
+            (
+                "cur_pre_listing_phase",
+                opt_string(r().map(|t| t.cur_pre_listing_phase.as_deref())),
+            ),
+            (
+                "funding_interval_hour",
+                opt_string(r().map(|t| t.funding_interval_hour.as_deref())),
+            ),
+            ("funding_cap", opt_dec(r().map(|t| t.funding_cap))),
+            ("basis_rate_year", opt_dec(r().map(|t| t.basis_rate_year))),
+            (
+                "is_stale",
+                Arc::new(r().map(|t| Some(t.is_stale)).collect::<BooleanArray>()),
+            ),
+            (
+                "stale_fields",
+                string_list(r().map(|t| t.stale_fields.as_slice())),
+            ),
+        ])?)
+    }
+}
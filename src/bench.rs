@@ -0,0 +1,158 @@
+//! `bench-parse`: runs the recordings selected by `[replay]` through the parser
+//! as fast as possible and reports throughput. Built with the `alloc-stats`
+//! feature it also counts heap allocations.
+
+use crate::config::{ChannelConfig, Config};
+use crate::dispatch::worker_of;
+use crate::parser::{BybitOTT, Frame, ParserContext, async_parse};
+use crate::queue;
+use crate::recorder::RawFrame;
+use crate::replay::replay_files;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::channel;
+use tokio::task::JoinSet;
+use tracing::info;
+
+#[cfg(feature = "alloc-stats")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+    pub static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+}
+
+/// Allocations and allocated bytes since start.
+fn allocations() -> Option<(u64, u64)> {
+    #[cfg(feature = "alloc-stats")]
+    {
+        use std::sync::atomic::Ordering;
+        Some((
+            counting::ALLOCATIONS.load(Ordering::Relaxed),
+            counting::ALLOCATED_BYTES.load(Ordering::Relaxed),
+        ))
+    }
+    #[cfg(not(feature = "alloc-stats"))]
+    None
+}
+
+/// Reads every frame up front, so decompression is not part of the measurement.
+fn load_frames(config: &Config) -> Result<Vec<Frame>> {
+    let mut frames = Vec::new();
+    for path in replay_files(&config.replay)? {
+        let file =
+            File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut reader = zstd::stream::read::Decoder::new(file)?;
+        while let Some(raw) = RawFrame::read_from(&mut reader)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+        {
+            frames.push(raw.into_frame());
+        }
+    }
+    anyhow::ensure!(!frames.is_empty(), "No recorded frames to parse");
+    Ok(frames)
+}
+
+/// Frames are split between the `[parser] workers` up front, each worker
+/// reports its own throughput when it is done.
+pub async fn bench_parse(config: &Config) -> Result<()> {
+    let frames = load_frames(config)?;
+    let (count, bytes) = (
+        frames.len() as u64,
+        frames.iter().map(|f| f.message.len() as u64).sum::<u64>(),
+    );
+    let workers = config.parser.workers;
+    let mut shards: Vec<Vec<Frame>> = (0..workers).map(|_| Vec::new()).collect();
+    for frame in frames {
+        shards[worker_of(&frame.message, workers)].push(frame);
+    }
+
+    let (writer_tx, mut writer_rx) = queue::channel::<BybitOTT>("writer", &config.channels.writer)?;
+    let drain = tokio::spawn(async move {
+        let mut records = 0u64;
+        while writer_rx.recv().await.is_some() {
+            records += 1;
+        }
+        records
+    });
+    let (tx, mut reconnect_rx) = channel::<String>(100);
+    tokio::spawn(async move { while reconnect_rx.recv().await.is_some() {} });
+
+    let before = allocations();
+    let started = Instant::now();
+    let mut parsers = JoinSet::new();
+    for (worker, shard) in shards.into_iter().enumerate() {
+        let channel = ChannelConfig {
+            capacity: shard.len().max(1),
+            ..ChannelConfig::default()
+        };
+        let (parser_tx, mut parser_rx) =
+            queue::channel::<Frame>(&format!("parser_{}", worker), &channel)?;
+        for frame in shard {
+            parser_tx.send(frame).await?;
+        }
+        drop(parser_tx);
+        let ctx = ParserContext::new(
+            worker,
+            Duration::MAX,
+            tx.clone(),
+            writer_tx.clone(),
+            config.ticker.clone(),
+            config.orderbook.clone(),
+        );
+        parsers.spawn(async move { async_parse(ctx, &mut parser_rx).await });
+    }
+    drop((tx, writer_tx));
+    while let Some(parsed) = parsers.join_next().await {
+        parsed.context("Parser worker panicked")??;
+    }
+    let records = drain.await.context("Drain task panicked")?;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    info!(
+        frames = count,
+        records,
+        workers,
+        secs = format!("{:.2}", elapsed),
+        frames_per_sec = format!("{:.0}", count as f64 / elapsed),
+        mb_per_sec = format!("{:.1}", bytes as f64 / elapsed / 1e6),
+        "Parse benchmark:"
+    );
+    match (before, allocations()) {
+        (Some((allocs_before, bytes_before)), Some((allocs_after, bytes_after))) => info!(
+            allocations_per_frame = format!(
+                "{:.1}",
+                (allocs_after - allocs_before) as f64 / count as f64
+            ),
+            allocated_bytes_per_frame = (bytes_after - bytes_before) / count,
+            "Parse benchmark allocations:"
+        ),
+        _ => info!("Build with --features alloc-stats to count allocations."),
+    }
+    Ok(())
+}
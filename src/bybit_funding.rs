@@ -0,0 +1,116 @@
+use crate::bybit_ticker::{BybitTicker, BybitTickerData};
+use crate::parser::Decimal128;
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// A funding settlement, detected when a symbol's `next_funding_time` rolls forward.
+/// Rate and prices are the last values seen before the roll.
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitFundingEvent {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub funding_time: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub next_funding_time: OffsetDateTime,
+    pub symbol: String,
+    pub funding_rate: Decimal128,
+    pub mark_price: Decimal128,
+    pub index_price: Decimal128,
+    pub funding_interval_hour: Option<String>,
+    pub funding_cap: Option<Decimal128>,
+}
+
+impl BybitFundingEvent {
+    /// Settlement candidate built from the cached ticker before `data` is applied.
+    /// Only a message that carries a `nextFundingTime` can roll it forward: without
+    /// one, e.g. for dated futures, a snapshot falls back to its own timestamp.
+    pub fn pending(previous: &BybitTicker, data: &BybitTickerData) -> Option<Self> {
+        data.next_funding_time.as_ref().filter(|s| !s.is_empty())?;
+        Some(Self {
+            server_timestamp: previous.server_timestamp,
+            received_timestamp: previous.received_timestamp,
+            funding_time: previous.next_funding_time,
+            next_funding_time: previous.next_funding_time,
+            symbol: previous.symbol.clone(),
+            funding_rate: previous.funding_rate,
+            mark_price: previous.mark_price,
+            index_price: previous.index_price,
+            funding_interval_hour: previous.funding_interval_hour.clone(),
+            funding_cap: previous.funding_cap,
+        })
+    }
+
+    /// Returns the settlement if `current` moved `next_funding_time` past the pending one.
+    pub fn settled_by(mut self, current: &BybitTicker) -> Option<Self> {
+        if current.next_funding_time <= self.funding_time {
+            return None;
+        }
+        self.server_timestamp = current.server_timestamp;
+        self.received_timestamp = current.received_timestamp;
+        self.next_funding_time = current.next_funding_time;
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bybit_ticker::TickerCache;
+
+    /// Applies a message like the parser does and returns the settlement it caused.
+    async fn apply(
+        cache: &mut TickerCache,
+        ttype: &str,
+        server_secs: i64,
+        json: &str,
+    ) -> Option<BybitFundingEvent> {
+        let data: BybitTickerData = serde_json::from_str(json).unwrap();
+        let pending = cache
+            .ticker
+            .get(&data.symbol)
+            .and_then(|previous| BybitFundingEvent::pending(previous, &data));
+        let time = OffsetDateTime::from_unix_timestamp(server_secs).unwrap();
+        let current = BybitTicker::parse_bybit_ticker(time, time, data, 1, ttype, false, cache)
+            .await
+            .unwrap();
+        pending.and_then(|f| f.settled_by(&current))
+    }
+
+    const FIRST: &str =
+        r#"{"symbol":"BTCUSDT","nextFundingTime":"1700006400000","fundingRate":"0.0001"}"#;
+    const SECOND: &str =
+        r#"{"symbol":"BTCUSDT","nextFundingTime":"1700035200000","fundingRate":"0.0002"}"#;
+
+    #[tokio::test]
+    async fn a_settlement_fires_once() {
+        let cache = &mut TickerCache::new();
+        assert_eq!(apply(cache, "snapshot", 1_700_000_000, FIRST).await, None);
+        let delta = r#"{"symbol":"BTCUSDT","markPrice":"65000"}"#;
+        assert_eq!(apply(cache, "delta", 1_700_000_001, delta).await, None);
+
+        let funding = apply(cache, "delta", 1_700_006_401, SECOND).await.unwrap();
+        assert_eq!(funding.funding_time.unix_timestamp(), 1_700_006_400);
+        assert_eq!(funding.next_funding_time.unix_timestamp(), 1_700_035_200);
+        assert_eq!(funding.funding_rate.to_string(), "0.0001");
+
+        assert_eq!(apply(cache, "delta", 1_700_006_402, SECOND).await, None);
+        assert_eq!(apply(cache, "delta", 1_700_006_403, delta).await, None);
+    }
+
+    #[tokio::test]
+    async fn reconnect_snapshots_do_not_settle() {
+        let cache = &mut TickerCache::new();
+        assert_eq!(apply(cache, "snapshot", 1_700_000_000, FIRST).await, None);
+        assert_eq!(apply(cache, "snapshot", 1_700_000_100, FIRST).await, None);
+
+        // Dated futures have no funding, so every snapshot falls back to its
+        // own timestamp.
+        let future = r#"{"symbol":"BTCUSDT-29MAR24","nextFundingTime":""}"#;
+        assert_eq!(apply(cache, "snapshot", 1_700_000_000, future).await, None);
+        assert_eq!(apply(cache, "snapshot", 1_700_000_100, future).await, None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::parser::Decimal128;
+use anyhow::{Context, Result};
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use time::OffsetDateTime;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BybitKlineData {
+    start: u64,
+    end: u64,
+    interval: String,
+    open: String,
+    close: String,
+    high: String,
+    low: String,
+    volume: String,
+    turnover: String,
+    confirm: bool,
+    timestamp: u64,
+}
+
+/// Exchange candle. In-progress updates (`confirm = false`) and the final candle
+/// are kept as separate rows in `kline_raw_ml`.
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitKline {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub kline_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub start_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub end_timestamp: OffsetDateTime,
+    pub symbol: String,
+    pub interval: String,
+    pub open: Decimal128,
+    pub high: Decimal128,
+    pub low: Decimal128,
+    pub close: Decimal128,
+    pub volume: Decimal128,
+    pub turnover: Decimal128,
+    pub confirm: bool,
+    pub exchange: String,
+}
+
+impl BybitKline {
+    /// Splits `kline.{interval}.{symbol}` into interval and symbol.
+    pub fn parse_topic(topic: &str) -> Result<(&str, &str)> {
+        match topic.split('.').collect::<Vec<_>>().as_slice() {
+            ["kline", interval, symbol] => Ok((interval, symbol)),
+            _ => anyhow::bail!("Invalid kline topic: {}", topic),
+        }
+    }
+
+    pub async fn parse_bybit_kline(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        topic: &str,
+        kline_data: Vec<BybitKlineData>,
+    ) -> Result<Vec<Self>> {
+        let (interval, symbol) = Self::parse_topic(topic)?;
+        kline_data
+            .iter()
+            .map(|kd| {
+                Self::parse_bybit_kline_data(
+                    kd,
+                    interval,
+                    symbol,
+                    server_timestamp,
+                    received_timestamp,
+                )
+            })
+            .collect()
+    }
+
+    fn parse_bybit_kline_data(
+        kd: &BybitKlineData,
+        interval: &str,
+        symbol: &str,
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+    ) -> Result<Self> {
+        if kd.interval != interval {
+            anyhow::bail!(
+                "Kline interval mismatch for {}: topic {} vs data {}",
+                symbol,
+                interval,
+                kd.interval
+            );
+        }
+        let to_time = |ms: u64| {
+            OffsetDateTime::from_unix_timestamp_nanos((ms as i128) * 1_000_000)
+                .map_err(|_| anyhow::anyhow!("Kline timestamp out of range for {}: {}", symbol, ms))
+        };
+        let to_dec = |name: &str, value: &str| {
+            Decimal128::from_str(value).with_context(|| {
+                format!(
+                    "Invalid {} '{}' in kline {} {}",
+                    name, value, interval, symbol
+                )
+            })
+        };
+
+        Ok(Self {
+            server_timestamp,
+            received_timestamp,
+            kline_timestamp: to_time(kd.timestamp)?,
+            start_timestamp: to_time(kd.start)?,
+            end_timestamp: to_time(kd.end)?,
+            symbol: symbol.to_string(),
+            interval: interval.to_string(),
+            open: to_dec("open", &kd.open)?,
+            high: to_dec("high", &kd.high)?,
+            low: to_dec("low", &kd.low)?,
+            close: to_dec("close", &kd.close)?,
+            volume: to_dec("volume", &kd.volume)?,
+            turnover: to_dec("turnover", &kd.turnover)?,
+            confirm: kd.confirm,
+            exchange: "Bybit".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(confirm: bool) -> Result<Vec<BybitKline>> {
+        let json = format!(
+            r#"[{{"start":1700000000000,"end":1700000059999,"interval":"1","open":"65000",
+            "close":"65010.5","high":"65020","low":"64990","volume":"12.5","turnover":"812000",
+            "confirm":{},"timestamp":1700000030000}}]"#,
+            confirm
+        );
+        let data: Vec<BybitKlineData> = serde_json::from_str(&json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_030).unwrap();
+        BybitKline::parse_bybit_kline(time, time, "kline.1.BTCUSDT", data).await
+    }
+
+    #[tokio::test]
+    async fn klines_parse() {
+        let klines = parse(false).await.unwrap();
+        let kline = &klines[0];
+        assert_eq!(kline.symbol, "BTCUSDT");
+        assert_eq!(kline.interval, "1");
+        assert_eq!(kline.start_timestamp.unix_timestamp(), 1_700_000_000);
+        assert_eq!(kline.kline_timestamp.unix_timestamp(), 1_700_000_030);
+        assert_eq!(kline.close, Decimal128::from_str("65010.5").unwrap());
+        assert!(!kline.confirm);
+
+        assert!(parse(true).await.unwrap()[0].confirm);
+    }
+
+    #[tokio::test]
+    async fn topic_and_data_intervals_must_match() {
+        let data: Vec<BybitKlineData> = serde_json::from_str(
+            r#"[{"start":0,"end":59999,"interval":"5","open":"1","close":"1","high":"1",
+            "low":"1","volume":"1","turnover":"1","confirm":true,"timestamp":0}]"#,
+        )
+        .unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(0).unwrap();
+        assert!(
+            BybitKline::parse_bybit_kline(time, time, "kline.1.BTCUSDT", data)
+                .await
+                .is_err()
+        );
+        assert!(BybitKline::parse_topic("kline.1").is_err());
+    }
+}
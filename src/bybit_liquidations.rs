@@ -0,0 +1,136 @@
+use crate::parser::{Decimal128, intern};
+use anyhow::{Context, Result};
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::str::FromStr;
+use time::OffsetDateTime;
+
+/// Borrows from the frame; Bybit never escapes these strings.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BybitLiquidationData<'a> {
+    #[serde(rename = "T")]
+    liquidation_timestamp: u64,
+    #[serde(rename = "s", borrow)]
+    symbol: Cow<'a, str>,
+    #[serde(rename = "S", borrow)]
+    side: Cow<'a, str>,
+    #[serde(rename = "v", borrow)]
+    volume: Cow<'a, str>,
+    #[serde(rename = "p", borrow)]
+    price: Cow<'a, str>,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitLiquidations {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub liquidation_timestamp: OffsetDateTime,
+    pub symbol: Cow<'static, str>,
+    pub side: Cow<'static, str>,
+    pub price: Decimal128,
+    pub volume: Decimal128,
+    pub exchange: Cow<'static, str>,
+}
+
+impl BybitLiquidations {
+    pub async fn parse_bybit_liquidations(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        liquidation_data: Vec<BybitLiquidationData<'_>>,
+    ) -> Result<Vec<Self>> {
+        liquidation_data
+            .iter()
+            .map(|liq| {
+                Self::parse_bybit_liquidation_data(liq, server_timestamp, received_timestamp)
+            })
+            .collect()
+    }
+
+    fn parse_bybit_liquidation_data(
+        ld: &BybitLiquidationData<'_>,
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+    ) -> Result<Self> {
+        let liquidation_timestamp = OffsetDateTime::from_unix_timestamp_nanos(
+            (ld.liquidation_timestamp as i128) * 1_000_000,
+        )
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Liquidation timestamp out of range for {}: {}",
+                ld.symbol,
+                ld.liquidation_timestamp
+            )
+        })?;
+
+        Ok(Self {
+            server_timestamp,
+            received_timestamp,
+            liquidation_timestamp,
+            symbol: Cow::Borrowed(intern(&ld.symbol)),
+            side: match &*ld.side {
+                "Buy" => Cow::Borrowed("Buy"),
+                "Sell" => Cow::Borrowed("Sell"),
+                side => anyhow::bail!("Invalid side '{}' in liquidation for {}", side, ld.symbol),
+            },
+            price: Decimal128::from_str(&ld.price).with_context(|| {
+                format!(
+                    "Invalid price '{}' in liquidation for {}",
+                    ld.price, ld.symbol
+                )
+            })?,
+            volume: Decimal128::from_str(&ld.volume).with_context(|| {
+                format!(
+                    "Invalid volume '{}' in liquidation for {}",
+                    ld.volume, ld.symbol
+                )
+            })?,
+            exchange: Cow::Borrowed("Bybit"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(json: &str) -> Result<Vec<BybitLiquidations>> {
+        let data: Vec<BybitLiquidationData> = serde_json::from_str(json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitLiquidations::parse_bybit_liquidations(time, time, data).await
+    }
+
+    #[tokio::test]
+    async fn liquidations_parse() {
+        let liquidations =
+            parse(r#"[{"T":1700000000123,"s":"BTCUSDT","S":"Sell","v":"0.5","p":"6500\u0030.5"}]"#)
+                .await
+                .unwrap();
+        assert_eq!(liquidations[0].symbol, "BTCUSDT");
+        assert_eq!(liquidations[0].side, "Sell");
+        assert_eq!(
+            liquidations[0].price,
+            Decimal128::from_str("65000.5").unwrap()
+        );
+        assert_eq!(liquidations[0].volume, Decimal128::from_str("0.5").unwrap());
+        assert_eq!(
+            liquidations[0].liquidation_timestamp.unix_timestamp_nanos(),
+            1_700_000_000_123_000_000
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_sides_are_rejected() {
+        let error = parse(r#"[{"T":1700000000123,"s":"BTCUSDT","S":"Hold","v":"0.5","p":"1"}]"#)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid side 'Hold'"),
+            "{}",
+            error
+        );
+    }
+}
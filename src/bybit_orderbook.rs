@@ -1,20 +1,33 @@
-use crate::parser::Decimal128;
+use crate::config::OrderbookLayout;
+use crate::parser::{BybitOTT, Decimal128, JsonStr, intern};
 use anyhow::{Context, Result};
 use clickhouse::Row;
+use fixnum::ops::Zero;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use time::OffsetDateTime;
+use tracing::{info, warn};
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct OrderbookCache {
     pub orderbook: HashMap<String, BybitCachedOrderbook>,
+    /// Levels of the message being applied, reused across messages.
+    #[serde(skip)]
+    levels: Vec<(Decimal128, Decimal128)>,
+    /// Symbols whose book missed an update. Their deltas are skipped until a
+    /// snapshot arrives.
+    #[serde(skip)]
+    resyncing: HashSet<String>,
 }
 
 impl OrderbookCache {
     pub fn new() -> Self {
         Self {
             orderbook: HashMap::new(),
+            levels: Vec::new(),
+            resyncing: HashSet::new(),
         }
     }
 }
@@ -29,7 +42,8 @@ pub struct BybitCachedOrderbook {
 }
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct BybitOrderbookCachedData {
-    pub symbol: String,
+    /// Interned once when the book is created, rows share it.
+    pub symbol: Cow<'static, str>,
     pub bid: HashMap<Decimal128, Decimal128>,
     pub ask: HashMap<Decimal128, Decimal128>,
     pub update: u64,
@@ -43,141 +57,219 @@ pub struct BybitOrderbook {
     pub received_timestamp: OffsetDateTime,
     #[serde(with = "clickhouse::serde::time::datetime64::millis")]
     pub client_timestamp: OffsetDateTime,
-    pub symbol: String,
-    pub side: &'static str,
+    pub symbol: Cow<'static, str>,
+    pub side: Cow<'static, str>,
     pub price: Decimal128,
     pub volume: Decimal128,
     pub update: u64,
-    pub exchange: &'static str,
+    pub exchange: Cow<'static, str>,
 }
 
+/// The whole book after one update, each side sorted from the best price
+/// outwards: bids descending, asks ascending.
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitOrderbookLevels {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub client_timestamp: OffsetDateTime,
+    pub symbol: Cow<'static, str>,
+    pub update: u64,
+    pub bid_prices: Vec<Decimal128>,
+    pub bid_sizes: Vec<Decimal128>,
+    pub ask_prices: Vec<Decimal128>,
+    pub ask_sizes: Vec<Decimal128>,
+    pub exchange: Cow<'static, str>,
+}
+
+impl BybitOrderbookLevels {
+    fn from_cache(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        client_timestamp: OffsetDateTime,
+        orderbook_cache: &BybitCachedOrderbook,
+    ) -> Self {
+        let cache_data = &orderbook_cache.data;
+        let mut bid: Vec<_> = cache_data.bid.iter().collect();
+        bid.sort_unstable_by(|a, b| b.0.cmp(a.0));
+        let mut ask: Vec<_> = cache_data.ask.iter().collect();
+        ask.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        Self {
+            server_timestamp,
+            received_timestamp,
+            client_timestamp,
+            symbol: cache_data.symbol.clone(),
+            update: cache_data.update,
+            bid_prices: bid.iter().map(|(price, _)| **price).collect(),
+            bid_sizes: bid.iter().map(|(_, volume)| **volume).collect(),
+            ask_prices: ask.iter().map(|(price, _)| **price).collect(),
+            ask_sizes: ask.iter().map(|(_, volume)| **volume).collect(),
+            exchange: Cow::Borrowed("Bybit"),
+        }
+    }
+}
+
+/// Borrows from the frame; prices and sizes are parsed straight from the
+/// JSON strings.
 #[derive(Deserialize, Debug, PartialEq, Clone)]
-pub struct BybitOrderbookData {
-    #[serde(rename = "s")]
-    symbol: String,
-    #[serde(rename = "b")]
-    bid: Vec<[String; 2]>,
-    #[serde(rename = "a")]
-    ask: Vec<[String; 2]>,
+pub struct BybitOrderbookData<'a> {
+    #[serde(rename = "s", borrow)]
+    symbol: Cow<'a, str>,
+    #[serde(rename = "b", borrow)]
+    bid: Vec<[JsonStr<'a>; 2]>,
+    #[serde(rename = "a", borrow)]
+    ask: Vec<[JsonStr<'a>; 2]>,
     #[serde(rename = "u")]
     update: u64,
 }
 
 impl BybitOrderbook {
+    /// Applies the message to the cached book and returns the whole book in
+    /// `layout`, or `None` when a gap was detected and a resync of `topic`
+    /// requested.
+    #[allow(clippy::too_many_arguments)]
     pub async fn parse_bybit_orderbook(
         server_timestamp: OffsetDateTime,
         received_timestamp: OffsetDateTime,
         client_timestamp: Option<u64>,
-        orderbook: BybitOrderbookData,
+        topic: &str,
+        orderbook: BybitOrderbookData<'_>,
         // orderbook_inserter: &mut Inserter<Self>,
         ttype: &String,
-        tx: tokio::sync::mpsc::Sender<String>,
+        tx: &tokio::sync::mpsc::Sender<String>,
         orderbook_cache: &mut OrderbookCache,
-    ) -> Result<Vec<Self>> {
+        layout: OrderbookLayout,
+    ) -> Result<Option<BybitOTT>> {
         let client_timestamp = OffsetDateTime::from_unix_timestamp_nanos(
             (client_timestamp.expect("unable to parse client timestamp") as i128) * 1_000_000,
         )?;
-        let symbol = orderbook.symbol;
-        let new_cache_data = BybitOrderbookCachedData {
-            symbol: symbol.clone(),
-            update: orderbook.update,
-
-            bid: orderbook
-                .bid
-                .into_iter()
-                .map(|[price, volume]| {
-                    Ok((
-                        Decimal128::from_str(&price)?,
-                        Decimal128::from_str(&volume)?,
-                    ))
-                })
-                .collect::<Result<HashMap<_, _>>>()?,
-
-            ask: orderbook
-                .ask
-                .into_iter()
-                .map(|[price, volume]| {
-                    Ok((
-                        Decimal128::from_str(&price)?,
-                        Decimal128::from_str(&volume)?,
-                    ))
-                })
-                .collect::<Result<HashMap<_, _>>>()?,
-        };
-        let new_cache_orderbook = BybitCachedOrderbook {
-            server_timestamp,
-            ttype: ttype.to_string(),
-            data: new_cache_data,
-            client_timestamp,
-            received_timestamp,
-        };
-        let cache = orderbook_cache;
+        let OrderbookCache {
+            orderbook: cache,
+            levels,
+            resyncing,
+        } = orderbook_cache;
+        // Every level is parsed before the book is touched, so a malformed
+        // message leaves it as it was. A delta that does not fit the book
+        // drops it for a resync below.
+        levels.clear();
+        for [price, volume] in orderbook.bid.iter().chain(&orderbook.ask) {
+            levels.push((
+                Decimal128::from_str(&price.0)?,
+                Decimal128::from_str(&volume.0)?,
+            ));
+        }
+        let (bids, asks) = levels.split_at(orderbook.bid.len());
+        let symbol: &str = &orderbook.symbol;
+        let update = orderbook.update;
         match ttype.as_str() {
             "snapshot" => {
-                cache.orderbook.insert(symbol.clone(), new_cache_orderbook);
+                if resyncing.remove(symbol) {
+                    info!(symbol, update, "Orderbook resynced.");
+                }
+                if !cache.contains_key(symbol) {
+                    cache.insert(
+                        symbol.to_string(),
+                        BybitCachedOrderbook {
+                            server_timestamp,
+                            ttype: String::new(),
+                            data: BybitOrderbookCachedData {
+                                symbol: Cow::Borrowed(intern(symbol)),
+                                bid: HashMap::new(),
+                                ask: HashMap::new(),
+                                update,
+                            },
+                            client_timestamp,
+                            received_timestamp,
+                        },
+                    );
+                }
+                let cache = cache.get_mut(symbol).expect("book was just inserted");
+                cache.server_timestamp = server_timestamp;
+                cache.ttype.clone_from(ttype);
+                cache.client_timestamp = client_timestamp;
+                cache.received_timestamp = received_timestamp;
+                let cache_data = &mut cache.data;
+                cache_data.update = update;
+                cache_data.bid.clear();
+                cache_data.bid.extend(bids.iter().copied());
+                cache_data.ask.clear();
+                cache_data.ask.extend(asks.iter().copied());
             }
             "delta" => {
-                let mut needs_reconnect = false;
-
-                match cache.orderbook.get_mut(&symbol) {
-                    Some(cache) => {
+                let applied = match cache.get_mut(symbol) {
+                    Some(cache) if update == cache.data.update + 1 => {
                         let cache_data = &mut cache.data;
-                        if new_cache_orderbook.data.update == (cache_data.update + 1) {
-                            let zero = Decimal128::from_str("0")?;
-                            for bid in new_cache_orderbook.data.bid {
-                                let (price, volume) = bid;
-                                if volume == zero {
-                                    let _ = &cache_data.bid.remove(&price).context("Couldnt remove orderbook price. Update id: {new_cache_orderbook.data.update:?}")?;
-                                } else {
-                                    cache_data.bid.insert(price, volume);
-                                    cache_data.update = new_cache_orderbook.data.update
-                                }
+                        let mut known = true;
+                        for &(price, volume) in bids {
+                            if volume == Decimal128::ZERO {
+                                known &= cache_data.bid.remove(&price).is_some();
+                            } else {
+                                cache_data.bid.insert(price, volume);
                             }
-                            for ask in new_cache_orderbook.data.ask {
-                                let (price, volume) = ask;
-                                if volume == zero {
-                                    cache_data.ask.remove(&price);
-                                } else {
-                                    cache_data.ask.insert(price, volume);
-                                    cache_data.update = new_cache_orderbook.data.update
-                                }
+                        }
+                        for &(price, volume) in asks {
+                            if volume == Decimal128::ZERO {
+                                cache_data.ask.remove(&price);
+                            } else {
+                                cache_data.ask.insert(price, volume);
                             }
-                        } else {
-                            needs_reconnect = true;
                         }
+                        cache_data.update = update;
+                        known
                     }
-                    None => needs_reconnect = true,
-                }
-                if needs_reconnect {
-                    tx.send("Reconnect".to_string()).await?;
-                    return Ok(vec![]);
+                    _ => false,
+                };
+                if !applied {
+                    // An update went missing, e.g. dropped by a full channel,
+                    // or a bid to remove was never in the book. The stale
+                    // book is discarded instead of carrying on with deltas
+                    // applied to it, and written again from the next snapshot.
+                    cache.remove(symbol);
+                    if !resyncing.contains(symbol) {
+                        warn!(symbol, update, "Orderbook out of sync, resyncing.");
+                        resyncing.insert(symbol.to_string());
+                    }
+                    // Asked again for every skipped delta. The stream resubscribes
+                    // a topic at most once in a while, so a full channel only
+                    // delays the request.
+                    let _ = tx.try_send(topic.to_string());
+                    return Ok(None);
                 }
             }
             _ => {
                 println!("ERROR");
             }
         }
-        let cache = cache
-            .orderbook
-            .get(&symbol)
-            .context("couldnt get cached symbol")?;
-        let parsed_orderbook = Self::parse_orderbook(
-            &server_timestamp,
-            &received_timestamp,
-            &client_timestamp,
-            &symbol,
-            cache,
-        )
-        .await
-        .context("failed to parse orderbook")?;
-        Ok(parsed_orderbook)
+        let cache = cache.get(symbol).context("couldnt get cached symbol")?;
+        let parsed_orderbook = match layout {
+            OrderbookLayout::Rows => BybitOTT::Orderbook(
+                Self::parse_orderbook(
+                    &server_timestamp,
+                    &received_timestamp,
+                    &client_timestamp,
+                    cache,
+                )
+                .await
+                .context("failed to parse orderbook")?,
+            ),
+            OrderbookLayout::Arrays => {
+                BybitOTT::OrderbookLevels(Box::new(BybitOrderbookLevels::from_cache(
+                    server_timestamp,
+                    received_timestamp,
+                    client_timestamp,
+                    cache,
+                )))
+            }
+        };
+        Ok(Some(parsed_orderbook))
     }
 
     async fn parse_orderbook(
         server_timestamp: &OffsetDateTime,
         received_timestamp: &OffsetDateTime,
         client_timestamp: &OffsetDateTime,
-        symbol: &String,
         orderbook_cache: &BybitCachedOrderbook,
     ) -> Result<Vec<Self>> {
         let cache_data = &orderbook_cache.data;
@@ -192,12 +284,12 @@ impl BybitOrderbook {
                     server_timestamp: *server_timestamp,
                     received_timestamp: *received_timestamp,
                     client_timestamp: *client_timestamp,
-                    symbol: symbol.clone(),
-                    side,
+                    symbol: cache_data.symbol.clone(),
+                    side: Cow::Borrowed(side),
                     price,
                     volume,
                     update,
-                    exchange: "Bybit",
+                    exchange: Cow::Borrowed("Bybit"),
                 })
             };
 
@@ -214,3 +306,121 @@ impl BybitOrderbook {
         Ok(orderbook)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn snapshots_with_escaped_strings_parse() {
+        let data: BybitOrderbookData = serde_json::from_str(
+            r#"{"s":"BTC\u0055SDT","b":[["6500\u0030.5","1"]],"a":[["65001","2"]],"u":1}"#,
+        )
+        .unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let parsed = BybitOrderbook::parse_bybit_orderbook(
+            time,
+            time,
+            Some(1_700_000_000_000),
+            "orderbook.50.BTCUSDT",
+            data,
+            &"snapshot".to_string(),
+            &tx,
+            &mut OrderbookCache::new(),
+            OrderbookLayout::Arrays,
+        )
+        .await
+        .unwrap();
+        let Some(BybitOTT::OrderbookLevels(levels)) = parsed else {
+            panic!("expected a book, got {:?}", parsed);
+        };
+        assert_eq!(levels.symbol, "BTCUSDT");
+        assert_eq!(
+            levels.bid_prices,
+            [Decimal128::from_str("65000.5").unwrap()]
+        );
+        assert_eq!(levels.ask_sizes, [Decimal128::from_str("2").unwrap()]);
+    }
+
+    /// Applies a one-level message for BTCUSDT, true when a book came out.
+    async fn apply(
+        cache: &mut OrderbookCache,
+        tx: &tokio::sync::mpsc::Sender<String>,
+        ttype: &str,
+        update: u64,
+    ) -> bool {
+        apply_bid(cache, tx, ttype, update, "65000", "1").await
+    }
+
+    async fn apply_bid(
+        cache: &mut OrderbookCache,
+        tx: &tokio::sync::mpsc::Sender<String>,
+        ttype: &str,
+        update: u64,
+        price: &str,
+        volume: &str,
+    ) -> bool {
+        let json = format!(
+            r#"{{"s":"BTCUSDT","b":[["{}","{}"]],"a":[],"u":{}}}"#,
+            price, volume, update
+        );
+        let data: BybitOrderbookData = serde_json::from_str(&json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitOrderbook::parse_bybit_orderbook(
+            time,
+            time,
+            Some(1_700_000_000_000),
+            "orderbook.50.BTCUSDT",
+            data,
+            &ttype.to_string(),
+            tx,
+            cache,
+            OrderbookLayout::Arrays,
+        )
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    #[tokio::test]
+    async fn deltas_after_a_gap_ask_to_resync_their_topic() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let cache = &mut OrderbookCache::new();
+        assert!(apply(cache, &tx, "snapshot", 1).await);
+        assert!(apply(cache, &tx, "delta", 2).await);
+        assert!(!apply(cache, &tx, "delta", 4).await);
+        assert!(!apply(cache, &tx, "delta", 5).await);
+        assert!(cache.orderbook.is_empty());
+        assert!(apply(cache, &tx, "snapshot", 9).await);
+        assert!(apply(cache, &tx, "delta", 10).await);
+        assert!(cache.resyncing.is_empty());
+
+        assert_eq!(rx.recv().await.as_deref(), Some("orderbook.50.BTCUSDT"));
+        assert_eq!(rx.recv().await.as_deref(), Some("orderbook.50.BTCUSDT"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deletion_only_deltas_advance_the_book() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let cache = &mut OrderbookCache::new();
+        assert!(apply(cache, &tx, "snapshot", 1).await);
+        assert!(apply_bid(cache, &tx, "delta", 2, "65000", "0").await);
+        assert!(cache.orderbook["BTCUSDT"].data.bid.is_empty());
+        assert!(apply(cache, &tx, "delta", 3).await);
+        assert!(cache.resyncing.is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn removing_an_unknown_bid_resyncs_the_book() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let cache = &mut OrderbookCache::new();
+        assert!(apply(cache, &tx, "snapshot", 1).await);
+        assert!(!apply_bid(cache, &tx, "delta", 2, "64000", "0").await);
+        assert!(cache.orderbook.is_empty());
+        assert!(cache.resyncing.contains("BTCUSDT"));
+        assert_eq!(rx.recv().await.as_deref(), Some("orderbook.50.BTCUSDT"));
+    }
+}
@@ -0,0 +1,622 @@
+use crate::config::{RestConfig, RestEndpoint};
+use crate::parser::{BybitOTT, Decimal128};
+use crate::queue::Sender;
+use anyhow::{Context, Result};
+use clickhouse::{Client, Row};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BybitRestResponse<T> {
+    ret_code: i64,
+    ret_msg: String,
+    result: Option<T>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BybitRestPage<T> {
+    list: Vec<T>,
+    #[serde(default)]
+    next_page_cursor: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BybitOpenInterestData {
+    open_interest: String,
+    timestamp: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BybitLongShortRatioData {
+    buy_ratio: String,
+    sell_ratio: String,
+    timestamp: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BybitHistoricalVolatilityData {
+    period: u32,
+    value: String,
+    time: String,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitOpenInterest {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub symbol: String,
+    pub interval_time: String,
+    pub open_interest: Decimal128,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitLongShortRatio {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub symbol: String,
+    pub period: String,
+    pub buy_ratio: Decimal128,
+    pub sell_ratio: Decimal128,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitHistoricalVolatility {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub base_coin: String,
+    pub period: u32,
+    pub value: Decimal128,
+}
+
+/// Spaces requests evenly so all endpoints together stay under the configured rate.
+struct RateLimiter {
+    min_interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: u32) -> Self {
+        Self {
+            min_interval: Duration::from_secs(1) / requests_per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        let now = Instant::now();
+        if *next > now {
+            tokio::time::sleep_until(*next).await;
+        }
+        *next = Instant::now() + self.min_interval;
+    }
+}
+
+struct RestClient {
+    http: reqwest::Client,
+    base_url: String,
+    limiter: RateLimiter,
+}
+
+impl RestClient {
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<T> {
+        self.limiter.wait().await;
+        let url = format!("{}{}", self.base_url.trim_end_matches('/'), path);
+        let query: Vec<_> = query.iter().filter(|(_, v)| !v.is_empty()).collect();
+        let response: BybitRestResponse<T> = self
+            .http
+            .get(&url)
+            .query(&query)
+            .send()
+            .await
+            .with_context(|| format!("Request to {} failed", url))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Invalid response from {}", url))?;
+        if response.ret_code != 0 {
+            anyhow::bail!(
+                "{} returned retCode {}: {}",
+                path,
+                response.ret_code,
+                response.ret_msg
+            );
+        }
+        response
+            .result
+            .with_context(|| format!("{} returned no result", path))
+    }
+}
+
+fn parse_millis(ms: &str) -> Result<OffsetDateTime> {
+    let ms: i128 = ms
+        .parse()
+        .with_context(|| format!("Invalid timestamp '{}'", ms))?;
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000)?)
+}
+
+fn received_now() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(
+        (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) * 1_000_000,
+    )
+    .expect("received timestamp out of range")
+}
+
+fn endpoint_name(endpoint: &RestEndpoint) -> String {
+    match endpoint {
+        RestEndpoint::OpenInterest {
+            symbol,
+            interval_time,
+            ..
+        } => format!("open_interest {} {}", symbol, interval_time),
+        RestEndpoint::LongShortRatio { symbol, period, .. } => {
+            format!("long_short_ratio {} {}", symbol, period)
+        }
+        RestEndpoint::HistoricalVolatility {
+            base_coin, period, ..
+        } => format!("historical_volatility {} {}", base_coin, period),
+    }
+}
+
+/// Latest stored timestamp for the endpoint, so polling resumes after a restart.
+async fn last_stored(client: &Client, endpoint: &RestEndpoint) -> Result<OffsetDateTime> {
+    let query = match endpoint {
+        RestEndpoint::OpenInterest {
+            symbol,
+            interval_time,
+            ..
+        } => client
+            .query(
+                "SELECT toUnixTimestamp64Milli(max(timestamp)) FROM open_interest_history \
+                 WHERE symbol = ? AND interval_time = ?",
+            )
+            .bind(symbol)
+            .bind(interval_time),
+        RestEndpoint::LongShortRatio { symbol, period, .. } => client
+            .query(
+                "SELECT toUnixTimestamp64Milli(max(timestamp)) FROM long_short_ratio \
+                 WHERE symbol = ? AND period = ?",
+            )
+            .bind(symbol)
+            .bind(period),
+        RestEndpoint::HistoricalVolatility {
+            base_coin, period, ..
+        } => client
+            .query(
+                "SELECT toUnixTimestamp64Milli(max(timestamp)) FROM historical_volatility \
+                 WHERE base_coin = ? AND period = ?",
+            )
+            .bind(base_coin)
+            .bind(period),
+    };
+    let ms = query.fetch_one::<i64>().await?;
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(
+        (ms as i128) * 1_000_000,
+    )?)
+}
+
+/// Rows of a paged endpoint newer than `last`, following the cursor back to
+/// `startTime`. Without a stored row yet only the newest page is fetched.
+async fn fetch_since<T: DeserializeOwned>(
+    rest: &RestClient,
+    path: &str,
+    mut query: Vec<(&str, String)>,
+    last: OffsetDateTime,
+    timestamp: fn(&T) -> &str,
+) -> Result<Vec<(OffsetDateTime, T)>> {
+    let resume = last > OffsetDateTime::UNIX_EPOCH;
+    if resume {
+        let start_ms = last.unix_timestamp_nanos() / 1_000_000 + 1;
+        query.push(("startTime", start_ms.to_string()));
+    }
+    query.push(("cursor", String::new()));
+
+    let mut rows = Vec::new();
+    loop {
+        let page: BybitRestPage<T> = rest.get(path, &query).await?;
+        let done = !resume || page.list.is_empty() || page.next_page_cursor.is_empty();
+        for data in page.list {
+            let ts = parse_millis(timestamp(&data))?;
+            if ts > last {
+                rows.push((ts, data));
+            }
+        }
+        if done {
+            return Ok(rows);
+        }
+        if let Some((_, cursor)) = query.last_mut() {
+            *cursor = page.next_page_cursor;
+        }
+    }
+}
+
+/// Fetches rows newer than `last` and returns them with the new latest timestamp.
+async fn poll_once(
+    rest: &RestClient,
+    endpoint: &RestEndpoint,
+    last: OffsetDateTime,
+) -> Result<Option<(BybitOTT, OffsetDateTime)>> {
+    let received_timestamp = received_now();
+    match endpoint {
+        RestEndpoint::OpenInterest {
+            symbol,
+            interval_time,
+            ..
+        } => {
+            let list = fetch_since(
+                rest,
+                "/v5/market/open-interest",
+                vec![
+                    ("category", "linear".to_string()),
+                    ("symbol", symbol.clone()),
+                    ("intervalTime", interval_time.clone()),
+                    ("limit", "200".to_string()),
+                ],
+                last,
+                |data: &BybitOpenInterestData| &data.timestamp,
+            )
+            .await?;
+            let mut rows = Vec::with_capacity(list.len());
+            for (timestamp, data) in list {
+                rows.push(BybitOpenInterest {
+                    timestamp,
+                    received_timestamp,
+                    symbol: symbol.clone(),
+                    interval_time: interval_time.clone(),
+                    open_interest: Decimal128::from_str(&data.open_interest)?,
+                });
+            }
+            let Some(newest) = rows.iter().map(|r| r.timestamp).max() else {
+                return Ok(None);
+            };
+            Ok(Some((BybitOTT::OpenInterest(rows), newest)))
+        }
+        RestEndpoint::LongShortRatio { symbol, period, .. } => {
+            let list = fetch_since(
+                rest,
+                "/v5/market/account-ratio",
+                vec![
+                    ("category", "linear".to_string()),
+                    ("symbol", symbol.clone()),
+                    ("period", period.clone()),
+                    ("limit", "500".to_string()),
+                ],
+                last,
+                |data: &BybitLongShortRatioData| &data.timestamp,
+            )
+            .await?;
+            let mut rows = Vec::with_capacity(list.len());
+            for (timestamp, data) in list {
+                rows.push(BybitLongShortRatio {
+                    timestamp,
+                    received_timestamp,
+                    symbol: symbol.clone(),
+                    period: period.clone(),
+                    buy_ratio: Decimal128::from_str(&data.buy_ratio)?,
+                    sell_ratio: Decimal128::from_str(&data.sell_ratio)?,
+                });
+            }
+            let Some(newest) = rows.iter().map(|r| r.timestamp).max() else {
+                return Ok(None);
+            };
+            Ok(Some((BybitOTT::LongShortRatio(rows), newest)))
+        }
+        RestEndpoint::HistoricalVolatility {
+            base_coin, period, ..
+        } => {
+            let list: Vec<BybitHistoricalVolatilityData> = rest
+                .get(
+                    "/v5/market/historical-volatility",
+                    &[
+                        ("category", "option".to_string()),
+                        ("baseCoin", base_coin.clone()),
+                        ("period", period.to_string()),
+                    ],
+                )
+                .await?;
+            let mut rows = Vec::new();
+            for data in list {
+                let timestamp = parse_millis(&data.time)?;
+                if timestamp <= last {
+                    continue;
+                }
+                rows.push(BybitHistoricalVolatility {
+                    timestamp,
+                    received_timestamp,
+                    base_coin: base_coin.clone(),
+                    period: data.period,
+                    value: Decimal128::from_str(&data.value)?,
+                });
+            }
+            let Some(newest) = rows.iter().map(|r| r.timestamp).max() else {
+                return Ok(None);
+            };
+            Ok(Some((BybitOTT::HistoricalVolatility(rows), newest)))
+        }
+    }
+}
+
+/// Polls one endpoint forever. Resumes after the newest row in ClickHouse when
+/// it is a sink, otherwise starts from the newest page.
+async fn poll_endpoint(
+    rest: Arc<RestClient>,
+    endpoint: RestEndpoint,
+    client: Option<Client>,
+    writer_tx: Sender<BybitOTT>,
+) -> Result<()> {
+    let name = endpoint_name(&endpoint);
+    let mut last = match &client {
+        Some(client) => last_stored(client, &endpoint)
+            .await
+            .with_context(|| format!("Failed to read last stored timestamp for {}", name))?,
+        None => OffsetDateTime::UNIX_EPOCH,
+    };
+    info!(
+        "Polling {} every {}s from {}.",
+        name,
+        endpoint.poll_secs(),
+        last
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(endpoint.poll_secs().max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match poll_once(&rest, &endpoint, last).await {
+            Ok(Some((to_write, newest))) => {
+                writer_tx
+                    .send(to_write)
+                    .await
+                    .context("Writer channel closed (REST poller)")?;
+                // Only keeps this loop from fetching the rows again. The rows
+                // are not committed yet; after a restart polling resumes from
+                // the stored max instead.
+                last = newest;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to poll {}: {:?}", name, e),
+        }
+    }
+}
+
+/// Runs one polling task per configured endpoint, sharing a single rate limit.
+/// Fails with the first poller that fails; the others are aborted with it.
+pub async fn run_pollers(
+    config: RestConfig,
+    client: Option<Client>,
+    writer_tx: Sender<BybitOTT>,
+) -> Result<()> {
+    let rest = Arc::new(RestClient {
+        http: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?,
+        base_url: config.base_url,
+        limiter: RateLimiter::new(config.requests_per_second),
+    });
+
+    let mut pollers = JoinSet::new();
+    for endpoint in config.endpoints {
+        pollers.spawn(poll_endpoint(
+            rest.clone(),
+            endpoint,
+            client.clone(),
+            writer_tx.clone(),
+        ));
+    }
+    drop(writer_tx);
+    while let Some(polled) = pollers.join_next().await {
+        polled.context("REST poller panicked")??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::crypto::CryptoProvider;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves open interest newest first, `per_page` rows per page, from
+    /// `newest` down to 1 ms, honouring `startTime` and `cursor`. Returns the
+    /// base url and the received request targets.
+    async fn mock_open_interest(
+        newest: u64,
+        per_page: u64,
+    ) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let target = request.split(' ').nth(1).unwrap().to_string();
+                let url = url::Url::parse(&format!("http://mock{}", target)).unwrap();
+                let param = |name: &str| {
+                    url.query_pairs()
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.parse::<u64>().unwrap())
+                };
+                seen.lock().unwrap().push(target);
+
+                let start = param("startTime").unwrap_or(1);
+                let top = param("cursor").unwrap_or(newest);
+                let list: Vec<String> = (start.max(top.saturating_sub(per_page) + 1)..=top)
+                    .rev()
+                    .map(|ts| format!(r#"{{"openInterest":"{}.5","timestamp":"{}"}}"#, ts, ts))
+                    .collect();
+                let bottom = top.saturating_sub(per_page);
+                let cursor = if bottom >= start {
+                    bottom.to_string()
+                } else {
+                    String::new()
+                };
+                let body = format!(
+                    r#"{{"retCode":0,"retMsg":"OK","result":{{"list":[{}],"nextPageCursor":"{}"}}}}"#,
+                    list.join(","),
+                    cursor
+                );
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn rest_client(base_url: String) -> RestClient {
+        let _ = CryptoProvider::install_default(rustls::crypto::ring::default_provider());
+        RestClient {
+            http: reqwest::Client::new(),
+            base_url,
+            limiter: RateLimiter::new(1000),
+        }
+    }
+
+    fn open_interest() -> RestEndpoint {
+        RestEndpoint::OpenInterest {
+            symbol: "BTCUSDT".to_string(),
+            interval_time: "5min".to_string(),
+            poll_secs: 300,
+        }
+    }
+
+    fn millis(ms: i128) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).unwrap()
+    }
+
+    #[tokio::test]
+    async fn catches_up_on_a_gap_longer_than_many_pages() {
+        let (base_url, requests) = mock_open_interest(5_000, 200).await;
+        let rest = rest_client(base_url);
+
+        let (to_write, newest) = poll_once(&rest, &open_interest(), millis(1_000))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let BybitOTT::OpenInterest(rows) = to_write else {
+            panic!("expected open interest rows");
+        };
+        assert_eq!(newest, millis(5_000));
+        assert_eq!(rows.len(), 4_000);
+        assert_eq!(rows.iter().map(|r| r.timestamp).min(), Some(millis(1_001)));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 20);
+        assert!(requests.iter().all(|r| r.contains("startTime=1001")));
+    }
+
+    #[tokio::test]
+    async fn without_stored_rows_fetches_only_the_newest_page() {
+        let (base_url, requests) = mock_open_interest(5_000, 200).await;
+        let rest = rest_client(base_url);
+
+        let (to_write, newest) = poll_once(&rest, &open_interest(), OffsetDateTime::UNIX_EPOCH)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let BybitOTT::OpenInterest(rows) = to_write else {
+            panic!("expected open interest rows");
+        };
+        assert_eq!(newest, millis(5_000));
+        assert_eq!(rows.len(), 200);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].contains("startTime"));
+    }
+
+    #[tokio::test]
+    async fn nothing_new_returns_none() {
+        let (base_url, _) = mock_open_interest(5_000, 200).await;
+        let rest = rest_client(base_url);
+
+        let polled = poll_once(&rest, &open_interest(), millis(5_000))
+            .await
+            .unwrap();
+
+        assert!(polled.is_none());
+    }
+
+    fn rest_config(base_url: String) -> RestConfig {
+        let _ = CryptoProvider::install_default(rustls::crypto::ring::default_provider());
+        RestConfig {
+            base_url,
+            requests_per_second: 1000,
+            endpoints: vec![
+                // The mock only serves open interest, so this one keeps failing
+                // its polls and never returns.
+                RestEndpoint::HistoricalVolatility {
+                    base_coin: "BTC".to_string(),
+                    period: 7,
+                    poll_secs: 1,
+                },
+                open_interest(),
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn any_failing_poller_stops_the_pollers() {
+        let (base_url, _) = mock_open_interest(5_000, 200).await;
+        let (writer_tx, writer_rx) =
+            crate::queue::channel("rest_test_failing", &Default::default()).unwrap();
+        drop(writer_rx);
+
+        let polled = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_pollers(rest_config(base_url), None, writer_tx),
+        )
+        .await
+        .expect("the open interest poller fails");
+
+        assert!(format!("{:#}", polled.unwrap_err()).contains("Writer channel closed"));
+    }
+
+    #[tokio::test]
+    async fn stopping_the_pollers_releases_the_writer_channel() {
+        let (base_url, _) = mock_open_interest(5_000, 200).await;
+        let (writer_tx, mut writer_rx) =
+            crate::queue::channel("rest_test_stopped", &Default::default()).unwrap();
+        let pollers = tokio::spawn(run_pollers(rest_config(base_url), None, writer_tx));
+
+        assert!(matches!(
+            writer_rx.recv().await,
+            Some(BybitOTT::OpenInterest(_))
+        ));
+        pollers.abort();
+        let closed = tokio::time::timeout(Duration::from_secs(5), writer_rx.recv()).await;
+        assert!(matches!(closed, Ok(None)));
+    }
+}
@@ -1,21 +1,65 @@
+use crate::bybit_ticker_option::BybitOptionTicker;
+use crate::bybit_ticker_spot::BybitSpotTicker;
 use crate::parser::Decimal128;
 use anyhow::{Context, Result};
 use clickhouse::Row;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
 use time::OffsetDateTime;
+use tracing::warn;
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct TickerCache {
     pub ticker: HashMap<String, BybitTicker>,
+    pub spot: HashMap<String, BybitSpotTicker>,
+    pub option: HashMap<String, BybitOptionTicker>,
+    /// Number of rejected values per ticker field since start.
+    pub rejected_fields: HashMap<String, u64>,
 }
 
 impl TickerCache {
     pub fn new() -> Self {
         Self {
             ticker: HashMap::new(),
+            spot: HashMap::new(),
+            option: HashMap::new(),
+            rejected_fields: HashMap::new(),
         }
     }
+
+    /// Counts and logs rejected fields. In strict mode any rejection fails the message.
+    pub fn record_rejected(
+        &mut self,
+        symbol: &str,
+        rejected: &[RejectedField],
+        strict: bool,
+    ) -> Result<()> {
+        for field in rejected {
+            let count = self
+                .rejected_fields
+                .entry(field.field.to_string())
+                .or_insert(0);
+            *count += 1;
+            warn!(
+                symbol = %symbol,
+                field = field.field,
+                raw = %field.raw,
+                rejected_total = *count,
+                "Rejected malformed ticker field"
+            );
+        }
+        if strict && !rejected.is_empty() {
+            anyhow::bail!(
+                "Rejected ticker message for {} with {} malformed field(s)",
+                symbol,
+                rejected.len()
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -53,6 +97,85 @@ pub struct BybitTickerData {
     pub basis_rate_year: Option<String>,
 }
 
+impl BybitTickerData {
+    /// Fields present in this message, keyed by their `ticker_raw_ml` column name.
+    pub fn changed_fields(&self) -> BTreeMap<String, String> {
+        let fields = [
+            ("tick_direction", &self.tick_direction),
+            ("price_24h_pcnt", &self.price24h_pcnt),
+            ("last_price", &self.last_price),
+            ("prev_price_24h", &self.prev_price24h),
+            ("high_price_24h", &self.high_price24h),
+            ("low_price_24h", &self.low_price24h),
+            ("prev_price_1h", &self.prev_price1h),
+            ("mark_price", &self.mark_price),
+            ("index_price", &self.index_price),
+            ("open_interest", &self.open_interest),
+            ("open_interest_value", &self.open_interest_value),
+            ("turnover_24h", &self.turnover24h),
+            ("volume_24h", &self.volume24h),
+            ("next_funding_time", &self.next_funding_time),
+            ("funding_rate", &self.funding_rate),
+            ("bid1_price", &self.bid1_price),
+            ("bid1_size", &self.bid1_size),
+            ("ask1_price", &self.ask1_price),
+            ("ask1_size", &self.ask1_size),
+            ("delivery_time", &self.delivery_time),
+            ("basis_rate", &self.basis_rate),
+            ("delivery_fee_rate", &self.delivery_fee_rate),
+            ("predicted_delivery_price", &self.predicted_delivery_price),
+            ("pre_open_price", &self.pre_open_price),
+            ("pre_qty", &self.pre_qty),
+            ("cur_pre_listing_phase", &self.cur_pre_listing_phase),
+            ("funding_interval_hour", &self.funding_interval_hour),
+            ("funding_cap", &self.funding_cap),
+            ("basis_rate_year", &self.basis_rate_year),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(name, value)| {
+                value
+                    .as_ref()
+                    .filter(|v| !v.is_empty())
+                    .map(|v| (name.to_string(), v.clone()))
+            })
+            .collect()
+    }
+}
+
+/// One row per ticker message holding only the fields Bybit sent.
+/// `ticker_sparse_full` (see `migrations`) rebuilds the full row.
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitTickerSparse {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub cross_sequence: u64,
+    pub symbol: String,
+    pub ttype: String,
+    pub changes: BTreeMap<String, String>,
+}
+
+impl BybitTickerSparse {
+    pub fn from_data(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        ticker_data: &BybitTickerData,
+        cross_sequence: u64,
+        ttype: &str,
+    ) -> Self {
+        Self {
+            server_timestamp,
+            received_timestamp,
+            cross_sequence,
+            symbol: ticker_data.symbol.clone(),
+            ttype: ttype.to_lowercase(),
+            changes: ticker_data.changed_fields(),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
 pub struct BybitTicker {
     #[serde(with = "clickhouse::serde::time::datetime64::millis")]
@@ -81,9 +204,10 @@ pub struct BybitTicker {
     pub bid1_size: Decimal128,
     pub ask1_price: Decimal128,
     pub ask1_size: Decimal128,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis::option")]
     pub delivery_time: Option<OffsetDateTime>,
     pub basis_rate: Option<Decimal128>,
-    pub delivery_fee_rate: Option<i64>,
+    pub delivery_fee_rate: Option<Decimal128>,
     pub predicted_delivery_price: Option<Decimal128>,
     pub pre_open_price: Option<Decimal128>,
     pub pre_qty: Option<Decimal128>,
@@ -91,57 +215,254 @@ pub struct BybitTicker {
     pub funding_interval_hour: Option<String>,
     pub funding_cap: Option<Decimal128>,
     pub basis_rate_year: Option<Decimal128>,
+    pub is_stale: bool,
+    pub stale_fields: Vec<String>,
 }
 
-impl BybitTicker {
-    fn update_dec(field: &mut Decimal128, opt: Option<String>) {
+/// A ticker field whose raw value could not be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedField {
+    pub field: &'static str,
+    pub raw: String,
+}
+
+/// Collects which fields of a message were applied and which were rejected.
+#[derive(Default)]
+pub(crate) struct FieldUpdates {
+    accepted: Vec<&'static str>,
+    rejected: Vec<RejectedField>,
+}
+
+impl FieldUpdates {
+    pub(crate) fn accept(&mut self, field: &'static str) {
+        self.accepted.push(field);
+    }
+
+    pub(crate) fn reject(&mut self, field: &'static str, raw: String) {
+        self.rejected.push(RejectedField { field, raw });
+    }
+
+    pub(crate) fn dec(&mut self, field: &mut Decimal128, opt: Option<String>, name: &'static str) {
         if let Some(s) = opt
             && !s.is_empty()
-                && let Ok(val) = Decimal128::from_str(&s) {
+        {
+            match Decimal128::from_str(&s) {
+                Ok(val) => {
                     *field = val;
+                    self.accept(name);
                 }
+                Err(_) => self.reject(name, s),
+            }
+        }
     }
 
-    fn update_opt_dec(field: &mut Option<Decimal128>, opt: Option<String>) {
+    pub(crate) fn opt_dec(
+        &mut self,
+        field: &mut Option<Decimal128>,
+        opt: Option<String>,
+        name: &'static str,
+    ) {
         if let Some(s) = opt
             && !s.is_empty()
-                && let Ok(val) = Decimal128::from_str(&s) {
+        {
+            match Decimal128::from_str(&s) {
+                Ok(val) => {
                     *field = Some(val);
+                    self.accept(name);
                 }
+                Err(_) => self.reject(name, s),
+            }
+        }
     }
 
-    pub fn apply_delta(&mut self, delta: BybitTickerData) {
+    /// Updates `stale_fields` and `is_stale` and returns the rejected fields.
+    pub(crate) fn finish(
+        self,
+        stale_fields: &mut Vec<String>,
+        is_stale: &mut bool,
+    ) -> Vec<RejectedField> {
+        stale_fields.retain(|f| !self.accepted.contains(&f.as_str()));
+        for rejected in &self.rejected {
+            if !stale_fields.iter().any(|f| f == rejected.field) {
+                stale_fields.push(rejected.field.to_string());
+            }
+        }
+        *is_stale = !stale_fields.is_empty();
+        self.rejected
+    }
+}
+
+/// A ticker kept per symbol in `TickerCache`. Snapshots start from an empty
+/// ticker, deltas from the cached one.
+pub(crate) trait CachedTicker: Clone {
+    type Data;
+    type CrossSequence;
+    /// Names the cache in errors.
+    const CATEGORY: &'static str;
+
+    fn cache(cache: &mut TickerCache) -> &mut HashMap<String, Self>;
+    fn symbol(data: &Self::Data) -> &str;
+    fn empty_with_meta(
+        st: OffsetDateTime,
+        rt: OffsetDateTime,
+        cs: Self::CrossSequence,
+        sym: String,
+    ) -> Self;
+    fn set_meta(&mut self, st: OffsetDateTime, rt: OffsetDateTime, cs: Self::CrossSequence);
+    /// Applies the message and returns the fields whose values could not be parsed.
+    /// Rejected fields keep their cached value and are listed in `stale_fields`
+    /// until a later message updates them successfully.
+    fn apply_delta(&mut self, data: Self::Data) -> Vec<RejectedField>;
+}
+
+impl TickerCache {
+    /// Applies a snapshot or delta message and caches the result.
+    pub(crate) fn merge<T: CachedTicker>(
+        &mut self,
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        cross_sequence: T::CrossSequence,
+        data: T::Data,
+        ttype: &str,
+        strict: bool,
+    ) -> Result<T> {
+        let symbol = T::symbol(&data).to_string();
+
+        let mut tick = match ttype.to_lowercase().as_str() {
+            "snapshot" => T::empty_with_meta(
+                server_timestamp,
+                received_timestamp,
+                cross_sequence,
+                symbol.clone(),
+            ),
+            "delta" => {
+                let mut tick = T::cache(self)
+                    .get(&symbol)
+                    .with_context(|| format!("Missing {} cache for {}", T::CATEGORY, symbol))?
+                    .clone();
+                tick.set_meta(server_timestamp, received_timestamp, cross_sequence);
+                tick
+            }
+            _ => anyhow::bail!("Unknown type: {}", ttype),
+        };
+
+        let rejected = tick.apply_delta(data);
+        self.record_rejected(&symbol, &rejected, strict)?;
+
+        T::cache(self).insert(symbol, tick.clone());
+        Ok(tick)
+    }
+}
+
+impl BybitTicker {
+    pub async fn parse_bybit_ticker(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        ticker_data: BybitTickerData,
+        cross_sequence: u64,
+        ttype: &str,
+        strict: bool,
+        ticker_cache: &mut TickerCache,
+    ) -> Result<BybitTicker> {
+        ticker_cache.merge(
+            server_timestamp,
+            received_timestamp,
+            cross_sequence,
+            ticker_data,
+            ttype,
+            strict,
+        )
+    }
+}
+
+impl CachedTicker for BybitTicker {
+    type Data = BybitTickerData;
+    type CrossSequence = u64;
+    const CATEGORY: &'static str = "linear";
+
+    fn cache(cache: &mut TickerCache) -> &mut HashMap<String, Self> {
+        &mut cache.ticker
+    }
+
+    fn symbol(data: &BybitTickerData) -> &str {
+        &data.symbol
+    }
+
+    fn set_meta(&mut self, st: OffsetDateTime, rt: OffsetDateTime, cs: u64) {
+        self.server_timestamp = st;
+        self.received_timestamp = rt;
+        self.cross_sequence = cs;
+    }
+
+    fn apply_delta(&mut self, delta: BybitTickerData) -> Vec<RejectedField> {
+        let mut updates = FieldUpdates::default();
+        let u = &mut updates;
+
         if let Some(v) = delta.tick_direction {
             self.tick_direction = v;
         }
 
-        Self::update_dec(&mut self.price_24h_pcnt, delta.price24h_pcnt);
-        Self::update_dec(&mut self.last_price, delta.last_price);
-        Self::update_dec(&mut self.prev_price_24h, delta.prev_price24h);
-        Self::update_dec(&mut self.high_price_24h, delta.high_price24h);
-        Self::update_dec(&mut self.low_price_24h, delta.low_price24h);
-        Self::update_dec(&mut self.prev_price_1h, delta.prev_price1h);
-        Self::update_dec(&mut self.mark_price, delta.mark_price);
-        Self::update_dec(&mut self.index_price, delta.index_price);
-        Self::update_dec(&mut self.open_interest, delta.open_interest);
-        Self::update_dec(&mut self.open_interest_value, delta.open_interest_value);
-        Self::update_dec(&mut self.turnover_24h, delta.turnover24h);
-        Self::update_dec(&mut self.volume_24h, delta.volume24h);
-        Self::update_dec(&mut self.funding_rate, delta.funding_rate);
-        Self::update_dec(&mut self.bid1_price, delta.bid1_price);
-        Self::update_dec(&mut self.bid1_size, delta.bid1_size);
-        Self::update_dec(&mut self.ask1_price, delta.ask1_price);
-        Self::update_dec(&mut self.ask1_size, delta.ask1_size);
-
-        Self::update_opt_dec(&mut self.basis_rate, delta.basis_rate);
-        Self::update_opt_dec(
+        u.dec(
+            &mut self.price_24h_pcnt,
+            delta.price24h_pcnt,
+            "price_24h_pcnt",
+        );
+        u.dec(&mut self.last_price, delta.last_price, "last_price");
+        u.dec(
+            &mut self.prev_price_24h,
+            delta.prev_price24h,
+            "prev_price_24h",
+        );
+        u.dec(
+            &mut self.high_price_24h,
+            delta.high_price24h,
+            "high_price_24h",
+        );
+        u.dec(&mut self.low_price_24h, delta.low_price24h, "low_price_24h");
+        u.dec(&mut self.prev_price_1h, delta.prev_price1h, "prev_price_1h");
+        u.dec(&mut self.mark_price, delta.mark_price, "mark_price");
+        u.dec(&mut self.index_price, delta.index_price, "index_price");
+        u.dec(
+            &mut self.open_interest,
+            delta.open_interest,
+            "open_interest",
+        );
+        u.dec(
+            &mut self.open_interest_value,
+            delta.open_interest_value,
+            "open_interest_value",
+        );
+        u.dec(&mut self.turnover_24h, delta.turnover24h, "turnover_24h");
+        u.dec(&mut self.volume_24h, delta.volume24h, "volume_24h");
+        u.dec(&mut self.funding_rate, delta.funding_rate, "funding_rate");
+        u.dec(&mut self.bid1_price, delta.bid1_price, "bid1_price");
+        u.dec(&mut self.bid1_size, delta.bid1_size, "bid1_size");
+        u.dec(&mut self.ask1_price, delta.ask1_price, "ask1_price");
+        u.dec(&mut self.ask1_size, delta.ask1_size, "ask1_size");
+
+        u.opt_dec(&mut self.basis_rate, delta.basis_rate, "basis_rate");
+        u.opt_dec(
+            &mut self.delivery_fee_rate,
+            delta.delivery_fee_rate,
+            "delivery_fee_rate",
+        );
+        u.opt_dec(
             &mut self.predicted_delivery_price,
             delta.predicted_delivery_price,
+            "predicted_delivery_price",
+        );
+        u.opt_dec(
+            &mut self.pre_open_price,
+            delta.pre_open_price,
+            "pre_open_price",
+        );
+        u.opt_dec(&mut self.pre_qty, delta.pre_qty, "pre_qty");
+        u.opt_dec(&mut self.funding_cap, delta.funding_cap, "funding_cap");
+        u.opt_dec(
+            &mut self.basis_rate_year,
+            delta.basis_rate_year,
+            "basis_rate_year",
         );
-        Self::update_opt_dec(&mut self.pre_open_price, delta.pre_open_price);
-        Self::update_opt_dec(&mut self.pre_qty, delta.pre_qty);
-        Self::update_opt_dec(&mut self.funding_cap, delta.funding_cap);
-        Self::update_opt_dec(&mut self.basis_rate_year, delta.basis_rate_year);
 
         if let Some(v) = delta.cur_pre_listing_phase {
             self.cur_pre_listing_phase = Some(v);
@@ -151,61 +472,36 @@ impl BybitTicker {
         }
 
         if let Some(s) = delta.next_funding_time
-            && let Ok(ms) = s.parse::<i128>()
-                && let Ok(ts) = OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000) {
+            && !s.is_empty()
+        {
+            match s
+                .parse::<i128>()
+                .ok()
+                .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).ok())
+            {
+                Some(ts) => {
                     self.next_funding_time = ts;
+                    u.accept("next_funding_time");
                 }
-
-        if let Some(s) = delta.delivery_fee_rate
-            && let Ok(v) = s.parse::<i64>() {
-                self.delivery_fee_rate = Some(v);
+                None => u.reject("next_funding_time", s),
             }
+        }
 
+        // Perpetuals send "" or "0" for delivery_time.
         if let Some(s) = delta.delivery_time
-            && let Ok(ts) =
-                OffsetDateTime::parse(&s, &time::format_description::well_known::Rfc3339)
-            {
-                self.delivery_time = Some(ts);
-            }
-    }
-
-    pub async fn parse_bybit_ticker(
-        server_timestamp: OffsetDateTime,
-        received_timestamp: OffsetDateTime,
-        ticker_data: BybitTickerData,
-        cross_sequence: u64,
-        ttype: &str,
-        ticker_cache: &mut TickerCache,
-    ) -> Result<BybitTicker> {
-        let symbol = ticker_data.symbol.clone();
-
-        match ttype.to_lowercase().as_str() {
-            "snapshot" => {
-                let mut tick = Self::empty_with_meta(
-                    server_timestamp,
-                    received_timestamp,
-                    cross_sequence,
-                    symbol.clone(),
-                );
-                tick.apply_delta(ticker_data);
-                ticker_cache.ticker.insert(symbol, tick.clone());
-                Ok(tick)
-            }
-            "delta" => {
-                let cached_tick = ticker_cache
-                    .ticker
-                    .get_mut(&symbol)
-                    .with_context(|| format!("Missing cache for {}", symbol))?;
-
-                cached_tick.server_timestamp = server_timestamp;
-                cached_tick.received_timestamp = received_timestamp;
-                cached_tick.cross_sequence = cross_sequence;
-                cached_tick.apply_delta(ticker_data);
-
-                Ok(cached_tick.clone())
+            && !s.is_empty()
+            && s != "0"
+        {
+            match OffsetDateTime::parse(&s, &time::format_description::well_known::Rfc3339) {
+                Ok(ts) => {
+                    self.delivery_time = Some(ts);
+                    u.accept("delivery_time");
+                }
+                Err(_) => u.reject("delivery_time", s),
             }
-            _ => anyhow::bail!("Unknown type: {}", ttype),
         }
+
+        updates.finish(&mut self.stale_fields, &mut self.is_stale)
     }
 
     fn empty_with_meta(st: OffsetDateTime, rt: OffsetDateTime, cs: u64, sym: String) -> Self {
@@ -243,6 +539,122 @@ impl BybitTicker {
             funding_interval_hour: None,
             funding_cap: None,
             basis_rate_year: None,
+            is_stale: false,
+            stale_fields: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn apply(
+        cache: &mut TickerCache,
+        ttype: &str,
+        strict: bool,
+        json: &str,
+    ) -> Result<BybitTicker> {
+        let data: BybitTickerData = serde_json::from_str(json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitTicker::parse_bybit_ticker(time, time, data, 1, ttype, strict, cache).await
+    }
+
+    fn decimal(value: &str) -> Decimal128 {
+        Decimal128::from_str(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejected_fields_stay_stale_until_a_good_value() {
+        let cache = &mut TickerCache::new();
+        apply(
+            cache,
+            "snapshot",
+            false,
+            r#"{"symbol":"BTCUSDT","markPrice":"65000"}"#,
+        )
+        .await
+        .unwrap();
+
+        let tick = apply(
+            cache,
+            "delta",
+            false,
+            r#"{"symbol":"BTCUSDT","markPrice":"6x"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tick.mark_price, decimal("65000"));
+        assert!(tick.is_stale);
+        assert_eq!(tick.stale_fields, ["mark_price"]);
+        assert_eq!(cache.rejected_fields["mark_price"], 1);
+
+        let tick = apply(
+            cache,
+            "delta",
+            false,
+            r#"{"symbol":"BTCUSDT","lastPrice":"1"}"#,
+        )
+        .await
+        .unwrap();
+        assert!(tick.is_stale);
+
+        let tick = apply(
+            cache,
+            "delta",
+            false,
+            r#"{"symbol":"BTCUSDT","markPrice":"65001"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tick.mark_price, decimal("65001"));
+        assert!(!tick.is_stale);
+        assert!(tick.stale_fields.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_mode_fails_the_message_and_keeps_the_cache() {
+        let cache = &mut TickerCache::new();
+        apply(
+            cache,
+            "snapshot",
+            true,
+            r#"{"symbol":"BTCUSDT","markPrice":"65000"}"#,
+        )
+        .await
+        .unwrap();
+
+        let delta = r#"{"symbol":"BTCUSDT","markPrice":"6x","lastPrice":"1"}"#;
+        assert!(apply(cache, "delta", true, delta).await.is_err());
+        let cached = &cache.ticker["BTCUSDT"];
+        assert_eq!(cached.mark_price, decimal("65000"));
+        assert_eq!(cached.last_price, Decimal128::default());
+        assert!(!cached.is_stale);
+        assert_eq!(cache.rejected_fields["mark_price"], 1);
+    }
+
+    #[tokio::test]
+    async fn empty_delivery_fields_are_skipped() {
+        let cache = &mut TickerCache::new();
+        let perpetual = r#"{"symbol":"BTCUSDT","nextFundingTime":"1700006400000",
+            "deliveryFeeRate":"","deliveryTime":"0"}"#;
+        let tick = apply(cache, "snapshot", true, perpetual).await.unwrap();
+        assert_eq!(tick.next_funding_time.unix_timestamp(), 1_700_006_400);
+        assert_eq!(tick.delivery_fee_rate, None);
+        assert_eq!(tick.delivery_time, None);
+
+        let delta = r#"{"symbol":"BTCUSDT","nextFundingTime":"","deliveryTime":""}"#;
+        let tick = apply(cache, "delta", true, delta).await.unwrap();
+        assert_eq!(tick.next_funding_time.unix_timestamp(), 1_700_006_400);
+        assert!(!tick.is_stale);
+
+        let dated = r#"{"symbol":"BTCUSDT-29MAR24","deliveryFeeRate":"0.0005",
+            "deliveryTime":"2024-03-29T08:00:00Z"}"#;
+        let tick = apply(cache, "snapshot", true, dated).await.unwrap();
+        assert_eq!(tick.delivery_fee_rate, Some(decimal("0.0005")));
+        assert_eq!(
+            tick.delivery_time.map(|t| t.unix_timestamp()),
+            Some(1_711_699_200)
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::bybit_ticker::{CachedTicker, FieldUpdates, RejectedField, TickerCache};
+use crate::parser::Decimal128;
+use anyhow::Result;
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BybitOptionTickerData {
+    pub symbol: String,
+    pub bid_price: Option<String>,
+    pub bid_size: Option<String>,
+    pub bid_iv: Option<String>,
+    pub ask_price: Option<String>,
+    pub ask_size: Option<String>,
+    pub ask_iv: Option<String>,
+    pub last_price: Option<String>,
+    pub high_price24h: Option<String>,
+    pub low_price24h: Option<String>,
+    pub mark_price: Option<String>,
+    pub index_price: Option<String>,
+    pub mark_price_iv: Option<String>,
+    pub underlying_price: Option<String>,
+    pub open_interest: Option<String>,
+    pub turnover24h: Option<String>,
+    pub volume24h: Option<String>,
+    pub total_volume: Option<String>,
+    pub total_turnover: Option<String>,
+    pub delta: Option<String>,
+    pub gamma: Option<String>,
+    pub vega: Option<String>,
+    pub theta: Option<String>,
+    pub predicted_delivery_price: Option<String>,
+    pub change24h: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitOptionTicker {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub cross_sequence: Option<u64>,
+    pub symbol: String,
+    pub bid_price: Decimal128,
+    pub bid_size: Decimal128,
+    pub bid_iv: Decimal128,
+    pub ask_price: Decimal128,
+    pub ask_size: Decimal128,
+    pub ask_iv: Decimal128,
+    pub last_price: Decimal128,
+    pub high_price_24h: Decimal128,
+    pub low_price_24h: Decimal128,
+    pub mark_price: Decimal128,
+    pub index_price: Decimal128,
+    pub mark_price_iv: Decimal128,
+    pub underlying_price: Decimal128,
+    pub open_interest: Decimal128,
+    pub turnover_24h: Decimal128,
+    pub volume_24h: Decimal128,
+    pub total_volume: Decimal128,
+    pub total_turnover: Decimal128,
+    pub delta: Decimal128,
+    pub gamma: Decimal128,
+    pub vega: Decimal128,
+    pub theta: Decimal128,
+    pub predicted_delivery_price: Option<Decimal128>,
+    pub change_24h: Decimal128,
+    pub is_stale: bool,
+    pub stale_fields: Vec<String>,
+}
+
+impl BybitOptionTicker {
+    pub async fn parse_bybit_option_ticker(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        ticker_data: BybitOptionTickerData,
+        cross_sequence: Option<u64>,
+        ttype: &str,
+        strict: bool,
+        ticker_cache: &mut TickerCache,
+    ) -> Result<Self> {
+        ticker_cache.merge(
+            server_timestamp,
+            received_timestamp,
+            cross_sequence,
+            ticker_data,
+            ttype,
+            strict,
+        )
+    }
+}
+
+impl CachedTicker for BybitOptionTicker {
+    type Data = BybitOptionTickerData;
+    type CrossSequence = Option<u64>;
+    const CATEGORY: &'static str = "option";
+
+    fn cache(cache: &mut TickerCache) -> &mut HashMap<String, Self> {
+        &mut cache.option
+    }
+
+    fn symbol(data: &BybitOptionTickerData) -> &str {
+        &data.symbol
+    }
+
+    fn set_meta(&mut self, st: OffsetDateTime, rt: OffsetDateTime, cs: Option<u64>) {
+        self.server_timestamp = st;
+        self.received_timestamp = rt;
+        self.cross_sequence = cs;
+    }
+
+    fn apply_delta(&mut self, delta: BybitOptionTickerData) -> Vec<RejectedField> {
+        let mut updates = FieldUpdates::default();
+        let u = &mut updates;
+
+        u.dec(&mut self.bid_price, delta.bid_price, "bid_price");
+        u.dec(&mut self.bid_size, delta.bid_size, "bid_size");
+        u.dec(&mut self.bid_iv, delta.bid_iv, "bid_iv");
+        u.dec(&mut self.ask_price, delta.ask_price, "ask_price");
+        u.dec(&mut self.ask_size, delta.ask_size, "ask_size");
+        u.dec(&mut self.ask_iv, delta.ask_iv, "ask_iv");
+        u.dec(&mut self.last_price, delta.last_price, "last_price");
+        u.dec(
+            &mut self.high_price_24h,
+            delta.high_price24h,
+            "high_price_24h",
+        );
+        u.dec(&mut self.low_price_24h, delta.low_price24h, "low_price_24h");
+        u.dec(&mut self.mark_price, delta.mark_price, "mark_price");
+        u.dec(&mut self.index_price, delta.index_price, "index_price");
+        u.dec(
+            &mut self.mark_price_iv,
+            delta.mark_price_iv,
+            "mark_price_iv",
+        );
+        u.dec(
+            &mut self.underlying_price,
+            delta.underlying_price,
+            "underlying_price",
+        );
+        u.dec(
+            &mut self.open_interest,
+            delta.open_interest,
+            "open_interest",
+        );
+        u.dec(&mut self.turnover_24h, delta.turnover24h, "turnover_24h");
+        u.dec(&mut self.volume_24h, delta.volume24h, "volume_24h");
+        u.dec(&mut self.total_volume, delta.total_volume, "total_volume");
+        u.dec(
+            &mut self.total_turnover,
+            delta.total_turnover,
+            "total_turnover",
+        );
+        u.dec(&mut self.delta, delta.delta, "delta");
+        u.dec(&mut self.gamma, delta.gamma, "gamma");
+        u.dec(&mut self.vega, delta.vega, "vega");
+        u.dec(&mut self.theta, delta.theta, "theta");
+        u.opt_dec(
+            &mut self.predicted_delivery_price,
+            delta.predicted_delivery_price,
+            "predicted_delivery_price",
+        );
+        u.dec(&mut self.change_24h, delta.change24h, "change_24h");
+
+        updates.finish(&mut self.stale_fields, &mut self.is_stale)
+    }
+
+    fn empty_with_meta(
+        st: OffsetDateTime,
+        rt: OffsetDateTime,
+        cs: Option<u64>,
+        sym: String,
+    ) -> Self {
+        Self {
+            server_timestamp: st,
+            received_timestamp: rt,
+            cross_sequence: cs,
+            symbol: sym,
+            bid_price: Decimal128::default(),
+            bid_size: Decimal128::default(),
+            bid_iv: Decimal128::default(),
+            ask_price: Decimal128::default(),
+            ask_size: Decimal128::default(),
+            ask_iv: Decimal128::default(),
+            last_price: Decimal128::default(),
+            high_price_24h: Decimal128::default(),
+            low_price_24h: Decimal128::default(),
+            mark_price: Decimal128::default(),
+            index_price: Decimal128::default(),
+            mark_price_iv: Decimal128::default(),
+            underlying_price: Decimal128::default(),
+            open_interest: Decimal128::default(),
+            turnover_24h: Decimal128::default(),
+            volume_24h: Decimal128::default(),
+            total_volume: Decimal128::default(),
+            total_turnover: Decimal128::default(),
+            delta: Decimal128::default(),
+            gamma: Decimal128::default(),
+            vega: Decimal128::default(),
+            theta: Decimal128::default(),
+            predicted_delivery_price: None,
+            change_24h: Decimal128::default(),
+            is_stale: false,
+            stale_fields: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const SYMBOL: &str = "BTC-27DEC24-100000-C";
+
+    async fn apply(cache: &mut TickerCache, ttype: &str, json: &str) -> Result<BybitOptionTicker> {
+        let data: BybitOptionTickerData = serde_json::from_str(json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitOptionTicker::parse_bybit_option_ticker(time, time, data, None, ttype, false, cache)
+            .await
+    }
+
+    #[tokio::test]
+    async fn deltas_update_the_snapshot() {
+        let cache = &mut TickerCache::new();
+        let snapshot = r#"{"symbol":"BTC-27DEC24-100000-C","markPrice":"1200","delta":"0.4",
+            "markPriceIv":"0.55"}"#;
+        apply(cache, "snapshot", snapshot).await.unwrap();
+
+        let delta = r#"{"symbol":"BTC-27DEC24-100000-C","markPrice":"1250","gamma":"x"}"#;
+        let tick = apply(cache, "delta", delta).await.unwrap();
+        assert_eq!(tick.mark_price, Decimal128::from_str("1250").unwrap());
+        assert_eq!(tick.delta, Decimal128::from_str("0.4").unwrap());
+        assert_eq!(tick.mark_price_iv, Decimal128::from_str("0.55").unwrap());
+        assert!(tick.is_stale);
+        assert_eq!(tick.stale_fields, ["gamma"]);
+        assert_eq!(cache.option[SYMBOL], tick);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::bybit_ticker::{CachedTicker, FieldUpdates, RejectedField, TickerCache};
+use crate::parser::Decimal128;
+use anyhow::Result;
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BybitSpotTickerData {
+    pub symbol: String,
+    pub last_price: Option<String>,
+    pub high_price24h: Option<String>,
+    pub low_price24h: Option<String>,
+    pub prev_price24h: Option<String>,
+    pub volume24h: Option<String>,
+    pub turnover24h: Option<String>,
+    pub price24h_pcnt: Option<String>,
+    pub usd_index_price: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Row, Serialize, Deserialize, Debug)]
+pub struct BybitSpotTicker {
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub server_timestamp: OffsetDateTime,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    pub received_timestamp: OffsetDateTime,
+    pub cross_sequence: Option<u64>,
+    pub symbol: String,
+    pub last_price: Decimal128,
+    pub high_price_24h: Decimal128,
+    pub low_price_24h: Decimal128,
+    pub prev_price_24h: Decimal128,
+    pub volume_24h: Decimal128,
+    pub turnover_24h: Decimal128,
+    pub price_24h_pcnt: Decimal128,
+    pub usd_index_price: Option<Decimal128>,
+    pub is_stale: bool,
+    pub stale_fields: Vec<String>,
+}
+
+impl BybitSpotTicker {
+    pub async fn parse_bybit_spot_ticker(
+        server_timestamp: OffsetDateTime,
+        received_timestamp: OffsetDateTime,
+        ticker_data: BybitSpotTickerData,
+        cross_sequence: Option<u64>,
+        ttype: &str,
+        strict: bool,
+        ticker_cache: &mut TickerCache,
+    ) -> Result<Self> {
+        ticker_cache.merge(
+            server_timestamp,
+            received_timestamp,
+            cross_sequence,
+            ticker_data,
+            ttype,
+            strict,
+        )
+    }
+}
+
+impl CachedTicker for BybitSpotTicker {
+    type Data = BybitSpotTickerData;
+    type CrossSequence = Option<u64>;
+    const CATEGORY: &'static str = "spot";
+
+    fn cache(cache: &mut TickerCache) -> &mut HashMap<String, Self> {
+        &mut cache.spot
+    }
+
+    fn symbol(data: &BybitSpotTickerData) -> &str {
+        &data.symbol
+    }
+
+    fn set_meta(&mut self, st: OffsetDateTime, rt: OffsetDateTime, cs: Option<u64>) {
+        self.server_timestamp = st;
+        self.received_timestamp = rt;
+        self.cross_sequence = cs;
+    }
+
+    fn apply_delta(&mut self, delta: BybitSpotTickerData) -> Vec<RejectedField> {
+        let mut updates = FieldUpdates::default();
+        let u = &mut updates;
+
+        u.dec(&mut self.last_price, delta.last_price, "last_price");
+        u.dec(
+            &mut self.high_price_24h,
+            delta.high_price24h,
+            "high_price_24h",
+        );
+        u.dec(&mut self.low_price_24h, delta.low_price24h, "low_price_24h");
+        u.dec(
+            &mut self.prev_price_24h,
+            delta.prev_price24h,
+            "prev_price_24h",
+        );
+        u.dec(&mut self.volume_24h, delta.volume24h, "volume_24h");
+        u.dec(&mut self.turnover_24h, delta.turnover24h, "turnover_24h");
+        u.dec(
+            &mut self.price_24h_pcnt,
+            delta.price24h_pcnt,
+            "price_24h_pcnt",
+        );
+        u.opt_dec(
+            &mut self.usd_index_price,
+            delta.usd_index_price,
+            "usd_index_price",
+        );
+
+        updates.finish(&mut self.stale_fields, &mut self.is_stale)
+    }
+
+    fn empty_with_meta(
+        st: OffsetDateTime,
+        rt: OffsetDateTime,
+        cs: Option<u64>,
+        sym: String,
+    ) -> Self {
+        Self {
+            server_timestamp: st,
+            received_timestamp: rt,
+            cross_sequence: cs,
+            symbol: sym,
+            last_price: Decimal128::default(),
+            high_price_24h: Decimal128::default(),
+            low_price_24h: Decimal128::default(),
+            prev_price_24h: Decimal128::default(),
+            volume_24h: Decimal128::default(),
+            turnover_24h: Decimal128::default(),
+            price_24h_pcnt: Decimal128::default(),
+            usd_index_price: None,
+            is_stale: false,
+            stale_fields: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    async fn apply(cache: &mut TickerCache, ttype: &str, json: &str) -> Result<BybitSpotTicker> {
+        let data: BybitSpotTickerData = serde_json::from_str(json).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitSpotTicker::parse_bybit_spot_ticker(time, time, data, None, ttype, false, cache).await
+    }
+
+    #[tokio::test]
+    async fn deltas_update_the_snapshot() {
+        let cache = &mut TickerCache::new();
+        assert!(
+            apply(cache, "delta", r#"{"symbol":"BTCUSDT"}"#)
+                .await
+                .is_err()
+        );
+
+        let snapshot =
+            r#"{"symbol":"BTCUSDT","lastPrice":"65000","volume24h":"100","usdIndexPrice":""}"#;
+        let tick = apply(cache, "snapshot", snapshot).await.unwrap();
+        assert_eq!(tick.usd_index_price, None);
+
+        let delta = r#"{"symbol":"BTCUSDT","lastPrice":"65001","usdIndexPrice":"64990"}"#;
+        let tick = apply(cache, "delta", delta).await.unwrap();
+        assert_eq!(tick.last_price, Decimal128::from_str("65001").unwrap());
+        assert_eq!(tick.volume_24h, Decimal128::from_str("100").unwrap());
+        assert_eq!(
+            tick.usd_index_price,
+            Some(Decimal128::from_str("64990").unwrap())
+        );
+        assert!(!tick.is_stale);
+        assert_eq!(cache.spot["BTCUSDT"], tick);
+    }
+}
@@ -1,26 +1,28 @@
-use crate::parser::Decimal128;
+use crate::parser::{Decimal128, intern};
 use anyhow::{Context, Result};
 use clickhouse::Row;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::str::FromStr;
 use time::OffsetDateTime;
 
+/// Borrows from the frame; Bybit never escapes these strings.
 #[derive(Deserialize, Debug, Clone)]
-pub struct BybitTradeData {
+pub struct BybitTradeData<'a> {
     #[serde(rename = "T")]
     trade_timestamp: u64,
-    #[serde(rename = "s")]
-    symbol: String,
-    #[serde(rename = "S")]
-    side: String,
-    #[serde(rename = "v")]
-    volume: String,
-    #[serde(rename = "p")]
-    price: String,
-    #[serde(rename = "L")]
-    tick_direction: String,
-    #[serde(rename = "i")]
-    trade_id: String,
+    #[serde(rename = "s", borrow)]
+    symbol: Cow<'a, str>,
+    #[serde(rename = "S", borrow)]
+    side: Cow<'a, str>,
+    #[serde(rename = "v", borrow)]
+    volume: Cow<'a, str>,
+    #[serde(rename = "p", borrow)]
+    price: Cow<'a, str>,
+    #[serde(rename = "L", borrow)]
+    tick_direction: Cow<'a, str>,
+    #[serde(rename = "i", borrow)]
+    trade_id: Cow<'a, str>,
     #[serde(rename = "BT")]
     is_block_trade: bool,
     #[serde(rename = "RPI")]
@@ -36,29 +38,29 @@ pub struct BybitTrades {
     pub received_timestamp: OffsetDateTime,
     #[serde(with = "clickhouse::serde::time::datetime64::millis")]
     pub trade_timestamp: OffsetDateTime,
-    pub symbol: String,
+    pub symbol: Cow<'static, str>,
     pub trade_id: String,
-    pub side: String,
+    pub side: Cow<'static, str>,
     pub price: Decimal128,
     pub volume: Decimal128,
-    pub tick_direction: String,
+    pub tick_direction: Cow<'static, str>,
     pub is_block_trade: bool,
     pub is_rpi: bool,
     pub seq: u64,
-    pub exchange: String,
+    pub exchange: Cow<'static, str>,
 }
 
 impl BybitTrades {
     pub async fn parse_bybit_trades(
         server_timestamp: OffsetDateTime,
         received_timestamp: OffsetDateTime,
-        trade_data: Vec<BybitTradeData>,
+        trade_data: Vec<BybitTradeData<'_>>,
     ) -> Result<Vec<Self>> {
         Self::parse_bybit_trade(trade_data, server_timestamp, received_timestamp)
     }
 
     fn parse_bybit_trade(
-        data: Vec<BybitTradeData>,
+        data: Vec<BybitTradeData<'_>>,
         server_timestamp: OffsetDateTime,
         received_timestamp: OffsetDateTime,
     ) -> Result<Vec<Self>> {
@@ -68,7 +70,7 @@ impl BybitTrades {
     }
 
     fn parse_bybit_trade_data(
-        td: &BybitTradeData,
+        td: &BybitTradeData<'_>,
         server_timestamp: OffsetDateTime,
         received_timestamp: OffsetDateTime,
     ) -> Result<Self> {
@@ -82,20 +84,38 @@ impl BybitTrades {
             server_timestamp,
             received_timestamp,
             trade_timestamp,
-            symbol: td.symbol.clone(),
-            trade_id: td.trade_id.clone(),
-            side: if td.side == "Buy" { "Buy" } else { "Sell" }.to_string(),
+            symbol: Cow::Borrowed(intern(&td.symbol)),
+            trade_id: td.trade_id.to_string(),
+            side: Cow::Borrowed(if td.side == "Buy" { "Buy" } else { "Sell" }),
             price: Decimal128::from_str(&td.price).with_context(|| {
                 format!("Invalid price '{}' in trade {}", td.price, td.trade_id)
             })?,
             volume: Decimal128::from_str(&td.volume).with_context(|| {
                 format!("Invalid volume '{}' in trade {}", td.volume, td.trade_id)
             })?,
-            tick_direction: td.tick_direction.clone(),
+            tick_direction: Cow::Borrowed(intern(&td.tick_direction)),
             is_block_trade: td.is_block_trade,
             is_rpi: td.is_rpi,
             seq: td.seq,
-            exchange: "Bybit".to_string(),
+            exchange: Cow::Borrowed("Bybit"),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trades_with_escaped_strings_parse() {
+        let data: Vec<BybitTradeData> = serde_json::from_str(
+            r#"[{"T":1700000000000,"s":"BTC\u0055SDT","S":"Buy","v":"0.001","p":"6500\u0030.5","L":"PlusTick","i":"a\/b","BT":false,"RPI":false,"seq":1}]"#,
+        )
+        .unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let trades = BybitTrades::parse_bybit_trade(data, time, time).unwrap();
+        assert_eq!(trades[0].symbol, "BTCUSDT");
+        assert_eq!(trades[0].trade_id, "a/b");
+        assert_eq!(trades[0].price, Decimal128::from_str("65000.5").unwrap());
+    }
+}
@@ -0,0 +1,356 @@
+use crate::bybit_funding::BybitFundingEvent;
+use crate::bybit_kline::BybitKline;
+use crate::bybit_liquidations::BybitLiquidations;
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_rest::{BybitHistoricalVolatility, BybitLongShortRatio, BybitOpenInterest};
+use crate::bybit_ticker::{BybitTicker, BybitTickerSparse};
+use crate::bybit_ticker_option::BybitOptionTicker;
+use crate::bybit_ticker_spot::BybitSpotTicker;
+use crate::bybit_trades::BybitTrades;
+use crate::config::ClickhouseBatchConfig;
+use crate::parser::BybitOTT;
+use crate::sink::Sink;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use clickhouse::{self, Client, RowOwned, RowWrite, inserter::Inserter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// Used when an inserter has no commit period, only to wake up for stats.
+const IDLE_WAKEUP: Duration = Duration::from_secs(60);
+
+enum TableCommand<T> {
+    Rows(Vec<T>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Throughput and commit latency of one table since the last report.
+struct TableStats {
+    rows: u64,
+    commits: u64,
+    commit_time: Duration,
+    max_commit: Duration,
+    since: Instant,
+}
+
+impl TableStats {
+    fn new() -> Self {
+        Self {
+            rows: 0,
+            commits: 0,
+            commit_time: Duration::ZERO,
+            max_commit: Duration::ZERO,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, rows: u64, latency: Duration) {
+        self.rows += rows;
+        self.commits += 1;
+        self.commit_time += latency;
+        self.max_commit = self.max_commit.max(latency);
+    }
+
+    fn report(&mut self, table: &str, queued: usize, interval: Duration) {
+        let elapsed = self.since.elapsed();
+        if elapsed < interval {
+            return;
+        }
+        let avg_commit_ms = match self.commits {
+            0 => 0.0,
+            n => self.commit_time.as_secs_f64() * 1000.0 / n as f64,
+        };
+        info!(
+            target_db = table,
+            rows = self.rows,
+            rows_per_sec = format!("{:.1}", self.rows as f64 / elapsed.as_secs_f64()),
+            commits = self.commits,
+            avg_commit_ms = format!("{:.1}", avg_commit_ms),
+            max_commit_ms = self.max_commit.as_millis() as u64,
+            queued,
+            "Table writer stats:"
+        );
+        *self = Self::new();
+    }
+}
+
+async fn commit<T: RowOwned + RowWrite>(
+    inserter: &mut Inserter<T>,
+    table: &str,
+    stats: &mut TableStats,
+    force: bool,
+) -> Result<()> {
+    let started = Instant::now();
+    let quantities = match force {
+        true => inserter.force_commit().await,
+        false => inserter.commit().await,
+    }
+    .with_context(|| format!("Commit to {} failed", table))?;
+    if quantities.rows > 0 {
+        stats.record(quantities.rows, started.elapsed());
+        info!(target_db = table, rows = quantities.rows, "Data committed:");
+    }
+    Ok(())
+}
+
+/// Owns the inserter of one table. Commits when the inserter's row/byte limits
+/// are hit or its period elapses, independently of the other tables.
+async fn run_table<T: RowOwned + RowWrite + Send + Sync>(
+    table: &'static str,
+    mut inserter: Inserter<T>,
+    mut rx: Receiver<TableCommand<T>>,
+    stats_interval: Duration,
+) -> Result<()> {
+    let mut stats = TableStats::new();
+    loop {
+        let wakeup = inserter
+            .time_left()
+            .unwrap_or(IDLE_WAKEUP)
+            .min(stats_interval);
+        tokio::select! {
+            command = rx.recv() => match command {
+                Some(TableCommand::Rows(rows)) => {
+                    for row in &rows {
+                        inserter.write(row).await?;
+                    }
+                    commit(&mut inserter, table, &mut stats, false).await?;
+                }
+                Some(TableCommand::Flush(done)) => {
+                    match commit(&mut inserter, table, &mut stats, true).await {
+                        Ok(()) => {
+                            let _ = done.send(Ok(()));
+                        }
+                        Err(e) => {
+                            let _ = done.send(Err(anyhow::anyhow!("{:#}", e)));
+                            return Err(e);
+                        }
+                    }
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep(wakeup) => {
+                commit(&mut inserter, table, &mut stats, false).await?;
+            }
+        }
+        stats.report(table, rx.len(), stats_interval);
+    }
+    inserter
+        .end()
+        .await
+        .with_context(|| format!("Final commit to {} failed", table))?;
+    Ok(())
+}
+
+struct TableWriter<T> {
+    table: &'static str,
+    tx: Sender<TableCommand<T>>,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl<T: RowOwned + RowWrite + Send + Sync> TableWriter<T> {
+    fn spawn(table: &'static str, inserter: Inserter<T>, config: &ClickhouseBatchConfig) -> Self {
+        let inserter = inserter
+            .with_max_rows(config.max_rows)
+            .with_max_bytes(config.max_bytes);
+        let (tx, rx) = mpsc::channel(config.table_queue);
+        let stats_interval = Duration::from_secs(config.stats_secs);
+        Self {
+            table,
+            tx,
+            task: Some(tokio::spawn(run_table(table, inserter, rx, stats_interval))),
+        }
+    }
+
+    /// The error the table task stopped with.
+    async fn failure(&mut self) -> anyhow::Error {
+        match self.task.take() {
+            Some(task) => match task.await {
+                Ok(Err(e)) => e.context(format!("{} writer failed", self.table)),
+                Ok(Ok(())) => anyhow::anyhow!("{} writer stopped", self.table),
+                Err(e) => anyhow::anyhow!("{} writer panicked: {:?}", self.table, e),
+            },
+            None => anyhow::anyhow!("{} writer failed earlier", self.table),
+        }
+    }
+
+    async fn send(&mut self, rows: Vec<T>) -> Result<()> {
+        if self.tx.send(TableCommand::Rows(rows)).await.is_err() {
+            return Err(self.failure().await);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(TableCommand::Flush(done_tx)).await.is_err() {
+            return Err(self.failure().await);
+        }
+        match done_rx.await {
+            Ok(result) => result,
+            Err(_) => Err(self.failure().await),
+        }
+    }
+
+    async fn close(mut self) -> Result<()> {
+        drop(self.tx);
+        match self.task.take() {
+            Some(task) => task
+                .await
+                .with_context(|| format!("{} writer panicked", self.table))?,
+            None => Ok(()),
+        }
+    }
+}
+
+fn inserter<T: RowOwned + RowWrite>(client: &Client, table: &str, period: u64) -> Inserter<T> {
+    client
+        .inserter::<T>(table)
+        .with_period(Some(Duration::from_secs(period)))
+        .with_period_bias(0.2)
+}
+
+/// Writes every table through its own task and bounded queue, so a slow insert
+/// into one table does not hold back the others.
+pub struct ClickhouseSink {
+    orderbook: TableWriter<BybitOrderbook>,
+    orderbook_levels: TableWriter<BybitOrderbookLevels>,
+    trades: TableWriter<BybitTrades>,
+    ticker: TableWriter<BybitTicker>,
+    ticker_sparse: TableWriter<BybitTickerSparse>,
+    ticker_spot: TableWriter<BybitSpotTicker>,
+    ticker_option: TableWriter<BybitOptionTicker>,
+    funding: TableWriter<BybitFundingEvent>,
+    liquidations: TableWriter<BybitLiquidations>,
+    kline: TableWriter<BybitKline>,
+    open_interest: TableWriter<BybitOpenInterest>,
+    long_short_ratio: TableWriter<BybitLongShortRatio>,
+    historical_volatility: TableWriter<BybitHistoricalVolatility>,
+}
+
+impl ClickhouseSink {
+    pub fn new(client: &Client, config: &ClickhouseBatchConfig) -> Self {
+        let c = config;
+        // Settlements and REST polls are rare, so commit them right away.
+        let rare = ClickhouseBatchConfig {
+            max_rows: 1,
+            ..config.clone()
+        };
+        Self {
+            orderbook: TableWriter::spawn(
+                "orderbook_raw_ml",
+                inserter(client, "orderbook_raw_ml", 5),
+                c,
+            ),
+            orderbook_levels: TableWriter::spawn(
+                "orderbook_levels_raw_ml",
+                inserter(client, "orderbook_levels_raw_ml", 5),
+                c,
+            ),
+            trades: TableWriter::spawn("trades_raw_ml", inserter(client, "trades_raw_ml", 1), c),
+            ticker: TableWriter::spawn("ticker_raw_ml", inserter(client, "ticker_raw_ml", 1), c),
+            ticker_sparse: TableWriter::spawn(
+                "ticker_sparse_raw_ml",
+                inserter(client, "ticker_sparse_raw_ml", 1),
+                c,
+            ),
+            ticker_spot: TableWriter::spawn(
+                "ticker_spot_raw_ml",
+                inserter(client, "ticker_spot_raw_ml", 1),
+                c,
+            ),
+            ticker_option: TableWriter::spawn(
+                "ticker_option_raw_ml",
+                inserter(client, "ticker_option_raw_ml", 1),
+                c,
+            ),
+            funding: TableWriter::spawn("funding_events", client.inserter("funding_events"), &rare),
+            liquidations: TableWriter::spawn(
+                "liquidations_raw_ml",
+                inserter(client, "liquidations_raw_ml", 1),
+                c,
+            ),
+            kline: TableWriter::spawn("kline_raw_ml", inserter(client, "kline_raw_ml", 1), c),
+            open_interest: TableWriter::spawn(
+                "open_interest_history",
+                client.inserter("open_interest_history"),
+                &rare,
+            ),
+            long_short_ratio: TableWriter::spawn(
+                "long_short_ratio",
+                client.inserter("long_short_ratio"),
+                &rare,
+            ),
+            historical_volatility: TableWriter::spawn(
+                "historical_volatility",
+                client.inserter("historical_volatility"),
+                &rare,
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for ClickhouseSink {
+    fn name(&self) -> &str {
+        "clickhouse"
+    }
+
+    /// Moves the rows to their table task. They are only copied when another
+    /// sink still holds the record.
+    async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()> {
+        match Arc::unwrap_or_clone(record) {
+            BybitOTT::Ticker(ticker) => self.ticker.send(vec![*ticker]).await,
+            BybitOTT::TickerSparse(ticker) => self.ticker_sparse.send(vec![ticker]).await,
+            BybitOTT::TickerSpot(ticker) => self.ticker_spot.send(vec![*ticker]).await,
+            BybitOTT::TickerOption(ticker) => self.ticker_option.send(vec![*ticker]).await,
+            BybitOTT::Funding(funding) => self.funding.send(vec![funding]).await,
+            BybitOTT::Orderbook(orderbook) => self.orderbook.send(orderbook).await,
+            BybitOTT::OrderbookLevels(levels) => self.orderbook_levels.send(vec![*levels]).await,
+            BybitOTT::Trades(trades) => self.trades.send(trades).await,
+            BybitOTT::Liquidations(liquidations) => self.liquidations.send(liquidations).await,
+            BybitOTT::Klines(klines) => self.kline.send(klines).await,
+            BybitOTT::OpenInterest(rows) => self.open_interest.send(rows).await,
+            BybitOTT::LongShortRatio(rows) => self.long_short_ratio.send(rows).await,
+            BybitOTT::HistoricalVolatility(rows) => self.historical_volatility.send(rows).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.orderbook.flush().await?;
+        self.orderbook_levels.flush().await?;
+        self.trades.flush().await?;
+        self.ticker.flush().await?;
+        self.ticker_sparse.flush().await?;
+        self.ticker_spot.flush().await?;
+        self.ticker_option.flush().await?;
+        self.funding.flush().await?;
+        self.liquidations.flush().await?;
+        self.kline.flush().await?;
+        self.open_interest.flush().await?;
+        self.long_short_ratio.flush().await?;
+        self.historical_volatility.flush().await?;
+        Ok(())
+    }
+
+    async fn close(self: Box<Self>) -> Result<()> {
+        self.orderbook.close().await?;
+        self.orderbook_levels.close().await?;
+        self.trades.close().await?;
+        self.ticker.close().await?;
+        self.ticker_sparse.close().await?;
+        self.ticker_spot.close().await?;
+        self.ticker_option.close().await?;
+        self.funding.close().await?;
+        self.liquidations.close().await?;
+        self.kline.close().await?;
+        self.open_interest.close().await?;
+        self.long_short_ratio.close().await?;
+        self.historical_volatility.close().await?;
+        info!("ClickHouse sink closed.");
+        Ok(())
+    }
+}
@@ -0,0 +1,802 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const CONFIG_ENV: &str = "BYBIT_FETCHER_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub streams: StreamsConfig,
+    pub ticker: TickerConfig,
+    pub orderbook: OrderbookConfig,
+    pub parser: ParserConfig,
+    pub channels: ChannelsConfig,
+    pub rest: RestConfig,
+    pub recorder: RecorderConfig,
+    pub replay: ReplayConfig,
+    pub supervisor: SupervisorConfig,
+    pub clickhouse: ClickhouseConfig,
+    pub migrations: MigrationsConfig,
+    /// Retention per table, keyed by table name. Tables not listed keep their data.
+    pub retention: BTreeMap<String, RetentionPolicy>,
+    pub rollups: RollupsConfig,
+    /// Output sinks. The first one is primary, the rest are best-effort copies.
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            streams: StreamsConfig::default(),
+            ticker: TickerConfig::default(),
+            orderbook: OrderbookConfig::default(),
+            parser: ParserConfig::default(),
+            channels: ChannelsConfig::default(),
+            rest: RestConfig::default(),
+            recorder: RecorderConfig::default(),
+            replay: ReplayConfig::default(),
+            supervisor: SupervisorConfig::default(),
+            clickhouse: ClickhouseConfig::default(),
+            migrations: MigrationsConfig::default(),
+            retention: BTreeMap::new(),
+            rollups: RollupsConfig::default(),
+            sinks: vec![SinkConfig {
+                kind: SinkKind::Clickhouse {
+                    batch: ClickhouseBatchConfig::default(),
+                },
+                queue: default_sink_queue(),
+                spool: None,
+            }],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Records buffered for this sink before it applies backpressure (primary)
+    /// or starts dropping (secondary).
+    #[serde(default = "default_sink_queue")]
+    pub queue: usize,
+    /// Buffer records on disk while the sink is failing.
+    pub spool: Option<SpoolConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// Records arriving while the spool holds this many bytes are dropped.
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_spool_segment_bytes")]
+    pub segment_bytes: u64,
+    /// How often the sink is flushed to confirm the records it was given.
+    #[serde(default = "default_spool_checkpoint_secs")]
+    pub checkpoint_secs: u64,
+    #[serde(default = "default_spool_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_spool_max_bytes() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_spool_segment_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_spool_checkpoint_secs() -> u64 {
+    5
+}
+
+fn default_spool_max_backoff_secs() -> u64 {
+    60
+}
+
+fn default_sink_queue() -> usize {
+    10_000
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkKind {
+    Clickhouse {
+        #[serde(default)]
+        batch: ClickhouseBatchConfig,
+    },
+    /// Parquet files under `{dir}/{table}/date=YYYY-MM-DD/symbol={symbol}/`.
+    Parquet {
+        dir: PathBuf,
+        #[serde(default)]
+        rotation: ParquetRotation,
+        /// A file is finalized once it grows past this size, whatever the rotation.
+        #[serde(default = "default_max_file_bytes")]
+        max_file_bytes: usize,
+    },
+    /// Trades, orderbook and linear tickers in a local DuckDB database file.
+    Duckdb { path: PathBuf },
+    /// Trades, orderbook and linear tickers in PostgreSQL, as TimescaleDB
+    /// hypertables unless `hypertables` is off.
+    Postgres {
+        /// libpq style connection string, e.g. `host=localhost user=postgres`.
+        url: String,
+        #[serde(default = "default_hypertables")]
+        hypertables: bool,
+    },
+}
+
+/// Commit policy of the per-table ClickHouse writers. A table commits when it
+/// has buffered `max_rows` rows or `max_bytes` bytes, or when its period elapses.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClickhouseBatchConfig {
+    pub max_rows: u64,
+    pub max_bytes: u64,
+    /// Batches queued per table before the sink waits for that table.
+    pub table_queue: usize,
+    /// How often each table logs its throughput and commit latency.
+    pub stats_secs: u64,
+}
+
+impl Default for ClickhouseBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_rows: 100_000,
+            max_bytes: 64 * 1024 * 1024,
+            table_queue: 1_000,
+            stats_secs: 60,
+        }
+    }
+}
+
+fn default_max_file_bytes() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_hypertables() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParquetRotation {
+    /// One file per symbol and hour of server time.
+    #[default]
+    Hourly,
+    /// One file per symbol and day, split only by `max_file_bytes`.
+    Size,
+}
+
+/// Bybit product category. Each category has its own public WebSocket endpoint.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Linear,
+    Spot,
+    Option,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::Linear, Category::Spot, Category::Option];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Linear => "linear",
+            Category::Spot => "spot",
+            Category::Option => "option",
+        }
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("wss://stream.bybit.com/v5/public/{}", self.as_str())
+    }
+}
+
+/// What a topic carries, from its name up to the first dot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicKind {
+    Trades,
+    Liquidations,
+    Klines,
+    Orderbook,
+    Ticker,
+}
+
+impl TopicKind {
+    pub fn of(topic: &str) -> Option<Self> {
+        let prefix = topic.split_once('.').map_or(topic, |(prefix, _)| prefix);
+        match prefix {
+            "publicTrade" => Some(Self::Trades),
+            "allLiquidation" => Some(Self::Liquidations),
+            "kline" => Some(Self::Klines),
+            "orderbook" => Some(Self::Orderbook),
+            "tickers" => Some(Self::Ticker),
+            _ => None,
+        }
+    }
+}
+
+/// Topics to subscribe to, per category.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamsConfig {
+    pub linear: Vec<String>,
+    pub spot: Vec<String>,
+    pub option: Vec<String>,
+}
+
+impl Default for StreamsConfig {
+    fn default() -> Self {
+        // Liquidations (`allLiquidation.{symbol}`) and candles
+        // (`kline.{interval}.{symbol}`) are opt-in.
+        let linear = [
+            "publicTrade.BTCUSDT",
+            "orderbook.50.BTCUSDT",
+            "tickers.BTCUSDT",
+            "publicTrade.ETHUSDT",
+            "orderbook.50.ETHUSDT",
+            "tickers.ETHUSDT",
+            "tickers.ELSAUSDT",
+            "publicTrade.ELSAUSDT",
+            "orderbook.50.ELSAUSDT",
+        ];
+        Self {
+            linear: linear.iter().map(|t| t.to_string()).collect(),
+            spot: Vec::new(),
+            option: Vec::new(),
+        }
+    }
+}
+
+impl StreamsConfig {
+    /// Every topic needs a parser. Spot and option connections only carry
+    /// tickers, which have their own models.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(topic) = self.linear.iter().find(|t| TopicKind::of(t).is_none()) {
+            anyhow::bail!("Unsupported linear topic {}", topic);
+        }
+        for (category, topics) in [
+            (Category::Spot, &self.spot),
+            (Category::Option, &self.option),
+        ] {
+            if let Some(topic) = topics
+                .iter()
+                .find(|t| TopicKind::of(t) != Some(TopicKind::Ticker))
+            {
+                anyhow::bail!(
+                    "Unsupported {} topic {}: only tickers are supported",
+                    category.as_str(),
+                    topic
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Non-empty topic lists with their category.
+    pub fn by_category(&self) -> Vec<(Category, Vec<String>)> {
+        [
+            (Category::Linear, &self.linear),
+            (Category::Spot, &self.spot),
+            (Category::Option, &self.option),
+        ]
+        .into_iter()
+        .filter(|(_, topics)| !topics.is_empty())
+        .map(|(category, topics)| (category, topics.clone()))
+        .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TickerConfig {
+    /// Write only the fields present in each ticker message to `ticker_sparse_raw_ml`
+    /// instead of the full cached row to `ticker_raw_ml`.
+    pub sparse: bool,
+    /// Drop the whole ticker message when any of its fields fails to parse.
+    pub strict: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct OrderbookConfig {
+    pub layout: OrderbookLayout,
+}
+
+/// Parsing is split across `workers` tasks by symbol, each owning the caches
+/// of its symbols.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ParserConfig {
+    pub workers: usize,
+    /// Seconds between the throughput reports of each worker.
+    pub stats_secs: u64,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            stats_secs: 60,
+        }
+    }
+}
+
+/// The channels between the pipeline stages.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChannelsConfig {
+    /// Frames from the sockets to the parser, and from the dispatcher to
+    /// each parser worker.
+    pub parser: ChannelConfig,
+    /// Parsed records to the writer.
+    pub writer: ChannelConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChannelConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// Directory of the spill files, required by `spill`.
+    pub spill_dir: Option<PathBuf>,
+    /// Size of the spilled items not received yet at which senders wait like
+    /// with `block`.
+    pub spill_max_bytes: u64,
+    /// Size at which a new spill segment is started.
+    pub spill_segment_bytes: u64,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000,
+            overflow: OverflowPolicy::default(),
+            spill_dir: None,
+            spill_max_bytes: 1024 * 1024 * 1024,
+            spill_segment_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChannelConfig {
+    fn validate(&self, name: &str) -> Result<()> {
+        anyhow::ensure!(
+            self.capacity > 0,
+            "channels.{}.capacity must be at least 1",
+            name
+        );
+        anyhow::ensure!(
+            self.overflow != OverflowPolicy::Spill || self.spill_dir.is_some(),
+            "channels.{}.overflow = \"spill\" needs spill_dir",
+            name
+        );
+        Ok(())
+    }
+}
+
+/// What a full channel does with the next item.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// The sender waits for room.
+    #[default]
+    Block,
+    /// The oldest queued item is discarded to make room.
+    DropOldest,
+    /// The new item is discarded.
+    DropNewest,
+    /// Items that do not fit go to a file and are read back in order.
+    Spill,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderbookLayout {
+    /// One row per price level in `orderbook_raw_ml`.
+    #[default]
+    Rows,
+    /// One row per update in `orderbook_levels_raw_ml`, with the levels of
+    /// each side in parallel arrays sorted from the best price outwards.
+    Arrays,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClickhouseConfig {
+    /// Replica endpoints in order of preference. New connections go to the
+    /// last one that worked and move on to the next when it is unreachable.
+    pub urls: Vec<String>,
+    pub user: String,
+    pub password: String,
+    /// Create tables as `ReplicatedMergeTree` on this cluster with a
+    /// `Distributed` table in front. Needs the `{shard}` and `{replica}` macros.
+    pub cluster: Option<String>,
+    /// PEM file with extra CA certificates trusted for `https://` endpoints.
+    pub ca_cert: Option<PathBuf>,
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for ClickhouseConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec!["http://localhost:8123".to_string()],
+            user: "default".to_string(),
+            password: "yourpassword".to_string(),
+            cluster: None,
+            ca_cert: None,
+            connect_timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MigrationsConfig {
+    /// Log the pending schema migrations, retention changes and rollups and
+    /// exit without applying them.
+    pub dry_run: bool,
+}
+
+/// TTL rules and storage policy of one table, ages counted from its event time.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Set before `move_to_volume` when the table is still on a policy without that volume.
+    pub storage_policy: Option<String>,
+    pub move_after_days: Option<u32>,
+    pub move_to_volume: Option<String>,
+    pub recompress_after_days: Option<u32>,
+    pub recompress_codec: String,
+    pub delete_after_days: Option<u32>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            storage_policy: None,
+            move_after_days: None,
+            move_to_volume: None,
+            recompress_after_days: None,
+            recompress_codec: "ZSTD(9)".to_string(),
+            delete_after_days: None,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// The codec goes into the TTL clause as written, so only codec syntax with
+    /// balanced parentheses is allowed. Policy and volume names are quoted
+    /// into the DDL, so they are limited to identifier characters.
+    fn validate(&self, table: &str) -> Result<()> {
+        for (key, name) in [
+            ("storage_policy", &self.storage_policy),
+            ("move_to_volume", &self.move_to_volume),
+        ] {
+            let Some(name) = name else {
+                continue;
+            };
+            anyhow::ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_-".contains(c)),
+                "retention.{}.{} {:?} may only contain letters, digits, underscores and dashes",
+                table,
+                key,
+                name
+            );
+        }
+
+        let codec = &self.recompress_codec;
+        let mut depth = 0i32;
+        let valid = !codec.trim().is_empty()
+            && codec.chars().all(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                depth >= 0 && (c.is_ascii_alphanumeric() || "(), ".contains(c))
+            })
+            && depth == 0;
+        anyhow::ensure!(
+            valid,
+            "retention.{}.recompress_codec {:?} may only contain letters, digits, commas, spaces and balanced parentheses",
+            table,
+            codec
+        );
+        Ok(())
+    }
+}
+
+/// Materialized rollups to create, each with the intervals to aggregate at,
+/// like "1s", "5m", "1h" or "1d". See `rollups.rs` for the columns.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RollupsConfig {
+    /// Trade count, volume, turnover and OHLC from `trades_raw_ml`.
+    pub trade_stats: Vec<String>,
+    /// Best bid and ask at the end of each interval from `orderbook_raw_ml`.
+    pub top_of_book: Vec<String>,
+    /// Funding rate, mark price and open interest from `ticker_raw_ml`.
+    pub funding: Vec<String>,
+}
+
+/// Archive of every raw WebSocket text frame, see `recorder.rs` for the file format.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RecorderConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Start a new file after this many seconds...
+    pub rotate_secs: u64,
+    /// ...or once this many uncompressed payload bytes were written to it.
+    pub max_file_bytes: u64,
+    /// zstd compression level.
+    pub level: i32,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("data/raw"),
+            rotate_secs: 3600,
+            max_file_bytes: 1024 * 1024 * 1024,
+            level: 3,
+        }
+    }
+}
+
+/// Feeds recorded frames to the parser instead of connecting to Bybit.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReplayConfig {
+    pub enabled: bool,
+    /// Recorder directory holding the archives and `index.jsonl`.
+    pub dir: PathBuf,
+    /// Files to replay, relative to `dir`. Empty means everything in the index.
+    pub files: Vec<String>,
+    /// 0 replays as fast as possible, 1 in real time, N at N times real time.
+    pub speed: f64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: RecorderConfig::default().dir,
+            files: Vec::new(),
+            speed: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    /// An internal task failing more than `max_restarts` times within
+    /// `restart_window_secs` shuts the process down with an error. Sources
+    /// reconnect without a limit.
+    pub max_restarts: usize,
+    pub restart_window_secs: u64,
+    /// Serves task health as JSON over HTTP, e.g. "127.0.0.1:9100".
+    pub health_addr: Option<String>,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            restart_window_secs: 600,
+            health_addr: None,
+        }
+    }
+}
+
+/// Scheduled polling of REST-only market statistics.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RestConfig {
+    pub base_url: String,
+    /// Upper bound on requests per second across all endpoints.
+    pub requests_per_second: u32,
+    pub endpoints: Vec<RestEndpoint>,
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.bybit.com".to_string(),
+            requests_per_second: 5,
+            endpoints: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RestEndpoint {
+    /// `/v5/market/open-interest`, `interval_time` is one of 5min, 15min, 30min, 1h, 4h, 1d.
+    OpenInterest {
+        symbol: String,
+        interval_time: String,
+        poll_secs: u64,
+    },
+    /// `/v5/market/account-ratio`, `period` uses the same values as `interval_time`.
+    LongShortRatio {
+        symbol: String,
+        period: String,
+        poll_secs: u64,
+    },
+    /// `/v5/market/historical-volatility` for options, `period` in days.
+    HistoricalVolatility {
+        base_coin: String,
+        period: u32,
+        poll_secs: u64,
+    },
+}
+
+impl RestEndpoint {
+    pub fn poll_secs(&self) -> u64 {
+        match self {
+            RestEndpoint::OpenInterest { poll_secs, .. }
+            | RestEndpoint::LongShortRatio { poll_secs, .. }
+            | RestEndpoint::HistoricalVolatility { poll_secs, .. } => *poll_secs,
+        }
+    }
+}
+
+impl Config {
+    /// Whether any sink writes to ClickHouse, so its tables exist.
+    pub fn uses_clickhouse(&self) -> bool {
+        self.sinks
+            .iter()
+            .any(|s| matches!(s.kind, SinkKind::Clickhouse { .. }))
+    }
+
+    /// Loads the config from `$BYBIT_FETCHER_CONFIG` (or `config.toml`).
+    /// Falls back to defaults when the file does not exist.
+    pub fn load() -> Result<Self> {
+        let path = std::env::var(CONFIG_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        if !Path::new(&path).exists() {
+            info!("Config file {} not found, using defaults.", path);
+            return Ok(Self::default());
+        }
+        let raw = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path))?;
+        let config: Self = toml::from_str(&raw)
+            .with_context(|| format!("Failed to parse config file {}", path))?;
+        config.validate()?;
+        info!("Config loaded from {}.", path);
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.streams.validate()?;
+        anyhow::ensure!(self.parser.workers > 0, "parser.workers must be at least 1");
+        self.channels.parser.validate("parser")?;
+        self.channels.writer.validate("writer")?;
+        for (table, policy) in &self.retention {
+            policy.validate(table)?;
+        }
+        // The rollup reads orderbook_raw_ml, which only the rows layout fills.
+        anyhow::ensure!(
+            self.rollups.top_of_book.is_empty() || self.orderbook.layout == OrderbookLayout::Rows,
+            "rollups.top_of_book needs orderbook.layout = \"rows\""
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recompress_codec_allows_only_codec_syntax() {
+        let policy = |codec: &str| RetentionPolicy {
+            recompress_codec: codec.to_string(),
+            ..RetentionPolicy::default()
+        };
+        for codec in ["ZSTD(9)", "LZ4HC", "Delta(8), ZSTD(3)"] {
+            assert!(policy(codec).validate("t").is_ok(), "{}", codec);
+        }
+        for codec in [
+            "",
+            "ZSTD(9)) DELETE WHERE (1",
+            "ZSTD(9); DROP TABLE t",
+            "ZSTD('9')",
+            "ZSTD(9",
+        ] {
+            assert!(policy(codec).validate("t").is_err(), "{}", codec);
+        }
+    }
+
+    #[test]
+    fn storage_names_allow_only_identifiers() {
+        let policy = |name: &str| RetentionPolicy {
+            storage_policy: Some(name.to_string()),
+            move_after_days: Some(7),
+            move_to_volume: Some(name.to_string()),
+            ..RetentionPolicy::default()
+        };
+        for name in ["hot_cold", "cold-s3", "Volume2"] {
+            assert!(policy(name).validate("t").is_ok(), "{}", name);
+        }
+        for name in ["", "cold' DELETE", "cold volume", "s3;"] {
+            assert!(policy(name).validate("t").is_err(), "{}", name);
+        }
+        let volume_only = RetentionPolicy {
+            move_after_days: Some(7),
+            move_to_volume: Some("cold'".to_string()),
+            ..RetentionPolicy::default()
+        };
+        assert!(volume_only.validate("t").is_err());
+    }
+
+    #[test]
+    fn top_of_book_needs_the_rows_layout() {
+        let mut config = Config::default();
+        config.rollups.top_of_book = vec!["1s".to_string()];
+        assert!(config.validate().is_ok());
+        config.orderbook.layout = OrderbookLayout::Arrays;
+        assert!(config.validate().is_err());
+        config.rollups.top_of_book.clear();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn topic_kind_comes_from_the_prefix() {
+        assert_eq!(
+            TopicKind::of("publicTrade.BTCUSDT"),
+            Some(TopicKind::Trades)
+        );
+        assert_eq!(
+            TopicKind::of("allLiquidation.BTCUSDT"),
+            Some(TopicKind::Liquidations)
+        );
+        assert_eq!(TopicKind::of("kline.1.BTCUSDT"), Some(TopicKind::Klines));
+        assert_eq!(
+            TopicKind::of("orderbook.50.BTCUSDT"),
+            Some(TopicKind::Orderbook)
+        );
+        assert_eq!(
+            TopicKind::of("tickers.BTC-27DEC24-100000-C"),
+            Some(TopicKind::Ticker)
+        );
+        assert_eq!(TopicKind::of("tickers"), Some(TopicKind::Ticker));
+        for topic in [
+            "",
+            "trade.BTCUSDT",
+            "Tickers.BTCUSDT",
+            "orderbookX.50.BTCUSDT",
+            ".tickers",
+        ] {
+            assert_eq!(TopicKind::of(topic), None, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn streams_reject_topics_without_a_parser() {
+        let streams = |linear: &[&str], spot: &[&str]| StreamsConfig {
+            linear: linear.iter().map(|t| t.to_string()).collect(),
+            spot: spot.iter().map(|t| t.to_string()).collect(),
+            option: Vec::new(),
+        };
+        assert!(StreamsConfig::default().validate().is_ok());
+        assert!(
+            streams(&["tickers.BTCUSDT"], &["tickers.BTCUSDT"])
+                .validate()
+                .is_ok()
+        );
+        assert!(streams(&["liquidation.BTCUSDT"], &[]).validate().is_err());
+        assert!(streams(&[], &["publicTrade.BTCUSDT"]).validate().is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Splits the frames between parser workers by symbol.
+//!
+//! Every frame of a symbol goes to the same worker, so the messages of one
+//! symbol and topic are parsed in the order they arrived while different
+//! symbols are parsed in parallel. Each worker owns the orderbook and ticker
+//! caches of its symbols.
+
+use crate::parser::Frame;
+use crate::queue::{Receiver, Sender};
+use anyhow::{Context, Result};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tracing::info;
+
+/// Symbol at the end of the frame's topic, e.g. `BTCUSDT` in
+/// `{"topic":"orderbook.50.BTCUSDT",...}`, found without parsing the message.
+fn topic_symbol(message: &str) -> Option<&str> {
+    const KEY: &str = "\"topic\":\"";
+    let topic = &message[message.find(KEY)? + KEY.len()..];
+    let topic = &topic[..topic.find('"')?];
+    Some(topic.rsplit_once('.').map_or(topic, |(_, symbol)| symbol))
+}
+
+/// Worker for a frame. Frames without a topic, such as replies to `subscribe`,
+/// all go to the same worker.
+pub fn worker_of(message: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    topic_symbol(message).hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+pub async fn dispatch(parser_rx: &mut Receiver<Frame>, workers: &[Sender<Frame>]) -> Result<()> {
+    info!("Dispatching frames to {} parser workers.", workers.len());
+    while let Some(frame) = parser_rx.recv().await {
+        let worker = worker_of(&frame.message, workers.len());
+        workers[worker]
+            .send(frame)
+            .await
+            .with_context(|| format!("Parser worker {} is gone", worker))?;
+    }
+    info!("Parser channel closed. Exiting dispatcher.");
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Local DuckDB database with the trades, orderbook and ticker tables of the
+//! ClickHouse schema, for running without a ClickHouse server. Appends run on
+//! the sink's own thread behind a `ThreadSink`.
+
+use crate::arrow_record::ArrowRecord;
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_ticker::BybitTicker;
+use crate::bybit_trades::BybitTrades;
+use crate::parser::BybitOTT;
+use crate::sink::BlockingSink;
+use anyhow::{Context, Result};
+use duckdb::Connection;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Rows buffered per table before they are appended.
+const BATCH_ROWS: usize = 10_000;
+const APPEND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Same columns and types as the ClickHouse tables. `DateTime64(3, 'UTC')` is
+/// `TIMESTAMPTZ` and `Decimal128(18)` is `DECIMAL(38, 18)`.
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS trades_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ,
+        received_timestamp      TIMESTAMPTZ,
+        trade_timestamp         TIMESTAMPTZ,
+        symbol                  VARCHAR,
+        trade_id                VARCHAR,
+        side                    VARCHAR,
+        price                   DECIMAL(38, 18),
+        volume                  DECIMAL(38, 18),
+        tick_direction          VARCHAR,
+        is_block_trade          BOOLEAN,
+        is_rpi                  BOOLEAN,
+        seq                     UBIGINT,
+        exchange                VARCHAR DEFAULT 'bybit'
+    );
+
+    CREATE TABLE IF NOT EXISTS orderbook_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ,
+        received_timestamp      TIMESTAMPTZ,
+        client_timestamp        TIMESTAMPTZ,
+        symbol                  VARCHAR,
+        side                    VARCHAR,
+        price                   DECIMAL(38, 18),
+        volume                  DECIMAL(38, 18),
+        "update"                UBIGINT,
+        exchange                VARCHAR DEFAULT 'bybit'
+    );
+
+    CREATE TABLE IF NOT EXISTS orderbook_levels_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ,
+        received_timestamp      TIMESTAMPTZ,
+        client_timestamp        TIMESTAMPTZ,
+        symbol                  VARCHAR,
+        "update"                UBIGINT,
+        bid_prices              DECIMAL(38, 18)[],
+        bid_sizes               DECIMAL(38, 18)[],
+        ask_prices              DECIMAL(38, 18)[],
+        ask_sizes               DECIMAL(38, 18)[],
+        exchange                VARCHAR DEFAULT 'bybit'
+    );
+
+    CREATE TABLE IF NOT EXISTS ticker_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ,
+        received_timestamp      TIMESTAMPTZ,
+        cross_sequence          UBIGINT,
+        symbol                  VARCHAR,
+        tick_direction          VARCHAR,
+        price_24h_pcnt          DECIMAL(38, 18),
+        last_price              DECIMAL(38, 18),
+        prev_price_24h          DECIMAL(38, 18),
+        high_price_24h          DECIMAL(38, 18),
+        low_price_24h           DECIMAL(38, 18),
+        prev_price_1h           DECIMAL(38, 18),
+        mark_price              DECIMAL(38, 18),
+        index_price             DECIMAL(38, 18),
+        open_interest           DECIMAL(38, 18),
+        open_interest_value     DECIMAL(38, 18),
+        turnover_24h            DECIMAL(38, 18),
+        volume_24h              DECIMAL(38, 18),
+        next_funding_time       TIMESTAMPTZ,
+        funding_rate            DECIMAL(38, 18),
+        bid1_price              DECIMAL(38, 18),
+        bid1_size               DECIMAL(38, 18),
+        ask1_price              DECIMAL(38, 18),
+        ask1_size               DECIMAL(38, 18),
+        delivery_time           TIMESTAMPTZ,
+        basis_rate              DECIMAL(38, 18),
+        delivery_fee_rate       DECIMAL(38, 18),
+        predicted_delivery_price DECIMAL(38, 18),
+        pre_open_price          DECIMAL(38, 18),
+        pre_qty                 DECIMAL(38, 18),
+        cur_pre_listing_phase   VARCHAR,
+        funding_interval_hour   VARCHAR,
+        funding_cap             DECIMAL(38, 18),
+        basis_rate_year         DECIMAL(38, 18),
+        is_stale                BOOLEAN DEFAULT false,
+        stale_fields            VARCHAR[],
+        exchange                VARCHAR DEFAULT 'bybit'
+    );
+    -- delivery_fee_rate used to be a BIGINT.
+    ALTER TABLE ticker_raw_ml ALTER delivery_fee_rate TYPE DECIMAL(38, 18);
+"#;
+
+/// Appends `rows` to their table by column name, columns the record does not
+/// have get their default.
+fn append<T: ArrowRecord>(conn: &Connection, rows: &mut Vec<T>) -> Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let batch = T::to_batch(rows)?;
+    let mut appender = conn.appender(T::TABLE)?;
+    for field in batch.schema().fields() {
+        appender.add_column(field.name())?;
+    }
+    appender
+        .append_record_batch(batch)
+        .with_context(|| format!("Failed to append to {}", T::TABLE))?;
+    appender.flush()?;
+    rows.clear();
+    Ok(())
+}
+
+pub struct DuckdbSink {
+    conn: Connection,
+    trades: Vec<BybitTrades>,
+    orderbook: Vec<BybitOrderbook>,
+    orderbook_levels: Vec<BybitOrderbookLevels>,
+    ticker: Vec<BybitTicker>,
+    last_append: Instant,
+}
+
+impl DuckdbSink {
+    pub fn new(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn =
+            Connection::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .context("Failed to create DuckDB tables")?;
+        info!("DuckDB database {} opened.", path.display());
+        Ok(Self {
+            conn,
+            trades: Vec::new(),
+            orderbook: Vec::new(),
+            orderbook_levels: Vec::new(),
+            ticker: Vec::new(),
+            last_append: Instant::now(),
+        })
+    }
+
+    fn append_all(&mut self) -> Result<()> {
+        append(&self.conn, &mut self.trades)?;
+        append(&self.conn, &mut self.orderbook)?;
+        append(&self.conn, &mut self.orderbook_levels)?;
+        append(&self.conn, &mut self.ticker)?;
+        self.last_append = Instant::now();
+        Ok(())
+    }
+}
+
+impl BlockingSink for DuckdbSink {
+    fn write(&mut self, record: &BybitOTT) -> Result<()> {
+        match record {
+            BybitOTT::Trades(trades) => self.trades.extend_from_slice(trades),
+            BybitOTT::Orderbook(orderbook) => self.orderbook.extend_from_slice(orderbook),
+            BybitOTT::OrderbookLevels(levels) => {
+                self.orderbook_levels.push(levels.as_ref().clone())
+            }
+            BybitOTT::Ticker(ticker) => self.ticker.push(ticker.as_ref().clone()),
+            _ => return Ok(()),
+        }
+        let buffered = self
+            .trades
+            .len()
+            .max(self.orderbook.len())
+            .max(self.orderbook_levels.len())
+            .max(self.ticker.len());
+        if buffered >= BATCH_ROWS || self.last_append.elapsed() >= APPEND_INTERVAL {
+            self.append_all()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.append_all()
+    }
+}
@@ -1,121 +1,164 @@
-use anyhow::Result;
+use crate::config::{ClickhouseConfig, Config};
+use crate::migrations;
+use crate::retention;
+use crate::rollups;
+use anyhow::{Context, Result};
 use clickhouse::Client;
-use tracing::info;
+use futures_util::future::BoxFuture;
+use http::Uri;
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+use std::time::Duration;
+use tower_service::Service;
+use tracing::{info, warn};
 
-pub async fn load_db() -> Result<Client> {
-    use clickhouse::Client;
-    let password = "yourpassword";
-    let client = Client::default()
-        .with_url("http://localhost:8123")
-        .with_user("default")
-        .with_password(password.to_string())
-        .with_option("async_insert", "1")
-        .with_option("wait_for_async_insert", "0");
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+// ClickHouse closes idle connections after 3s.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
 
-    info!("DB loaded.");
-    client
-        .query(
-            r#"
-        CREATE TABLE IF NOT EXISTS trades_raw_ml
-        (
-            server_timestamp       DateTime64(3, 'UTC'),
-            received_timestamp       DateTime64(3, 'UTC'),
-            trade_timestamp       DateTime64(3, 'UTC'),
-            symbol          LowCardinality(String),
-            trade_id        LowCardinality(String),
-            side            LowCardinality(String),
-            price           Decimal128(18),
-            volume             Decimal128(18),
-            tick_direction  LowCardinality(String),
-            is_block_trade  Bool,
-            is_rpi          Bool,
-            seq             UInt64,
-            exchange        LowCardinality(String) DEFAULT 'bybit'
-        )
-        ENGINE = MergeTree()
-        PARTITION BY toYYYYMMDD(trade_timestamp)
-        ORDER BY (symbol, trade_timestamp, seq)
-        SETTINGS index_granularity = 8192
-        "#,
-        )
-        .execute()
-        .await?;
-    client
-        .query(
-            r#"
-        CREATE TABLE IF NOT EXISTS orderbook_raw_ml
-        (
-            server_timestamp       DateTime64(3, 'UTC'),
-            received_timestamp       DateTime64(3, 'UTC'),
-            client_timestamp       DateTime64(3, 'UTC'),
-            symbol          LowCardinality(String),
-            side            LowCardinality(String),
-            price           Decimal128(18),
-            volume             Decimal128(18),
-            update             UInt64,
-            exchange        LowCardinality(String) DEFAULT 'bybit'
-        )
-        ENGINE = MergeTree()
-        PARTITION BY toYYYYMMDD(server_timestamp)
-        ORDER BY (symbol, server_timestamp, update, side, price)
-        SETTINGS index_granularity = 8192
-        "#,
-        )
-        .execute()
-        .await?;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Opens every new connection to the first reachable endpoint, starting with
+/// the one that worked last. Requests keep the URL of the first endpoint, only
+/// the connection underneath is redirected, so pooled connections to a dead
+/// replica fail once and the retry lands on the next one.
+#[derive(Clone)]
+struct FailoverConnector<C> {
+    inner: C,
+    endpoints: Arc<Vec<Uri>>,
+    current: Arc<AtomicUsize>,
+}
+
+impl<C> Service<Uri> for FailoverConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Send,
+    C::Error: Into<BoxError>,
+    C::Future: Send,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<C::Response, BoxError>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (endpoints, current) = (self.endpoints.clone(), self.current.clone());
+        Box::pin(async move {
+            let start = current.load(Ordering::Relaxed);
+            let mut last_error: BoxError = "no ClickHouse endpoints configured".into();
+            for i in 0..endpoints.len() {
+                let index = (start + i) % endpoints.len();
+                let endpoint = &endpoints[index];
+                std::future::poll_fn(|cx| inner.poll_ready(cx))
+                    .await
+                    .map_err(Into::into)?;
+                match inner.call(endpoint.clone()).await {
+                    Ok(connection) => {
+                        if index != start {
+                            warn!("ClickHouse failed over to {}.", endpoint);
+                            current.store(index, Ordering::Relaxed);
+                        }
+                        return Ok(connection);
+                    }
+                    Err(e) => {
+                        let e = e.into();
+                        warn!("ClickHouse endpoint {} is unreachable: {}", endpoint, e);
+                        last_error = e;
+                    }
+                }
+            }
+            Err(last_error)
+        })
+    }
+}
+
+/// Native roots plus the configured CA, used for `https://` endpoints.
+fn tls_config(config: &ClickhouseConfig) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        warn!("Failed to load a native root certificate: {}", e);
+    }
+    roots.add_parsable_certificates(native.certs);
 
-    client
-        .query(
-            r#"
-        CREATE TABLE IF NOT EXISTS ticker_raw_ml
-        (
-            server_timestamp       DateTime64(3, 'UTC'),
-            received_timestamp       DateTime64(3, 'UTC'),
-            cross_sequence       UInt64,
-            symbol          LowCardinality(String),
-            tick_direction            LowCardinality(String),
-            price_24h_pcnt           Decimal128(18),
-            last_price             Decimal128(18),
-            prev_price_24h             Decimal128(18),
-            high_price_24h             Decimal128(18),
-            low_price_24h              Decimal128(18),
-            prev_price_1h             Decimal128(18),
-            mark_price                Decimal128(18),
+    if let Some(path) = &config.ca_cert {
+        let custom = rustls_native_certs::load_certs_from_paths(Some(path), None);
+        if let Some(e) = custom.errors.into_iter().next() {
+            return Err(e).with_context(|| format!("Failed to load {}", path.display()));
+        }
+        let (added, _) = roots.add_parsable_certificates(custom.certs);
+        anyhow::ensure!(added > 0, "No CA certificates in {}", path.display());
+    }
+    Ok(ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
 
-    index_price Decimal128(18),
-    open_interest Decimal128(18),
-    open_interest_value Decimal128(18),
-    turnover_24h Decimal128(18),
-    volume_24h Decimal128(18),
-    next_funding_time DateTime64(3, 'UTC'),
-    funding_rate Decimal128(18),
-    bid1_price Decimal128(18),
-    bid1_size Decimal128(18),
-    ask1_price Decimal128(18),
-    ask1_size Decimal128(18),
-    delivery_time Nullable(DateTime64(3, 'UTC')),
-    basis_rate Nullable(Decimal128(18)),
-    delivery_fee_rate Nullable(UInt64),
-    predicted_delivery_price Nullable(Decimal128(18)),
-    pre_open_price Nullable(Decimal128(18)),
-    pre_qty Nullable(Decimal128(18)),
-    cur_pre_listing_phase Nullable(String),
-    funding_interval_hour Nullable(String),
-    funding_cap Nullable(Decimal128(18)),
-    basis_rate_year Nullable(Decimal128(18)),
+pub fn connect(config: &ClickhouseConfig) -> Result<Client> {
+    let url = config.urls.first().context("clickhouse.urls is empty")?;
+    let endpoints = config
+        .urls
+        .iter()
+        .map(|u| {
+            u.parse::<Uri>()
+                .with_context(|| format!("Invalid ClickHouse url {}", u))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    exchange        LowCardinality(String) DEFAULT 'bybit',
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_keepalive(Some(TCP_KEEPALIVE));
+    http.set_connect_timeout(Some(Duration::from_secs(config.connect_timeout_secs)));
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config(config)?)
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    let connector = FailoverConnector {
+        inner: https,
+        endpoints: Arc::new(endpoints),
+        current: Arc::new(AtomicUsize::new(0)),
+    };
+    let http_client = HyperClient::builder(TokioExecutor::new())
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .build(connector);
 
-        )
-        ENGINE = MergeTree()
-        PARTITION BY toYYYYMMDD(server_timestamp)
-        ORDER BY (symbol, server_timestamp, cross_sequence)
-        SETTINGS index_granularity = 8192
-        "#,
-        )
-        .execute()
-        .await?;
+    Ok(Client::with_http_client(http_client)
+        .with_url(url)
+        .with_user(&config.user)
+        .with_password(&config.password)
+        .with_option("async_insert", "1")
+        .with_option("wait_for_async_insert", "0"))
+}
+
+pub async fn load_db(config: &Config) -> Result<Client> {
+    let client = connect(&config.clickhouse)?;
+    if !config.uses_clickhouse() && !config.migrations.dry_run {
+        info!("No ClickHouse sink configured, skipping schema setup.");
+        return Ok(client);
+    }
+    info!("DB loaded.");
 
-    info!("Table created or existed.");
+    let (cluster, dry_run) = (
+        config.clickhouse.cluster.as_deref(),
+        config.migrations.dry_run,
+    );
+    migrations::migrate(&client, cluster, dry_run).await?;
+    retention::apply_retention(&client, &config.retention, cluster, dry_run).await?;
+    rollups::create_rollups(&client, &config.rollups, cluster, dry_run).await?;
+    if dry_run {
+        return Ok(client);
+    }
+    migrations::check_schema(&client).await?;
     Ok(client)
 }
@@ -1,35 +1,106 @@
+mod arrow_record;
+mod bench;
+mod bybit_funding;
+mod bybit_kline;
+mod bybit_liquidations;
 mod bybit_orderbook;
+mod bybit_rest;
 mod bybit_ticker;
+mod bybit_ticker_option;
+mod bybit_ticker_spot;
 mod bybit_trades;
+mod clickhouse_sink;
+mod config;
+mod dispatch;
+mod duckdb_sink;
 mod load_db;
+mod migrations;
+mod orderbook_levels;
+mod parquet_sink;
 mod parser;
+mod postgres_sink;
+mod queue;
+mod recorder;
+mod replay;
+mod retention;
+mod rollups;
+mod sink;
+mod spool;
+mod supervisor;
 mod writer;
 
-use crate::bybit_ticker::BybitTicker;
-use crate::bybit_trades::BybitTrades;
-use crate::parser::BybitOTT;
+use crate::config::{Category, Config};
+use crate::parser::{BybitOTT, Frame};
+use crate::recorder::RawFrame;
+use crate::supervisor::{Policy, Supervisor};
 use anyhow::{Context, Result};
-use bybit_orderbook::{BybitOrderbook, OrderbookCache};
-use bybit_ticker::TickerCache;
-use clickhouse::{self, Client, inserter::Inserter};
 use futures_util::{SinkExt, StreamExt};
-use parser::async_parse;
+use parser::{ParserContext, async_parse};
 use rustls::crypto::CryptoProvider;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::{
     self,
     net::TcpStream,
-    sync::mpsc::{Sender, channel},
+    sync::Mutex,
+    sync::mpsc::{Receiver, Sender, channel},
 };
 use tokio_tungstenite::{
     self, MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message,
 };
-use tracing::{Level, error, info};
+use tracing::{Level, error, info, warn};
 use tracing_subscriber::fmt;
 
-pub async fn handle_ws(args: Vec<String>) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-    let url = "wss://stream.bybit.com/v5/public/linear";
-    let (mut ws, _) = connect_async(url).await?;
+/// Identifies a WebSocket connection in the raw recording.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A topic is resubscribed at most this often, which also retries a resync
+/// whose snapshot never came.
+const RESYNC_RETRY: Duration = Duration::from_secs(30);
+
+/// Orderbook topics the parser workers asked to resync. Workers ask again for
+/// every delta they skip, so requests are gathered across workers and each
+/// topic let through at most once per `RESYNC_RETRY`.
+#[derive(Default)]
+struct ResyncRequests {
+    requested: HashMap<String, Instant>,
+}
+
+impl ResyncRequests {
+    /// Waits for the next topics due, `None` once every worker is gone.
+    async fn next(&mut self, rx: &mut Receiver<String>) -> Option<Vec<String>> {
+        loop {
+            let mut topics = Vec::new();
+            let mut topic = rx.recv().await?;
+            loop {
+                let due = self
+                    .requested
+                    .get(&topic)
+                    .is_none_or(|requested| requested.elapsed() >= RESYNC_RETRY);
+                if due && !topics.contains(&topic) {
+                    self.requested.insert(topic.clone(), Instant::now());
+                    topics.push(topic);
+                }
+                match rx.try_recv() {
+                    Ok(next) => topic = next,
+                    Err(_) => break,
+                }
+            }
+            if !topics.is_empty() {
+                return Some(topics);
+            }
+        }
+    }
+}
+
+pub async fn handle_ws(
+    category: Category,
+    args: Vec<String>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let url = category.ws_url();
+    let (mut ws, _) = connect_async(&url).await?;
     info!("Connected via websocket to {:?}", url);
     let sub = serde_json::json!({
         "op": "subscribe",
@@ -40,48 +111,104 @@ pub async fn handle_ws(args: Vec<String>) -> Result<WebSocketStream<MaybeTlsStre
     Ok(ws)
 }
 
-pub async fn setup_inserters(
-    client: &Client,
-) -> (
-    Inserter<BybitOrderbook>,
-    Inserter<BybitTrades>,
-    Inserter<BybitTicker>,
-) {
-    let orderbook_inserter = client
-        .inserter::<BybitOrderbook>("orderbook_raw_ml")
-        .with_max_rows(100)
-        .with_period(Some(Duration::from_secs(5)))
-        .with_period_bias(0.2);
-    let trades_inserter = client
-        .inserter::<BybitTrades>("trades_raw_ml")
-        .with_max_rows(100)
-        .with_period(Some(Duration::from_secs(1)))
-        .with_period_bias(0.2);
-    let ticker_inserter = client
-        .inserter::<BybitTicker>("ticker_raw_ml")
-        .with_max_rows(100)
-        .with_period(Some(Duration::from_secs(1)))
-        .with_period_bias(0.2);
-    (orderbook_inserter, trades_inserter, ticker_inserter)
-}
-
 pub async fn fetch_bybit(
     mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    parser_tx: Sender<String>,
+    category: Category,
+    connection_id: u64,
+    parser_tx: queue::Sender<Frame>,
+    mut recorder_tx: Option<Sender<RawFrame>>,
+    mut resync_rx: Option<&mut Receiver<String>>,
 ) -> Result<()> {
+    let mut resync = ResyncRequests::default();
     loop {
+        let resync_due = async {
+            match resync_rx.as_deref_mut() {
+                Some(rx) => resync.next(rx).await,
+                None => std::future::pending().await,
+            }
+        };
         tokio::select! {
-            Some(Ok(msg))  = ws.next() => match msg {
-                Message::Text(message) => {
-                    parser_tx.send(message.to_string()).await.context("Failed to send to parser channel.")?},
-                Message::Ping(b) => ws.send(Message::Pong(b)).await?,
-                Message::Close(_) => {break},
-                other => println!("Received unexpected message type: {:?}.", other)},
+            msg = ws.next() => match msg.transpose()? {
+                None | Some(Message::Close(_)) => break,
+                Some(Message::Text(message)) => {
+                    let frame = Frame::received_now(category, message);
+                    if let Some(tx) = recorder_tx.as_ref()
+                        && tx.send(RawFrame::new(connection_id, &frame)).await.is_err()
+                    {
+                        warn!("Raw recorder is gone, frames are no longer archived.");
+                        recorder_tx = None;
+                    }
+                    parser_tx.send(frame).await.context("Failed to send to parser channel.")?},
+                Some(Message::Ping(b)) => ws.send(Message::Pong(b)).await?,
+                Some(other) => println!("Received unexpected message type: {:?}.", other)},
+            Some(topics) = resync_due => {
+                // Bybit answers a new subscription with a snapshot.
+                info!(?topics, "Resubscribing orderbooks to resync them.");
+                for op in ["unsubscribe", "subscribe"] {
+                    let request = serde_json::json!({ "op": op, "args": topics });
+                    ws.send(Message::Text(request.to_string().into())).await?;
+                }
+            }
         }
     }
     Ok(())
 }
 
+/// Keeps one category connection alive. Only the linear connection carries
+/// orderbooks, so only it listens for the parsers' resync requests.
+pub async fn run_stream(
+    category: Category,
+    topics: Vec<String>,
+    parser_tx: queue::Sender<Frame>,
+    recorder_tx: Option<Sender<RawFrame>>,
+    mut resync_rx: Option<&mut Receiver<String>>,
+) -> Result<()> {
+    loop {
+        let ws = handle_ws(category, topics.clone()).await?;
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Connection {} carries {} topics.",
+            connection_id,
+            category.as_str()
+        );
+        let res = fetch_bybit(
+            ws,
+            category,
+            connection_id,
+            parser_tx.clone(),
+            recorder_tx.clone(),
+            resync_rx.as_deref_mut(),
+        )
+        .await;
+        if let Err(e) = res {
+            error!("WS Error ({}): {:?}. Reconnecting...", category.as_str(), e);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// What the binary was asked to do on the command line.
+enum Command {
+    Run,
+    BenchParse,
+    Backfill(String),
+    ConvertOrderbook,
+}
+
+const USAGE: &str =
+    "usage: bybit-data-fetcher [bench-parse | backfill <rollup> | convert-orderbook]";
+
+fn parse_args(args: &[String]) -> Result<Command> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Ok(match args.as_slice() {
+        [] => Command::Run,
+        ["bench-parse"] => Command::BenchParse,
+        ["backfill", target] => Command::Backfill(target.to_string()),
+        ["convert-orderbook"] => Command::ConvertOrderbook,
+        _ => anyhow::bail!("Unexpected arguments {:?}, {}", args, USAGE),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     fmt().with_max_level(Level::INFO).with_target(false).init();
@@ -90,64 +217,247 @@ async fn main() -> Result<()> {
     CryptoProvider::install_default(rustls::crypto::ring::default_provider())
         .expect("Failed to install ring crypto provider.");
 
-    // symbols to fetch
-    let symbols = vec![
-        "publicTrade.BTCUSDT".to_string(),
-        "orderbook.50.BTCUSDT".to_string(),
-        "tickers.BTCUSDT".to_string(),
-        "publicTrade.ETHUSDT".to_string(),
-        "orderbook.50.ETHUSDT".to_string(),
-        "tickers.ETHUSDT".to_string(),
-        "tickers.ELSAUSDT".to_string(),
-        "publicTrade.ELSAUSDT".to_string(),
-        "orderbook.50.ELSAUSDT".to_string(),
-    ];
-
-    let client = load_db::load_db()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = parse_args(&args)?;
+    let config = Config::load().expect("Error while loading config.");
+    if let Command::BenchParse = command {
+        return bench::bench_parse(&config).await;
+    }
+
+    let client = load_db::load_db(&config)
         .await
         .expect("Error while loading database.");
-    let (orderbook_inserter, trades_inserter, ticker_inserter) = setup_inserters(&client).await;
-    let (tx, mut rx) = channel::<String>(100);
-    let (parser_tx, parser_rx) = channel::<String>(100_000);
-    let (writer_tx, writer_rx) = channel::<BybitOTT>(100_000);
-
-    let mut orderbook_cache = OrderbookCache::new();
-    let mut ticker_cache = TickerCache::new();
-
-    tokio::spawn(async move {
-        async_parse(
-            tx,
-            parser_rx,
-            writer_tx,
-            &mut orderbook_cache,
-            &mut ticker_cache,
-        )
-        .await
-    });
-    tokio::spawn(async move {
-        writer::async_write(
-            writer_rx,
-            orderbook_inserter,
-            trades_inserter,
-            ticker_inserter,
-        )
-        .await
-    });
+    if config.migrations.dry_run {
+        info!("Migrations dry run finished.");
+        return Ok(());
+    }
+    let cluster = config.clickhouse.cluster.as_deref();
+    match command {
+        Command::Backfill(target) => return rollups::backfill(&client, &config, &target).await,
+        Command::ConvertOrderbook => return orderbook_levels::convert(&client, cluster).await,
+        Command::Run | Command::BenchParse => {}
+    }
+    let sinks = sink::build_sinks(&config.sinks, &client)?;
+    let (tx, rx) = channel::<String>(100);
+    let (parser_tx, parser_rx) = queue::channel::<Frame>("parser", &config.channels.parser)?;
+    let (writer_tx, writer_rx) = queue::channel::<BybitOTT>("writer", &config.channels.writer)?;
+    let parser_rx = Arc::new(Mutex::new(parser_rx));
+    let writer_rx = Arc::new(Mutex::new(writer_rx));
+    let resync_rx = Arc::new(Mutex::new(rx));
 
-    loop {
-        let ws = handle_ws(symbols.clone()).await?;
-        tokio::select! {
-            res = fetch_bybit(ws, parser_tx.clone()) => {
-                if let Err(e) = res {
-                    error!("WS Error: {:?}. Reconnecting...", e);
-                }
-            }
-            Some(msg) = rx.recv() => {
-                if msg == "Reconnect" {
-                    info!("Received Reconnect signal from parser. Restarting WS...");
+    let mut supervisor = Supervisor::new(config.supervisor.clone());
+
+    // Stage 3: the writer. A restart rebuilds the sinks.
+    let mut sinks = Some(sinks);
+    let (sink_configs, sink_client) = (config.sinks.clone(), client.clone());
+    supervisor.add(
+        "writer",
+        3,
+        Policy::Restart,
+        Box::new(move || {
+            let sinks = sinks
+                .take()
+                .map_or_else(|| sink::build_sinks(&sink_configs, &sink_client), Ok);
+            let writer_rx = writer_rx.clone();
+            Box::pin(async move {
+                let mut writer_rx = writer_rx.lock().await;
+                writer::async_write(&mut writer_rx, sinks?).await
+            })
+        }),
+    );
+
+    // Stage 1: with more than one parser worker, frames are split between them
+    // by symbol.
+    let worker_rxs = match config.parser.workers {
+        1 => vec![parser_rx],
+        workers => {
+            let (worker_txs, worker_rxs): (Vec<_>, Vec<_>) = (0..workers)
+                .map(|worker| {
+                    let name = format!("parser_{}", worker);
+                    let (worker_tx, worker_rx) =
+                        queue::channel::<Frame>(&name, &config.channels.parser)?;
+                    Ok((worker_tx, Arc::new(Mutex::new(worker_rx))))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .unzip();
+            supervisor.add(
+                "parser_dispatch",
+                1,
+                Policy::Restart,
+                Box::new(move || {
+                    let (parser_rx, worker_txs) = (parser_rx.clone(), worker_txs.clone());
+                    Box::pin(async move {
+                        let mut parser_rx = parser_rx.lock().await;
+                        dispatch::dispatch(&mut parser_rx, &worker_txs).await
+                    })
+                }),
+            );
+            worker_rxs
+        }
+    };
+
+    // Stage 2: the parser workers. A restart starts from empty caches; the next
+    // delta of each book then asks for a resync like any missed update.
+    let stats_interval = Duration::from_secs(config.parser.stats_secs);
+    for (worker, worker_rx) in worker_rxs.into_iter().enumerate() {
+        let (tx, parser_writer_tx) = (tx.clone(), writer_tx.clone());
+        let (ticker_config, orderbook_config) = (config.ticker.clone(), config.orderbook.clone());
+        supervisor.add(
+            format!("parser_{}", worker),
+            2,
+            Policy::Restart,
+            Box::new(move || {
+                let (tx, writer_tx) = (tx.clone(), parser_writer_tx.clone());
+                let (worker_rx, ticker_config) = (worker_rx.clone(), ticker_config.clone());
+                let orderbook_config = orderbook_config.clone();
+                Box::pin(async move {
+                    let mut worker_rx = worker_rx.lock().await;
+                    let ctx = ParserContext::new(
+                        worker,
+                        stats_interval,
+                        tx,
+                        writer_tx,
+                        ticker_config,
+                        orderbook_config,
+                    );
+                    async_parse(ctx, &mut worker_rx).await
+                })
+            }),
+        );
+    }
+    drop(tx);
+
+    // Stage 0: the sources.
+    if config.replay.enabled {
+        if config.recorder.enabled {
+            info!("Recorder is disabled while replaying.");
+        }
+        supervisor.add(
+            "replay",
+            0,
+            Policy::RunOnce,
+            supervisor::once(replay::run_replay(config.replay.clone(), parser_tx)),
+        );
+        supervisor.add(
+            "replay_gaps",
+            2,
+            Policy::BestEffort,
+            supervisor::once(async move {
+                let mut resync_rx = resync_rx.lock().await;
+                let mut requests = ResyncRequests::default();
+                while let Some(topics) = requests.next(&mut resync_rx).await {
+                    warn!(
+                        ?topics,
+                        "Orderbook gap in the recording, waiting for the next snapshot."
+                    );
                 }
+                Ok(())
+            }),
+        );
+        drop(writer_tx);
+        return supervisor.run().await;
+    }
+
+    if !config.rest.endpoints.is_empty() {
+        let rest_config = config.rest.clone();
+        let rest_client = config.uses_clickhouse().then(|| client.clone());
+        let writer_tx = writer_tx.clone();
+        supervisor.add(
+            "rest_pollers",
+            0,
+            Policy::Reconnect,
+            Box::new(move || {
+                Box::pin(bybit_rest::run_pollers(
+                    rest_config.clone(),
+                    rest_client.clone(),
+                    writer_tx.clone(),
+                ))
+            }),
+        );
+    }
+
+    let recorder_tx = match config.recorder.enabled {
+        true => {
+            let (recorder_tx, recorder) = recorder::open_recorder(config.recorder.clone())?;
+            supervisor.add(
+                "recorder",
+                1,
+                Policy::BestEffort,
+                supervisor::once(recorder),
+            );
+            Some(recorder_tx)
+        }
+        false => None,
+    };
+
+    for (category, topics) in config.streams.by_category() {
+        let (parser_tx, recorder_tx) = (parser_tx.clone(), recorder_tx.clone());
+        let resync_rx = resync_rx.clone();
+        supervisor.add(
+            format!("stream_{}", category.as_str()),
+            0,
+            Policy::Reconnect,
+            Box::new(move || {
+                let (topics, parser_tx, recorder_tx) =
+                    (topics.clone(), parser_tx.clone(), recorder_tx.clone());
+                let resync_rx = resync_rx.clone();
+                Box::pin(async move {
+                    match category {
+                        Category::Linear => {
+                            let mut resync_rx = resync_rx.lock().await;
+                            run_stream(
+                                category,
+                                topics,
+                                parser_tx,
+                                recorder_tx,
+                                Some(&mut resync_rx),
+                            )
+                            .await
+                        }
+                        _ => run_stream(category, topics, parser_tx, recorder_tx, None).await,
+                    }
+                })
+            }),
+        );
+    }
+    // Only the task factories may hold channel ends, so shutdown can drain them.
+    drop((parser_tx, writer_tx, recorder_tx));
+
+    supervisor.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resync_requests_are_gathered_across_workers() {
+        let (tx, mut rx) = channel(8);
+        let mut requests = ResyncRequests::default();
+        for topic in ["orderbook.50.BTCUSDT", "orderbook.50.ETHUSDT"] {
+            for _ in 0..3 {
+                tx.send(topic.to_string()).await.unwrap();
             }
         }
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(
+            requests.next(&mut rx).await.unwrap(),
+            ["orderbook.50.BTCUSDT", "orderbook.50.ETHUSDT"]
+        );
+
+        // Asked again before RESYNC_RETRY: nothing is due.
+        tx.send("orderbook.50.BTCUSDT".to_string()).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(50), requests.next(&mut rx));
+        assert!(next.await.is_err());
+        assert!(rx.is_empty());
+
+        requests.requested.clear();
+        tx.send("orderbook.50.BTCUSDT".to_string()).await.unwrap();
+        drop(tx);
+        assert_eq!(
+            requests.next(&mut rx).await.unwrap(),
+            ["orderbook.50.BTCUSDT"]
+        );
+        assert_eq!(requests.next(&mut rx).await, None);
     }
 }
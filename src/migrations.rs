@@ -0,0 +1,1044 @@
+//! Ordered, versioned schema migrations.
+//!
+//! Applied versions are recorded in `schema_migrations`. Migrations are never
+//! edited once released: schema changes go into a new version at the end of
+//! `MIGRATIONS`.
+//!
+//! Statements are written for a single node and rewritten by `render` when a
+//! cluster is configured.
+
+use crate::bybit_funding::BybitFundingEvent;
+use crate::bybit_kline::BybitKline;
+use crate::bybit_liquidations::BybitLiquidations;
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_rest::{BybitHistoricalVolatility, BybitLongShortRatio, BybitOpenInterest};
+use crate::bybit_ticker::{BybitTicker, BybitTickerSparse};
+use crate::bybit_ticker_option::BybitOptionTicker;
+use crate::bybit_ticker_spot::BybitSpotTicker;
+use crate::bybit_trades::BybitTrades;
+use anyhow::{Context, Result};
+use clickhouse::{Client, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    // Everything that existed before migrations were versioned. All statements
+    // are idempotent so existing databases adopt it as-is.
+    Migration {
+        version: 1,
+        name: "baseline",
+        statements: &[
+            TRADES,
+            LIQUIDATIONS,
+            KLINE,
+            ORDERBOOK,
+            TICKER,
+            TICKER_STALE_COLUMNS,
+            TICKER_SPOT,
+            TICKER_OPTION,
+            OPEN_INTEREST,
+            LONG_SHORT_RATIO,
+            HISTORICAL_VOLATILITY,
+            FUNDING_EVENTS,
+            TICKER_SPARSE,
+            TICKER_SPARSE_FULL,
+        ],
+    },
+    // `BybitTicker::delivery_fee_rate` was an `Option<i64>`.
+    Migration {
+        version: 2,
+        name: "ticker_delivery_fee_rate_int64",
+        statements: &["ALTER TABLE ticker_raw_ml MODIFY COLUMN delivery_fee_rate Nullable(Int64)"],
+    },
+    Migration {
+        version: 3,
+        name: "retention_policies",
+        statements: &[RETENTION_POLICIES],
+    },
+    Migration {
+        version: 4,
+        name: "orderbook_levels",
+        statements: &[ORDERBOOK_LEVELS],
+    },
+    // Dated futures send a decimal `deliveryFeeRate`.
+    Migration {
+        version: 5,
+        name: "ticker_delivery_fee_rate_decimal",
+        statements: &[
+            "ALTER TABLE ticker_raw_ml MODIFY COLUMN delivery_fee_rate Nullable(Decimal128(18))",
+            "DROP VIEW IF EXISTS ticker_sparse_full",
+            TICKER_SPARSE_FULL,
+        ],
+    },
+];
+
+const SCHEMA_MIGRATIONS: &str = r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations
+        (
+            version         UInt32,
+            name            String,
+            applied_at      DateTime64(3, 'UTC')
+        )
+        ENGINE = MergeTree()
+        ORDER BY version
+        "#;
+
+/// A single replica set across the whole cluster, so every node sees the same
+/// applied versions.
+const SCHEMA_MIGRATIONS_CLUSTER: &str = r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations ON CLUSTER {cluster}
+        (
+            version         UInt32,
+            name            String,
+            applied_at      DateTime64(3, 'UTC')
+        )
+        ENGINE = ReplicatedMergeTree('/clickhouse/tables/{database}/schema_migrations', '{replica}')
+        ORDER BY version
+        "#;
+
+/// ` ON CLUSTER {cluster}` for DDL that has to run on every node.
+pub fn on_cluster(cluster: Option<&str>) -> String {
+    cluster
+        .map(|c| format!(" ON CLUSTER {}", c))
+        .unwrap_or_default()
+}
+
+/// The table holding the data of `table`, the replicated local table on a cluster.
+pub fn storage_table(table: &str, cluster: Option<&str>) -> String {
+    match cluster {
+        Some(_) => format!("{}_local", table),
+        None => table.to_string(),
+    }
+}
+
+fn split_name(sql: &str) -> (&str, &str) {
+    sql.split_at(sql.find(char::is_whitespace).unwrap_or(sql.len()))
+}
+
+/// Splits `s` at the characters matching `sep` outside of parentheses.
+fn split_top_level(s: &str, sep: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if depth == 0 && sep(c) => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts.into_iter().filter(|p| !p.trim().is_empty()).collect()
+}
+
+/// Contents of the parenthesized group `s` starts with, and the text after it.
+fn parenthesized(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start().strip_prefix('(')?;
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some((&s[..i], &s[i + 1..])),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The parts of a `CREATE TABLE IF NOT EXISTS` statement. Cluster DDL is
+/// assembled from these instead of editing the statement text.
+pub struct TableDef {
+    pub name: String,
+    /// Column definitions, e.g. `price Decimal128(18)`.
+    pub columns: Vec<String>,
+    /// Engine without arguments, e.g. `ReplacingMergeTree`.
+    pub engine: String,
+    pub engine_args: String,
+    /// `PARTITION BY`, `ORDER BY`, `SETTINGS` and the like, as written.
+    pub clauses: String,
+}
+
+impl TableDef {
+    pub fn parse(sql: &str) -> Result<Self> {
+        let rest = sql
+            .trim()
+            .strip_prefix("CREATE TABLE IF NOT EXISTS ")
+            .context("Not a CREATE TABLE IF NOT EXISTS statement")?;
+        let (name, rest) = split_name(rest);
+        let (columns, rest) =
+            parenthesized(rest).with_context(|| format!("No column list in {}", name))?;
+        let engine = rest
+            .trim_start()
+            .strip_prefix("ENGINE = ")
+            .with_context(|| format!("No ENGINE in {}", name))?;
+        let (engine, rest) = engine.split_at(
+            engine
+                .find('(')
+                .with_context(|| format!("No engine arguments in {}", name))?,
+        );
+        let (engine_args, clauses) = parenthesized(rest)
+            .with_context(|| format!("Unterminated engine arguments in {}", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            columns: split_top_level(columns, |c| c == ',')
+                .into_iter()
+                .map(|c| c.trim().to_string())
+                .collect(),
+            engine: engine.trim().to_string(),
+            engine_args: engine_args.trim().to_string(),
+            clauses: clauses.trim().to_string(),
+        })
+    }
+
+    /// First column of the sorting key.
+    fn sorting_key(&self) -> Option<&str> {
+        let (_, key) = self.clauses.split_once("ORDER BY")?;
+        match parenthesized(key) {
+            Some((key, _)) => split_top_level(key, |c| c == ',').first().map(|k| k.trim()),
+            None => split_top_level(key, char::is_whitespace).first().copied(),
+        }
+    }
+
+    fn sql(&self, name: &str, cluster: &str, engine: &str, engine_args: &str) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}{}\n(\n    {}\n)\nENGINE = {}({})\n{}",
+            name,
+            on_cluster(Some(cluster).filter(|c| !c.is_empty())),
+            self.columns.join(",\n    "),
+            engine,
+            engine_args,
+            self.clauses
+        )
+    }
+
+    /// The statements creating the table. On a cluster it becomes a
+    /// `Replicated*` table named `{table}_local` on every node, with a
+    /// `Distributed` table under the original name that shards rows by the
+    /// first column of the sorting key.
+    pub fn render(&self, cluster: Option<&str>) -> Result<Vec<String>> {
+        let Some(cluster) = cluster else {
+            return Ok(vec![self.sql(
+                &self.name,
+                "",
+                &self.engine,
+                &self.engine_args,
+            )]);
+        };
+        let local = format!("{}_local", self.name);
+        let mut replicated_args = format!(
+            "'/clickhouse/tables/{{shard}}/{{database}}/{}', '{{replica}}'",
+            local
+        );
+        if !self.engine_args.is_empty() {
+            replicated_args = format!("{}, {}", replicated_args, self.engine_args);
+        }
+        let sharding_key = self
+            .sorting_key()
+            .filter(|key| !key.is_empty())
+            .with_context(|| format!("No sorting key in {}", self.name))?;
+        Ok(vec![
+            self.sql(
+                &local,
+                cluster,
+                &format!("Replicated{}", self.engine),
+                &replicated_args,
+            ),
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} ON CLUSTER {} AS {} \
+                 ENGINE = Distributed({}, currentDatabase(), {}, cityHash64({}))",
+                self.name, cluster, local, cluster, local, sharding_key
+            ),
+        ])
+    }
+}
+
+/// Rewrites a statement for `cluster`, see [`TableDef::render`] for tables.
+/// Without a cluster statements run as written.
+pub fn render(statement: &str, cluster: Option<&str>) -> Result<Vec<String>> {
+    let Some(cluster) = cluster else {
+        return Ok(vec![statement.to_string()]);
+    };
+    let sql = statement.trim();
+
+    if sql.starts_with("CREATE TABLE IF NOT EXISTS ") {
+        return TableDef::parse(sql)?.render(Some(cluster));
+    }
+    if let Some(rest) = sql.strip_prefix("ALTER TABLE ") {
+        // The Distributed table has to follow the column changes.
+        let (table, action) = split_name(rest);
+        return Ok(vec![
+            format!(
+                "ALTER TABLE {}_local ON CLUSTER {}{}",
+                table, cluster, action
+            ),
+            format!("ALTER TABLE {} ON CLUSTER {}{}", table, cluster, action),
+        ]);
+    }
+    if let Some(view) = sql.strip_prefix("DROP VIEW IF EXISTS ") {
+        return Ok(vec![format!(
+            "DROP VIEW IF EXISTS {} ON CLUSTER {}",
+            view, cluster
+        )]);
+    }
+    if let Some(rest) = sql.strip_prefix("CREATE VIEW IF NOT EXISTS ") {
+        let (view, body) = split_name(rest);
+        return Ok(vec![format!(
+            "CREATE VIEW IF NOT EXISTS {} ON CLUSTER {}{}",
+            view, cluster, body
+        )]);
+    }
+    anyhow::bail!("Don't know how to run on a cluster: {}", sql)
+}
+
+#[derive(Row, Serialize)]
+struct AppliedMigration {
+    version: u32,
+    name: String,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    applied_at: OffsetDateTime,
+}
+
+async fn applied_versions(client: &Client) -> Result<HashSet<u32>> {
+    let exists = client
+        .query("EXISTS TABLE schema_migrations")
+        .fetch_one::<u8>()
+        .await?;
+    if exists == 0 {
+        return Ok(HashSet::new());
+    }
+    let versions = client
+        .query("SELECT version FROM schema_migrations")
+        .fetch_all::<u32>()
+        .await?;
+    Ok(versions.into_iter().collect())
+}
+
+/// Applies pending migrations in order. With `dry_run` the pending statements
+/// are only logged.
+pub async fn migrate(client: &Client, cluster: Option<&str>, dry_run: bool) -> Result<()> {
+    let applied = applied_versions(client)
+        .await
+        .context("Failed to read schema_migrations")?;
+    let pending: Vec<&Migration> = MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect();
+    if pending.is_empty() {
+        info!("Schema is up to date.");
+        return Ok(());
+    }
+
+    if !dry_run {
+        let ddl = match cluster {
+            Some(cluster) => SCHEMA_MIGRATIONS_CLUSTER.replace("{cluster}", cluster),
+            None => SCHEMA_MIGRATIONS.to_string(),
+        };
+        client.query(&ddl).execute().await?;
+    }
+    for migration in pending {
+        let mut statements = Vec::new();
+        for statement in migration.statements {
+            statements.extend(render(statement, cluster)?);
+        }
+        if dry_run {
+            info!(
+                version = migration.version,
+                "Would apply migration {}:", migration.name
+            );
+            for statement in &statements {
+                info!("{}", statement.trim());
+            }
+            continue;
+        }
+        for statement in &statements {
+            client.query(statement).execute().await.with_context(|| {
+                format!(
+                    "Migration {} ({}) failed",
+                    migration.version, migration.name
+                )
+            })?;
+        }
+        // A plain insert, so the version is recorded before the next migration.
+        let mut insert = client
+            .insert::<AppliedMigration>("schema_migrations")
+            .await?
+            .with_option("async_insert", "0");
+        insert
+            .write(&AppliedMigration {
+                version: migration.version,
+                name: migration.name.to_string(),
+                applied_at: OffsetDateTime::now_utc(),
+            })
+            .await?;
+        insert.end().await?;
+        info!(
+            version = migration.version,
+            "Applied migration {}.", migration.name
+        );
+    }
+    Ok(())
+}
+
+#[derive(Row, Deserialize)]
+struct ColumnInfo {
+    name: String,
+    #[serde(rename = "type")]
+    ty: String,
+    default_kind: String,
+}
+
+/// Name and type of a column definition like `price Decimal128(18) DEFAULT 0`.
+fn column_type(definition: &str) -> Option<(&str, &str)> {
+    let parts = split_top_level(definition, char::is_whitespace);
+    Some((parts.first()?, parts.get(1)?))
+}
+
+/// Column types of `table` after every migration, as the migrations declare them.
+fn declared_columns(table: &str) -> HashMap<String, String> {
+    let mut columns = HashMap::new();
+    for statement in MIGRATIONS.iter().flat_map(|m| m.statements) {
+        let sql = statement.trim();
+        if sql.starts_with("CREATE TABLE IF NOT EXISTS ") {
+            let Ok(def) = TableDef::parse(sql) else {
+                continue;
+            };
+            if def.name != table {
+                continue;
+            }
+            for definition in &def.columns {
+                if let Some((column, ty)) = column_type(definition) {
+                    columns.insert(column.to_string(), ty.to_string());
+                }
+            }
+        } else if let Some(rest) = sql.strip_prefix("ALTER TABLE ") {
+            let (name, actions) = split_name(rest);
+            if name != table {
+                continue;
+            }
+            for action in split_top_level(actions, |c| c == ',') {
+                let action = action.trim();
+                let definition = action
+                    .strip_prefix("ADD COLUMN IF NOT EXISTS ")
+                    .or_else(|| action.strip_prefix("ADD COLUMN "))
+                    .or_else(|| action.strip_prefix("MODIFY COLUMN "));
+                if let Some((column, ty)) = definition.and_then(column_type) {
+                    columns.insert(column.to_string(), ty.to_string());
+                }
+            }
+        }
+    }
+    columns
+}
+
+/// Spells a type the way `system.columns` shows it, e.g. `Decimal128(18)` as
+/// `Decimal(38,18)`, without whitespace.
+fn normalize_type(ty: &str) -> String {
+    let mut ty: String = ty.chars().filter(|c| !c.is_whitespace()).collect();
+    for (alias, precision) in [
+        ("Decimal32(", 9),
+        ("Decimal64(", 18),
+        ("Decimal128(", 38),
+        ("Decimal256(", 76),
+    ] {
+        ty = ty.replace(alias, &format!("Decimal({},", precision));
+    }
+    ty
+}
+
+/// Compares the columns a row type writes with the table in ClickHouse: every
+/// field needs a column of the type the migrations declare.
+async fn check_table<T: Row>(client: &Client, table: &str) -> Result<Vec<String>> {
+    let columns = client
+        .query(
+            "SELECT name, type, default_kind FROM system.columns \
+             WHERE database = currentDatabase() AND table = ?",
+        )
+        .bind(table)
+        .fetch_all::<ColumnInfo>()
+        .await?;
+    if columns.is_empty() {
+        return Ok(vec![format!("table {} does not exist", table)]);
+    }
+
+    // `COLUMN_NAMES` is the list of fields the `Row` derive serializes.
+    let fields: HashSet<&str> = T::COLUMN_NAMES.iter().copied().collect();
+    let existing: HashSet<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+    let mut problems: Vec<String> = fields
+        .difference(&existing)
+        .map(|name| format!("{}.{} is written but missing in ClickHouse", table, name))
+        .collect();
+    let declared = declared_columns(table);
+    for column in columns.iter().filter(|c| fields.contains(c.name.as_str())) {
+        if let Some(expected) = declared.get(&column.name)
+            && normalize_type(expected) != normalize_type(&column.ty)
+        {
+            problems.push(format!(
+                "{}.{} is {} in ClickHouse but the migrations declare {}",
+                table, column.name, column.ty, expected
+            ));
+        }
+    }
+    for column in &columns {
+        if !fields.contains(column.name.as_str()) && column.default_kind.is_empty() {
+            warn!(
+                "{}.{} is never written and has no DEFAULT",
+                table, column.name
+            );
+        }
+    }
+    problems.sort();
+    Ok(problems)
+}
+
+/// Fails when a `Row` struct writes columns its table does not have.
+pub async fn check_schema(client: &Client) -> Result<()> {
+    let mut problems = Vec::new();
+    problems.extend(check_table::<BybitTrades>(client, "trades_raw_ml").await?);
+    problems.extend(check_table::<BybitLiquidations>(client, "liquidations_raw_ml").await?);
+    problems.extend(check_table::<BybitKline>(client, "kline_raw_ml").await?);
+    problems.extend(check_table::<BybitOrderbook>(client, "orderbook_raw_ml").await?);
+    problems.extend(check_table::<BybitOrderbookLevels>(client, "orderbook_levels_raw_ml").await?);
+    problems.extend(check_table::<BybitTicker>(client, "ticker_raw_ml").await?);
+    problems.extend(check_table::<BybitTickerSparse>(client, "ticker_sparse_raw_ml").await?);
+    problems.extend(check_table::<BybitSpotTicker>(client, "ticker_spot_raw_ml").await?);
+    problems.extend(check_table::<BybitOptionTicker>(client, "ticker_option_raw_ml").await?);
+    problems.extend(check_table::<BybitFundingEvent>(client, "funding_events").await?);
+    problems.extend(check_table::<BybitOpenInterest>(client, "open_interest_history").await?);
+    problems.extend(check_table::<BybitLongShortRatio>(client, "long_short_ratio").await?);
+    problems
+        .extend(check_table::<BybitHistoricalVolatility>(client, "historical_volatility").await?);
+    if !problems.is_empty() {
+        anyhow::bail!("Schema check failed:\n{}", problems.join("\n"));
+    }
+    info!("Schema check passed.");
+    Ok(())
+}
+
+const TRADES: &str = r#"
+        CREATE TABLE IF NOT EXISTS trades_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            trade_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            trade_id        LowCardinality(String),
+            side            LowCardinality(String),
+            price           Decimal128(18),
+            volume             Decimal128(18),
+            tick_direction  LowCardinality(String),
+            is_block_trade  Bool,
+            is_rpi          Bool,
+            seq             UInt64,
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(trade_timestamp)
+        ORDER BY (symbol, trade_timestamp, seq)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const LIQUIDATIONS: &str = r#"
+        CREATE TABLE IF NOT EXISTS liquidations_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            liquidation_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            side            LowCardinality(String),
+            price           Decimal128(18),
+            volume             Decimal128(18),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(liquidation_timestamp)
+        ORDER BY (symbol, liquidation_timestamp)
+        SETTINGS index_granularity = 8192
+        "#;
+
+/// In-progress updates collapse to the latest one per candle, the confirmed
+/// candle is kept as its own row.
+const KLINE: &str = r#"
+        CREATE TABLE IF NOT EXISTS kline_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            kline_timestamp       DateTime64(3, 'UTC'),
+            start_timestamp       DateTime64(3, 'UTC'),
+            end_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            interval        LowCardinality(String),
+            open           Decimal128(18),
+            high           Decimal128(18),
+            low           Decimal128(18),
+            close           Decimal128(18),
+            volume             Decimal128(18),
+            turnover             Decimal128(18),
+            confirm         Bool,
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = ReplacingMergeTree(kline_timestamp)
+        PARTITION BY toYYYYMMDD(start_timestamp)
+        ORDER BY (symbol, interval, start_timestamp, confirm)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const ORDERBOOK: &str = r#"
+        CREATE TABLE IF NOT EXISTS orderbook_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            client_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            side            LowCardinality(String),
+            price           Decimal128(18),
+            volume             Decimal128(18),
+            update             UInt64,
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp, update, side, price)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const TICKER: &str = r#"
+        CREATE TABLE IF NOT EXISTS ticker_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            cross_sequence       UInt64,
+            symbol          LowCardinality(String),
+            tick_direction            LowCardinality(String),
+            price_24h_pcnt           Decimal128(18),
+            last_price             Decimal128(18),
+            prev_price_24h             Decimal128(18),
+            high_price_24h             Decimal128(18),
+            low_price_24h              Decimal128(18),
+            prev_price_1h             Decimal128(18),
+            mark_price                Decimal128(18),
+
+    index_price Decimal128(18),
+    open_interest Decimal128(18),
+    open_interest_value Decimal128(18),
+    turnover_24h Decimal128(18),
+    volume_24h Decimal128(18),
+    next_funding_time DateTime64(3, 'UTC'),
+    funding_rate Decimal128(18),
+    bid1_price Decimal128(18),
+    bid1_size Decimal128(18),
+    ask1_price Decimal128(18),
+    ask1_size Decimal128(18),
+    delivery_time Nullable(DateTime64(3, 'UTC')),
+    basis_rate Nullable(Decimal128(18)),
+    delivery_fee_rate Nullable(UInt64),
+    predicted_delivery_price Nullable(Decimal128(18)),
+    pre_open_price Nullable(Decimal128(18)),
+    pre_qty Nullable(Decimal128(18)),
+    cur_pre_listing_phase Nullable(String),
+    funding_interval_hour Nullable(String),
+    funding_cap Nullable(Decimal128(18)),
+    basis_rate_year Nullable(Decimal128(18)),
+    is_stale Bool DEFAULT false,
+    stale_fields Array(LowCardinality(String)),
+
+    exchange        LowCardinality(String) DEFAULT 'bybit',
+
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp, cross_sequence)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const TICKER_STALE_COLUMNS: &str = r#"
+        ALTER TABLE ticker_raw_ml
+            ADD COLUMN IF NOT EXISTS is_stale Bool DEFAULT false,
+            ADD COLUMN IF NOT EXISTS stale_fields Array(LowCardinality(String))
+        "#;
+
+const TICKER_SPOT: &str = r#"
+        CREATE TABLE IF NOT EXISTS ticker_spot_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            cross_sequence       Nullable(UInt64),
+            symbol          LowCardinality(String),
+            last_price             Decimal128(18),
+            high_price_24h             Decimal128(18),
+            low_price_24h              Decimal128(18),
+            prev_price_24h             Decimal128(18),
+            volume_24h Decimal128(18),
+            turnover_24h Decimal128(18),
+            price_24h_pcnt           Decimal128(18),
+            usd_index_price Nullable(Decimal128(18)),
+            is_stale Bool DEFAULT false,
+            stale_fields Array(LowCardinality(String)),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const TICKER_OPTION: &str = r#"
+        CREATE TABLE IF NOT EXISTS ticker_option_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            cross_sequence       Nullable(UInt64),
+            symbol          LowCardinality(String),
+            bid_price Decimal128(18),
+            bid_size Decimal128(18),
+            bid_iv Decimal128(18),
+            ask_price Decimal128(18),
+            ask_size Decimal128(18),
+            ask_iv Decimal128(18),
+            last_price Decimal128(18),
+            high_price_24h Decimal128(18),
+            low_price_24h Decimal128(18),
+            mark_price Decimal128(18),
+            index_price Decimal128(18),
+            mark_price_iv Decimal128(18),
+            underlying_price Decimal128(18),
+            open_interest Decimal128(18),
+            turnover_24h Decimal128(18),
+            volume_24h Decimal128(18),
+            total_volume Decimal128(18),
+            total_turnover Decimal128(18),
+            delta Decimal128(18),
+            gamma Decimal128(18),
+            vega Decimal128(18),
+            theta Decimal128(18),
+            predicted_delivery_price Nullable(Decimal128(18)),
+            change_24h Decimal128(18),
+            is_stale Bool DEFAULT false,
+            stale_fields Array(LowCardinality(String)),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp)
+        SETTINGS index_granularity = 8192
+        "#;
+
+const OPEN_INTEREST: &str = r#"
+        CREATE TABLE IF NOT EXISTS open_interest_history
+        (
+            timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            interval_time   LowCardinality(String),
+            open_interest   Decimal128(18),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = ReplacingMergeTree()
+        PARTITION BY toYYYYMM(timestamp)
+        ORDER BY (symbol, interval_time, timestamp)
+        "#;
+
+const LONG_SHORT_RATIO: &str = r#"
+        CREATE TABLE IF NOT EXISTS long_short_ratio
+        (
+            timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            period          LowCardinality(String),
+            buy_ratio       Decimal128(18),
+            sell_ratio      Decimal128(18),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = ReplacingMergeTree()
+        PARTITION BY toYYYYMM(timestamp)
+        ORDER BY (symbol, period, timestamp)
+        "#;
+
+const HISTORICAL_VOLATILITY: &str = r#"
+        CREATE TABLE IF NOT EXISTS historical_volatility
+        (
+            timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            base_coin       LowCardinality(String),
+            period          UInt32,
+            value           Decimal128(18),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = ReplacingMergeTree()
+        PARTITION BY toYYYYMM(timestamp)
+        ORDER BY (base_coin, period, timestamp)
+        "#;
+
+const FUNDING_EVENTS: &str = r#"
+        CREATE TABLE IF NOT EXISTS funding_events
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            funding_time       DateTime64(3, 'UTC'),
+            next_funding_time       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            funding_rate           Decimal128(18),
+            mark_price             Decimal128(18),
+            index_price             Decimal128(18),
+            funding_interval_hour Nullable(String),
+            funding_cap Nullable(Decimal128(18)),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = ReplacingMergeTree()
+        PARTITION BY toYYYYMM(funding_time)
+        ORDER BY (symbol, funding_time)
+        "#;
+
+const TICKER_SPARSE: &str = r#"
+        CREATE TABLE IF NOT EXISTS ticker_sparse_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            cross_sequence       UInt64,
+            symbol          LowCardinality(String),
+            ttype           LowCardinality(String),
+            changes         Map(LowCardinality(String), String),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp, cross_sequence)
+        SETTINGS index_granularity = 8192
+        "#;
+
+/// Rebuilds full ticker rows by carrying the last seen value of every field forward.
+const TICKER_SPARSE_FULL: &str = r#"
+        CREATE VIEW IF NOT EXISTS ticker_sparse_full AS
+        SELECT
+    server_timestamp,
+    received_timestamp,
+    cross_sequence,
+    symbol,
+    last_value(nullIf(changes['tick_direction'], '')) OVER w AS tick_direction,
+    last_value(toDecimal128OrNull(nullIf(changes['price_24h_pcnt'], ''), 18)) OVER w AS price_24h_pcnt,
+    last_value(toDecimal128OrNull(nullIf(changes['last_price'], ''), 18)) OVER w AS last_price,
+    last_value(toDecimal128OrNull(nullIf(changes['prev_price_24h'], ''), 18)) OVER w AS prev_price_24h,
+    last_value(toDecimal128OrNull(nullIf(changes['high_price_24h'], ''), 18)) OVER w AS high_price_24h,
+    last_value(toDecimal128OrNull(nullIf(changes['low_price_24h'], ''), 18)) OVER w AS low_price_24h,
+    last_value(toDecimal128OrNull(nullIf(changes['prev_price_1h'], ''), 18)) OVER w AS prev_price_1h,
+    last_value(toDecimal128OrNull(nullIf(changes['mark_price'], ''), 18)) OVER w AS mark_price,
+    last_value(toDecimal128OrNull(nullIf(changes['index_price'], ''), 18)) OVER w AS index_price,
+    last_value(toDecimal128OrNull(nullIf(changes['open_interest'], ''), 18)) OVER w AS open_interest,
+    last_value(toDecimal128OrNull(nullIf(changes['open_interest_value'], ''), 18)) OVER w AS open_interest_value,
+    last_value(toDecimal128OrNull(nullIf(changes['turnover_24h'], ''), 18)) OVER w AS turnover_24h,
+    last_value(toDecimal128OrNull(nullIf(changes['volume_24h'], ''), 18)) OVER w AS volume_24h,
+    last_value(fromUnixTimestamp64Milli(toInt64OrNull(nullIf(changes['next_funding_time'], '')), 'UTC')) OVER w AS next_funding_time,
+    last_value(toDecimal128OrNull(nullIf(changes['funding_rate'], ''), 18)) OVER w AS funding_rate,
+    last_value(toDecimal128OrNull(nullIf(changes['bid1_price'], ''), 18)) OVER w AS bid1_price,
+    last_value(toDecimal128OrNull(nullIf(changes['bid1_size'], ''), 18)) OVER w AS bid1_size,
+    last_value(toDecimal128OrNull(nullIf(changes['ask1_price'], ''), 18)) OVER w AS ask1_price,
+    last_value(toDecimal128OrNull(nullIf(changes['ask1_size'], ''), 18)) OVER w AS ask1_size,
+    last_value(parseDateTime64BestEffortOrNull(nullIf(changes['delivery_time'], ''), 3, 'UTC')) OVER w AS delivery_time,
+    last_value(toDecimal128OrNull(nullIf(changes['basis_rate'], ''), 18)) OVER w AS basis_rate,
+    last_value(toDecimal128OrNull(nullIf(changes['delivery_fee_rate'], ''), 18)) OVER w AS delivery_fee_rate,
+    last_value(toDecimal128OrNull(nullIf(changes['predicted_delivery_price'], ''), 18)) OVER w AS predicted_delivery_price,
+    last_value(toDecimal128OrNull(nullIf(changes['pre_open_price'], ''), 18)) OVER w AS pre_open_price,
+    last_value(toDecimal128OrNull(nullIf(changes['pre_qty'], ''), 18)) OVER w AS pre_qty,
+    last_value(nullIf(changes['cur_pre_listing_phase'], '')) OVER w AS cur_pre_listing_phase,
+    last_value(nullIf(changes['funding_interval_hour'], '')) OVER w AS funding_interval_hour,
+    last_value(toDecimal128OrNull(nullIf(changes['funding_cap'], ''), 18)) OVER w AS funding_cap,
+    last_value(toDecimal128OrNull(nullIf(changes['basis_rate_year'], ''), 18)) OVER w AS basis_rate_year
+        FROM ticker_sparse_raw_ml
+        WINDOW w AS (
+            PARTITION BY symbol
+            ORDER BY server_timestamp, cross_sequence
+            ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+        )
+        "#;
+
+/// TTL currently set on each table by `retention`.
+const RETENTION_POLICIES: &str = r#"
+        CREATE TABLE IF NOT EXISTS retention_policies
+        (
+            table_name      LowCardinality(String),
+            ttl             String,
+            applied_at      DateTime64(3, 'UTC')
+        )
+        ENGINE = ReplacingMergeTree(applied_at)
+        ORDER BY table_name
+        "#;
+
+/// One row per orderbook update, levels sorted from the best price outwards.
+const ORDERBOOK_LEVELS: &str = r#"
+        CREATE TABLE IF NOT EXISTS orderbook_levels_raw_ml
+        (
+            server_timestamp       DateTime64(3, 'UTC'),
+            received_timestamp       DateTime64(3, 'UTC'),
+            client_timestamp       DateTime64(3, 'UTC'),
+            symbol          LowCardinality(String),
+            update             UInt64,
+            bid_prices      Array(Decimal128(18)),
+            bid_sizes       Array(Decimal128(18)),
+            ask_prices      Array(Decimal128(18)),
+            ask_sizes       Array(Decimal128(18)),
+            exchange        LowCardinality(String) DEFAULT 'bybit'
+        )
+        ENGINE = MergeTree()
+        PARTITION BY toYYYYMMDD(server_timestamp)
+        ORDER BY (symbol, server_timestamp, update)
+        SETTINGS index_granularity = 8192
+        "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statements() -> impl Iterator<Item = &'static str> {
+        MIGRATIONS.iter().flat_map(|m| m.statements.iter().copied())
+    }
+
+    #[test]
+    fn without_a_cluster_statements_run_as_written() {
+        for statement in statements() {
+            assert_eq!(render(statement, None).unwrap(), vec![statement]);
+        }
+    }
+
+    #[test]
+    fn every_table_parses_and_renders_back() {
+        for statement in statements().filter(|s| s.trim().starts_with("CREATE TABLE")) {
+            let def = TableDef::parse(statement).unwrap();
+            assert!(!def.columns.is_empty(), "{}", def.name);
+            let [single] = &def.render(None).unwrap()[..] else {
+                panic!("{} renders to one statement", def.name);
+            };
+            let again = TableDef::parse(single).unwrap();
+            assert_eq!(again.name, def.name);
+            assert_eq!(again.columns, def.columns);
+            assert_eq!(again.engine, def.engine);
+            assert_eq!(again.engine_args, def.engine_args);
+            assert_eq!(again.clauses, def.clauses);
+        }
+    }
+
+    #[test]
+    fn every_table_renders_for_a_cluster() {
+        for statement in statements().filter(|s| s.trim().starts_with("CREATE TABLE")) {
+            let def = TableDef::parse(statement).unwrap();
+            let rendered = render(statement, Some("main")).unwrap();
+            let [local, distributed] = &rendered[..] else {
+                panic!("{} renders to a local and a distributed table", def.name);
+            };
+            let local_name = format!("{}_local", def.name);
+            assert!(
+                local.starts_with(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} ON CLUSTER main\n(",
+                    local_name
+                )),
+                "{}",
+                local
+            );
+            let replicated = format!(
+                "ENGINE = Replicated{}('/clickhouse/tables/{{shard}}/{{database}}/{}', '{{replica}}'",
+                def.engine, local_name
+            );
+            assert!(local.contains(&replicated), "{}", local);
+            for column in &def.columns {
+                assert!(local.contains(column.as_str()), "{}: {}", def.name, column);
+            }
+            assert!(local.ends_with(&def.clauses), "{}", local);
+            assert_eq!(
+                *distributed,
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} ON CLUSTER main AS {} \
+                     ENGINE = Distributed(main, currentDatabase(), {}, cityHash64({}))",
+                    def.name,
+                    local_name,
+                    local_name,
+                    def.sorting_key().unwrap()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn cluster_table_keeps_engine_arguments() {
+        let rendered = render(KLINE, Some("main")).unwrap();
+        assert!(rendered[0].contains(
+            "ENGINE = ReplicatedReplacingMergeTree('/clickhouse/tables/{shard}/{database}/kline_raw_ml_local', '{replica}', kline_timestamp)"
+        ));
+        assert!(rendered[1].ends_with("cityHash64(symbol))"));
+
+        let retention = TableDef::parse(RETENTION_POLICIES).unwrap();
+        assert_eq!(retention.sorting_key(), Some("table_name"));
+    }
+
+    #[test]
+    fn alters_and_views_render_for_a_cluster() {
+        for statement in statements().filter(|s| !s.trim().starts_with("CREATE TABLE")) {
+            let rendered = render(statement, Some("main")).unwrap();
+            assert!(rendered.iter().all(|s| s.contains(" ON CLUSTER main")));
+        }
+        let rendered = render(TICKER_STALE_COLUMNS, Some("main")).unwrap();
+        assert!(rendered[0].starts_with("ALTER TABLE ticker_raw_ml_local ON CLUSTER main"));
+        assert!(rendered[1].starts_with("ALTER TABLE ticker_raw_ml ON CLUSTER main"));
+    }
+
+    #[test]
+    fn declared_columns_follow_later_migrations() {
+        let ticker = declared_columns("ticker_raw_ml");
+        assert_eq!(ticker["delivery_fee_rate"], "Nullable(Decimal128(18))");
+        assert_eq!(ticker["delivery_time"], "Nullable(DateTime64(3, 'UTC'))");
+        assert_eq!(ticker["stale_fields"], "Array(LowCardinality(String))");
+        assert_eq!(ticker["exchange"], "LowCardinality(String)");
+
+        let levels = declared_columns("orderbook_levels_raw_ml");
+        assert_eq!(levels["ask_sizes"], "Array(Decimal128(18))");
+        assert_eq!(levels.len(), 10);
+    }
+
+    #[test]
+    fn every_written_column_is_declared() {
+        fn check<T: Row>(table: &str) {
+            let declared = declared_columns(table);
+            for column in T::COLUMN_NAMES {
+                assert!(declared.contains_key(*column), "{}.{}", table, column);
+            }
+        }
+        check::<BybitTrades>("trades_raw_ml");
+        check::<BybitLiquidations>("liquidations_raw_ml");
+        check::<BybitKline>("kline_raw_ml");
+        check::<BybitOrderbook>("orderbook_raw_ml");
+        check::<BybitOrderbookLevels>("orderbook_levels_raw_ml");
+        check::<BybitTicker>("ticker_raw_ml");
+        check::<BybitTickerSparse>("ticker_sparse_raw_ml");
+        check::<BybitSpotTicker>("ticker_spot_raw_ml");
+        check::<BybitOptionTicker>("ticker_option_raw_ml");
+        check::<BybitFundingEvent>("funding_events");
+        check::<BybitOpenInterest>("open_interest_history");
+        check::<BybitLongShortRatio>("long_short_ratio");
+        check::<BybitHistoricalVolatility>("historical_volatility");
+    }
+
+    #[test]
+    fn types_compare_as_clickhouse_shows_them() {
+        assert_eq!(
+            normalize_type("Nullable(Decimal128(18))"),
+            normalize_type("Nullable(Decimal(38, 18))")
+        );
+        assert_eq!(
+            normalize_type("DateTime64(3,'UTC')"),
+            normalize_type("DateTime64(3, 'UTC')")
+        );
+        assert_ne!(
+            normalize_type("Nullable(UInt64)"),
+            normalize_type("Nullable(Int64)")
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Conversion of `orderbook_raw_ml` into the arrays layout of
+//! `orderbook_levels_raw_ml`.
+//!
+//! Every update written so far is regrouped into one row with its levels
+//! sorted like the live writer sorts them. The conversion goes hour by hour:
+//! rows of `orderbook_levels_raw_ml` with the keys of that hour's updates are
+//! deleted and the updates inserted again, so running it again gives the same
+//! result. Rows the fetcher wrote in the arrays layout for updates that are not
+//! in `orderbook_raw_ml` are left alone.
+
+use crate::migrations::{on_cluster, storage_table};
+use anyhow::{Context, Result};
+use clickhouse::Client;
+use time::OffsetDateTime;
+use tracing::info;
+
+/// Updates of `orderbook_raw_ml` within one hour, up to the newest row when the
+/// conversion started.
+fn hour_filter(hour: u32, cutoff: &str) -> String {
+    format!(
+        "server_timestamp >= toDateTime({hour}, 'UTC') \
+         AND server_timestamp < toDateTime({hour}, 'UTC') + INTERVAL 1 HOUR \
+         AND server_timestamp <= {cutoff}"
+    )
+}
+
+/// Removes the converted rows of one hour's updates, keyed like the table is
+/// sorted. Only the day's partition is rewritten.
+fn delete_hour(hour: u32, cutoff: &str, cluster: Option<&str>) -> Result<String> {
+    let date = OffsetDateTime::from_unix_timestamp(hour.into())?.date();
+    let partition = date.year() * 10_000 + date.month() as i32 * 100 + date.day() as i32;
+    let filter = hour_filter(hour, cutoff);
+    Ok(format!(
+        "ALTER TABLE {}{} DELETE IN PARTITION {partition} WHERE {filter} \
+         AND (symbol, server_timestamp, update) IN \
+         (SELECT symbol, server_timestamp, update FROM orderbook_raw_ml WHERE {filter})",
+        storage_table("orderbook_levels_raw_ml", cluster),
+        on_cluster(cluster),
+    ))
+}
+
+/// Converts one hour, to keep the groups of a single query small.
+fn insert_hour(hour: u32, cutoff: &str) -> String {
+    format!(
+        r#"
+        INSERT INTO orderbook_levels_raw_ml
+        SELECT
+            server_timestamp, received, client, symbol, update,
+            arrayMap(level -> level.1, bids), arrayMap(level -> level.2, bids),
+            arrayMap(level -> level.1, asks), arrayMap(level -> level.2, asks),
+            exchange
+        FROM
+        (
+            SELECT
+                symbol, server_timestamp, update, exchange,
+                any(received_timestamp) AS received,
+                any(client_timestamp) AS client,
+                arrayReverseSort(groupArrayIf((price, volume), side = 'Bid')) AS bids,
+                arraySort(groupArrayIf((price, volume), side = 'Ask')) AS asks
+            FROM orderbook_raw_ml
+            WHERE {}
+            GROUP BY symbol, server_timestamp, update, exchange
+        )
+        "#,
+        hour_filter(hour, cutoff)
+    )
+}
+
+pub async fn convert(client: &Client, cluster: Option<&str>) -> Result<()> {
+    let rows = client
+        .query("SELECT count() FROM orderbook_raw_ml")
+        .fetch_one::<u64>()
+        .await
+        .context("Failed to read orderbook_raw_ml")?;
+    if rows == 0 {
+        info!("orderbook_raw_ml is empty, nothing to convert.");
+        return Ok(());
+    }
+    let newest = client
+        .query("SELECT toUnixTimestamp64Milli(max(server_timestamp)) FROM orderbook_raw_ml")
+        .fetch_one::<i64>()
+        .await?;
+    let cutoff = format!("fromUnixTimestamp64Milli(toInt64({}), 'UTC')", newest);
+
+    info!("Converting {} orderbook rows up to {}.", rows, cutoff);
+    let hours = client
+        .query(&format!(
+            "SELECT DISTINCT toUnixTimestamp(toStartOfHour(server_timestamp)) AS hour \
+             FROM orderbook_raw_ml WHERE server_timestamp <= {} ORDER BY hour",
+            cutoff
+        ))
+        .fetch_all::<u32>()
+        .await?;
+    for hour in hours {
+        let start = OffsetDateTime::from_unix_timestamp(hour.into())?;
+        // Replicated tables only run mutations with a subquery when allowed to.
+        client
+            .query(&delete_hour(hour, &cutoff, cluster)?)
+            .with_option("mutations_sync", "2")
+            .with_option("allow_nondeterministic_mutations", "1")
+            .execute()
+            .await
+            .with_context(|| format!("Failed to clear the converted orderbook of {}", start))?;
+        client
+            .query(&insert_hour(hour, &cutoff))
+            .execute()
+            .await
+            .with_context(|| format!("Failed to convert orderbook of {}", start))?;
+        info!("Converted orderbook of {}.", start);
+    }
+    info!("Orderbook conversion finished.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_touches_only_the_keys_of_one_hour() {
+        // 2026-01-02 03:00 UTC
+        let sql = delete_hour(1_767_322_800, "now64()", Some("main")).unwrap();
+        assert!(sql.starts_with(
+            "ALTER TABLE orderbook_levels_raw_ml_local ON CLUSTER main DELETE IN PARTITION 20260102 \
+             WHERE server_timestamp >= toDateTime(1767322800, 'UTC')"
+        ));
+        assert!(sql.contains("AND (symbol, server_timestamp, update) IN (SELECT"));
+        assert_eq!(sql.matches("server_timestamp <= now64()").count(), 2);
+    }
+}
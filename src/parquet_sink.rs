@@ -0,0 +1,264 @@
+use crate::arrow_record::ArrowRecord;
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_ticker::BybitTicker;
+use crate::bybit_trades::BybitTrades;
+use crate::config::ParquetRotation;
+use crate::parser::BybitOTT;
+use crate::sink::BlockingSink;
+use anyhow::{Context, Result};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// Rows buffered per file before they are encoded into a row group.
+const BATCH_ROWS: usize = 10_000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PartitionKey {
+    date: String,
+    hour: String,
+    symbol: String,
+}
+
+/// One Parquet file being written. Rows land in a hidden temp file which is
+/// renamed into place on close, so readers never see partial files.
+struct OpenFile<T> {
+    buffer: Vec<T>,
+    writer: Option<ArrowWriter<File>>,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+}
+
+impl<T: ArrowRecord> OpenFile<T> {
+    fn encode_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let batch = T::to_batch(&self.buffer)?;
+        self.buffer.clear();
+        if self.writer.is_none() {
+            let props = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .build();
+            let file = File::create(&self.tmp_path)
+                .with_context(|| format!("Failed to create {}", self.tmp_path.display()))?;
+            self.writer = Some(ArrowWriter::try_new(file, batch.schema(), Some(props))?);
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write(&batch)?;
+        }
+        Ok(())
+    }
+
+    fn bytes(&self) -> usize {
+        self.writer
+            .as_ref()
+            .map(|w| w.bytes_written() + w.in_progress_size())
+            .unwrap_or(0)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.encode_buffer()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+            fs::rename(&self.tmp_path, &self.final_path)
+                .with_context(|| format!("Failed to finalize {}", self.final_path.display()))?;
+            info!(path = %self.final_path.display(), "Parquet file finalized");
+        }
+        Ok(())
+    }
+}
+
+/// Files for one table, partitioned as `{dir}/{table}/date=.../symbol=.../{hour}-{n}.parquet`.
+struct TableWriter<T> {
+    dir: PathBuf,
+    rotation: ParquetRotation,
+    max_file_bytes: usize,
+    files: HashMap<PartitionKey, OpenFile<T>>,
+    sequence: u64,
+}
+
+impl<T: ArrowRecord> TableWriter<T> {
+    fn new(dir: PathBuf, rotation: ParquetRotation, max_file_bytes: usize) -> Self {
+        Self {
+            dir,
+            rotation,
+            max_file_bytes,
+            files: HashMap::new(),
+            sequence: 0,
+        }
+    }
+
+    fn key(&self, row: &T) -> PartitionKey {
+        let timestamp = row.timestamp();
+        let hour = match self.rotation {
+            ParquetRotation::Hourly => format!("{:02}", timestamp.hour()),
+            ParquetRotation::Size => String::new(),
+        };
+        PartitionKey {
+            date: format!(
+                "{:04}-{:02}-{:02}",
+                timestamp.year(),
+                u8::from(timestamp.month()),
+                timestamp.day()
+            ),
+            hour,
+            symbol: row.symbol().to_string(),
+        }
+    }
+
+    fn open(&mut self, key: &PartitionKey) -> Result<OpenFile<T>> {
+        let partition = self
+            .dir
+            .join(T::TABLE)
+            .join(format!("date={}", key.date))
+            .join(format!("symbol={}", key.symbol));
+        fs::create_dir_all(&partition)
+            .with_context(|| format!("Failed to create {}", partition.display()))?;
+        self.sequence += 1;
+        let started = OffsetDateTime::now_utc().unix_timestamp();
+        let name = match key.hour.as_str() {
+            "" => format!("{}-{}.parquet", started, self.sequence),
+            hour => format!("{}-{}-{}.parquet", hour, started, self.sequence),
+        };
+        Ok(OpenFile {
+            buffer: Vec::with_capacity(BATCH_ROWS),
+            writer: None,
+            tmp_path: partition.join(format!(".{}.tmp", name)),
+            final_path: partition.join(name),
+        })
+    }
+
+    fn write(&mut self, rows: &[T]) -> Result<()> {
+        for row in rows {
+            let key = self.key(row);
+            if !self.files.contains_key(&key) {
+                // Server time has moved into a new period, so older files are complete.
+                let stale: Vec<PartitionKey> = self
+                    .files
+                    .keys()
+                    .filter(|k| (&k.date, &k.hour) < (&key.date, &key.hour))
+                    .cloned()
+                    .collect();
+                for k in stale {
+                    if let Some(file) = self.files.remove(&k) {
+                        file.finish()?;
+                    }
+                }
+                let file = self.open(&key)?;
+                self.files.insert(key.clone(), file);
+            }
+
+            let max_bytes = self.max_file_bytes;
+            let file = self.files.get_mut(&key).expect("file was just opened");
+            file.buffer.push(row.clone());
+            if file.buffer.len() >= BATCH_ROWS {
+                file.encode_buffer()?;
+                if file.bytes() >= max_bytes {
+                    let file = self.files.remove(&key).expect("file exists");
+                    file.finish()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close_all(&mut self) -> Result<()> {
+        for (_, file) in self.files.drain() {
+            file.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes temp files left by a crash. They have no footer, so their rows
+/// cannot be read back.
+fn sweep_tmp(dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir() {
+            sweep_tmp(&path)?;
+        } else if name.starts_with('.') && name.ends_with(".parquet.tmp") {
+            warn!(path = %path.display(), bytes = entry.metadata()?.len(), "Removing unfinished Parquet file");
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+pub struct ParquetSink {
+    trades: TableWriter<BybitTrades>,
+    orderbook: TableWriter<BybitOrderbook>,
+    orderbook_levels: TableWriter<BybitOrderbookLevels>,
+    ticker: TableWriter<BybitTicker>,
+}
+
+impl ParquetSink {
+    pub fn open(dir: PathBuf, rotation: ParquetRotation, max_file_bytes: usize) -> Result<Self> {
+        sweep_tmp(&dir)?;
+        Ok(Self {
+            trades: TableWriter::new(dir.clone(), rotation, max_file_bytes),
+            orderbook: TableWriter::new(dir.clone(), rotation, max_file_bytes),
+            orderbook_levels: TableWriter::new(dir.clone(), rotation, max_file_bytes),
+            ticker: TableWriter::new(dir, rotation, max_file_bytes),
+        })
+    }
+}
+
+impl BlockingSink for ParquetSink {
+    fn write(&mut self, record: &BybitOTT) -> Result<()> {
+        match record {
+            BybitOTT::Trades(trades) => self.trades.write(trades),
+            BybitOTT::Orderbook(orderbook) => self.orderbook.write(orderbook),
+            BybitOTT::OrderbookLevels(levels) => self
+                .orderbook_levels
+                .write(std::slice::from_ref(levels.as_ref())),
+            BybitOTT::Ticker(ticker) => self.ticker.write(std::slice::from_ref(ticker.as_ref())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parquet files are only readable once closed, so flushing finalizes them.
+    fn flush(&mut self) -> Result<()> {
+        self.trades.close_all()?;
+        self.orderbook.close_all()?;
+        self.orderbook_levels.close_all()?;
+        self.ticker.close_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_removes_only_unfinished_files() {
+        let dir = std::env::temp_dir().join(format!("parquet-sweep-{}", std::process::id()));
+        let partition = dir.join("trades_raw_ml/date=2026-01-01/symbol=BTCUSDT");
+        fs::create_dir_all(&partition).unwrap();
+        let done = partition.join("00-1-1.parquet");
+        let unfinished = partition.join(".00-1-2.parquet.tmp");
+        fs::write(&done, b"done").unwrap();
+        fs::write(&unfinished, b"partial").unwrap();
+
+        sweep_tmp(&dir).unwrap();
+
+        assert!(done.exists());
+        assert!(!unfinished.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,173 +1,671 @@
-
-use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookData, OrderbookCache};
-use crate::bybit_ticker::{BybitTicker, BybitTickerData, TickerCache};
+use crate::bybit_funding::BybitFundingEvent;
+use crate::bybit_kline::{BybitKline, BybitKlineData};
+use crate::bybit_liquidations::{BybitLiquidationData, BybitLiquidations};
+use crate::bybit_orderbook::{
+    BybitOrderbook, BybitOrderbookData, BybitOrderbookLevels, OrderbookCache,
+};
+use crate::bybit_rest::{BybitHistoricalVolatility, BybitLongShortRatio, BybitOpenInterest};
+use crate::bybit_ticker::{BybitTicker, BybitTickerData, BybitTickerSparse, TickerCache};
+use crate::bybit_ticker_option::{BybitOptionTicker, BybitOptionTickerData};
+use crate::bybit_ticker_spot::{BybitSpotTicker, BybitSpotTickerData};
 use crate::bybit_trades::{BybitTradeData, BybitTrades};
+use crate::config::{Category, OrderbookConfig, TickerConfig, TopicKind};
+use crate::queue;
 use anyhow::{Context, Result};
 use fixnum::{FixedPoint, typenum::U18};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
 use tracing::{error, info, warn};
 
 pub type Decimal128 = FixedPoint<i128, U18>;
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum Bybit {
-    Confirmation { success: bool },
-    Topics(BybitTopics),
+/// A raw text frame together with the category of the connection it came from.
+#[derive(Debug)]
+pub struct Frame {
+    pub category: Category,
+    /// Unix nanoseconds when the frame was read off the socket, or its recorded
+    /// receive time during a replay.
+    pub received_ns: i64,
+    /// Shares the socket's buffer, the parser borrows from it.
+    pub message: Utf8Bytes,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct BybitTopics {
-    topic: String,
+impl Frame {
+    pub fn received_now(category: Category, message: Utf8Bytes) -> Self {
+        Self {
+            category,
+            received_ns: Self::now_ns(),
+            message,
+        }
+    }
+
+    pub fn now_ns() -> i64 {
+        OffsetDateTime::now_utc().unix_timestamp_nanos() as i64
+    }
+}
+
+/// A `'static` copy of a symbol or another value from a small set, so rows
+/// can carry it without allocating. Every distinct value is leaked once. Each
+/// thread looks values up in its own table first, so parser workers only
+/// share a lock the first time they see a value.
+pub fn intern(value: &str) -> &'static str {
+    static INTERNED: LazyLock<Mutex<HashSet<&'static str>>> = LazyLock::new(Default::default);
+    thread_local! {
+        static LOCAL: RefCell<HashSet<&'static str>> = RefCell::default();
+    }
+    LOCAL.with_borrow_mut(|local| {
+        if let Some(value) = local.get(value) {
+            return *value;
+        }
+        let mut interned = INTERNED.lock().expect("intern table poisoned");
+        let value = match interned.get(value) {
+            Some(value) => *value,
+            None => {
+                let value: &'static str = Box::leak(value.into());
+                interned.insert(value);
+                value
+            }
+        };
+        local.insert(value);
+        value
+    })
+}
+
+/// A string inside a JSON array that borrows from the frame unless it has
+/// escapes. `Cow` only borrows as a field marked `#[serde(borrow)]`.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct JsonStr<'a>(#[serde(borrow)] pub Cow<'a, str>);
+
+/// Any message, with `data` left as raw JSON until the topic says what it is.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow)]
+    topic: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    op: Option<Cow<'a, str>>,
+    success: Option<bool>,
+    ret_msg: Option<String>,
     #[serde(rename = "ts")]
-    server_timestamp: u64,
+    server_timestamp: Option<u64>,
     #[serde(rename = "type")]
-    ttype: String,
-    data: BybitData,
+    ttype: Option<String>,
+    #[serde(borrow)]
+    data: Option<&'a RawValue>,
     #[serde(rename = "cs")]
     cross_sequence: Option<u64>,
     #[serde(rename = "cts")]
     client_timestamp: Option<u64>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-pub enum BybitData {
-    Trades(Vec<BybitTradeData>),
-    Orderbook(BybitOrderbookData),
-    Ticker(BybitTickerData),
+#[derive(Debug)]
+pub struct BybitTopics<D> {
+    topic: String,
+    server_timestamp: u64,
+    ttype: String,
+    data: D,
+    cross_sequence: Option<u64>,
+    client_timestamp: Option<u64>,
+}
+
+impl<'a> BybitTopics<&'a RawValue> {
+    /// Deserializes `data` with `decode`, which is given the topic name.
+    fn decode<D>(self, decode: fn(&str, &'a RawValue) -> Result<D>) -> Result<BybitTopics<D>> {
+        Ok(BybitTopics {
+            data: decode(&self.topic, self.data)?,
+            topic: self.topic,
+            server_timestamp: self.server_timestamp,
+            ttype: self.ttype,
+            cross_sequence: self.cross_sequence,
+            client_timestamp: self.client_timestamp,
+        })
+    }
 }
 
 #[derive(Debug)]
+pub enum BybitData<'a> {
+    Trades(Vec<BybitTradeData<'a>>),
+    Liquidations(Vec<BybitLiquidationData<'a>>),
+    Klines(Vec<BybitKlineData>),
+    Orderbook(BybitOrderbookData<'a>),
+    Ticker(Box<BybitTickerData>),
+}
+
+fn data<'a, D: Deserialize<'a>>(topic: &str, raw: &'a RawValue) -> Result<D> {
+    serde_json::from_str(raw.get()).with_context(|| format!("Invalid data for topic {}", topic))
+}
+
+fn linear_data<'a>(topic: &str, raw: &'a RawValue) -> Result<BybitData<'a>> {
+    let kind = TopicKind::of(topic).with_context(|| format!("Unsupported topic {}", topic))?;
+    Ok(match kind {
+        TopicKind::Trades => BybitData::Trades(data(topic, raw)?),
+        TopicKind::Liquidations => BybitData::Liquidations(data(topic, raw)?),
+        TopicKind::Klines => BybitData::Klines(data(topic, raw)?),
+        TopicKind::Orderbook => BybitData::Orderbook(data(topic, raw)?),
+        TopicKind::Ticker => BybitData::Ticker(data(topic, raw)?),
+    })
+}
+
+/// Spot and option connections only carry tickers.
+fn ticker_data<D: DeserializeOwned>(topic: &str, raw: &RawValue) -> Result<D> {
+    anyhow::ensure!(
+        TopicKind::of(topic) == Some(TopicKind::Ticker),
+        "Unsupported topic {}",
+        topic
+    );
+    data(topic, raw)
+}
+
+/// A parsed record on its way to the sinks. Serializable so it can be spooled to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BybitOTT {
-    BybitTicker(BybitTicker),
-    BybitOrderbook(Vec<BybitOrderbook>),
-    BybitTrades(Vec<BybitTrades>),
+    Ticker(Box<BybitTicker>),
+    TickerSparse(BybitTickerSparse),
+    TickerSpot(Box<BybitSpotTicker>),
+    TickerOption(Box<BybitOptionTicker>),
+    Funding(BybitFundingEvent),
+    Orderbook(Vec<BybitOrderbook>),
+    OrderbookLevels(Box<BybitOrderbookLevels>),
+    Trades(Vec<BybitTrades>),
+    Liquidations(Vec<BybitLiquidations>),
+    Klines(Vec<BybitKline>),
+    OpenInterest(Vec<BybitOpenInterest>),
+    LongShortRatio(Vec<BybitLongShortRatio>),
+    HistoricalVolatility(Vec<BybitHistoricalVolatility>),
 }
 
-pub async fn get_time(parsed_message: &BybitTopics) -> Result<(OffsetDateTime, OffsetDateTime)> {
+pub async fn get_time<D>(
+    parsed_message: &BybitTopics<D>,
+    received_ns: i64,
+) -> Result<(OffsetDateTime, OffsetDateTime)> {
     let server_timestamp = OffsetDateTime::from_unix_timestamp_nanos(
         (parsed_message.server_timestamp as i128) * 1_000_000,
     )
     .expect("server timestamp out of range");
 
-    let received_timestamp = OffsetDateTime::from_unix_timestamp_nanos(
-        (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) * 1_000_000,
-    )
-    .expect("received timestamp out of range");
+    let received_timestamp =
+        OffsetDateTime::from_unix_timestamp_nanos(((received_ns / 1_000_000) as i128) * 1_000_000)
+            .expect("received timestamp out of range");
 
     Ok((server_timestamp, received_timestamp))
 }
 
-pub async fn async_parse(
+/// Reads the envelope of a message. Replies to operations such as `subscribe`
+/// are logged and give `None`.
+fn parse_message(message: &str) -> Result<Option<BybitTopics<&RawValue>>> {
+    let envelope: Envelope = serde_json::from_str(message).context("Invalid message")?;
+    if let Some(op) = envelope.op {
+        match envelope.success {
+            Some(false) => warn!(
+                "Bybit {} failed: {}",
+                op,
+                envelope.ret_msg.unwrap_or_default()
+            ),
+            success => info!(
+                "Bybit {} status: success={}",
+                op,
+                success.unwrap_or_default()
+            ),
+        }
+        return Ok(None);
+    }
+    let topic = envelope.topic.context("Message has neither op nor topic")?;
+    let missing = |field: &str| format!("Message for topic {} has no {}", topic, field);
+    Ok(Some(BybitTopics {
+        server_timestamp: envelope.server_timestamp.with_context(|| missing("ts"))?,
+        ttype: envelope.ttype.with_context(|| missing("type"))?,
+        data: envelope.data.with_context(|| missing("data"))?,
+        cross_sequence: envelope.cross_sequence,
+        client_timestamp: envelope.client_timestamp,
+        topic: topic.into_owned(),
+    }))
+}
+
+/// What one parser worker handled since its last report.
+struct WorkerStats {
+    frames: u64,
+    bytes: u64,
+    busy: Duration,
+    since: Instant,
+}
+
+impl WorkerStats {
+    fn new() -> Self {
+        Self {
+            frames: 0,
+            bytes: 0,
+            busy: Duration::ZERO,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, bytes: usize, busy: Duration) {
+        self.frames += 1;
+        self.bytes += bytes as u64;
+        self.busy += busy;
+    }
+
+    fn report(&mut self, worker: usize, queued: usize) {
+        let secs = self.since.elapsed().as_secs_f64();
+        info!(
+            worker,
+            frames = self.frames,
+            frames_per_sec = format!("{:.1}", self.frames as f64 / secs),
+            mb_per_sec = format!("{:.2}", self.bytes as f64 / secs / 1e6),
+            busy_pct = format!("{:.1}", self.busy.as_secs_f64() * 100.0 / secs),
+            queued,
+            "Parser worker stats:"
+        );
+        *self = Self::new();
+    }
+}
+
+/// Everything one parser worker owns: where its records and resync requests
+/// go, the caches of its symbols and the parsing options.
+pub struct ParserContext {
+    worker: usize,
+    stats_interval: Duration,
     tx: Sender<String>,
-    mut parser_rx: Receiver<String>,
-    writer_tx: Sender<BybitOTT>,
-    orderbook_cache: &mut OrderbookCache,
-    ticker_cache: &mut TickerCache,
-) -> Result<()> {
-    info!("Starting parser task...");
+    writer_tx: queue::Sender<BybitOTT>,
+    orderbook_cache: OrderbookCache,
+    ticker_cache: TickerCache,
+    ticker_config: TickerConfig,
+    orderbook_config: OrderbookConfig,
+}
+
+impl ParserContext {
+    /// Starts from empty caches.
+    pub fn new(
+        worker: usize,
+        stats_interval: Duration,
+        tx: Sender<String>,
+        writer_tx: queue::Sender<BybitOTT>,
+        ticker_config: TickerConfig,
+        orderbook_config: OrderbookConfig,
+    ) -> Self {
+        Self {
+            worker,
+            stats_interval,
+            tx,
+            writer_tx,
+            orderbook_cache: OrderbookCache::new(),
+            ticker_cache: TickerCache::new(),
+            ticker_config,
+            orderbook_config,
+        }
+    }
 
-    while let Some(message) = parser_rx.recv().await {
-        let parsed_message: Bybit = match serde_json::from_str(&message) {
-            Ok(msg) => msg,
+    /// Parses one frame and sends its records on. Failures are logged, a bad
+    /// frame never stops the worker.
+    async fn handle_frame(&mut self, frame: &Frame) {
+        let topic = match parse_message(&frame.message) {
+            Ok(Some(topic)) => topic,
+            Ok(None) => return,
             Err(e) => {
-                error!(
-                    "JSON Deserialization failed: {} | Raw message: {}",
-                    e, message
-                );
-                continue;
+                error!("{:#} | Raw message: {}", e, frame.message.as_str());
+                return;
             }
         };
-
-        match parsed_message {
-            Bybit::Confirmation { success } => {
-                info!("Bybit subscription status: success={}", success);
-            }
-            Bybit::Topics(topic) => {
-                if let Err(e) =
-                    handle_topic(topic, &tx, &writer_tx, orderbook_cache, ticker_cache).await
-                {
-                    warn!("Failed to process topic: {:?}", e);
+        let received_ns = frame.received_ns;
+        let result = async {
+            match frame.category {
+                Category::Linear => {
+                    let topic = topic.decode(linear_data)?;
+                    self.handle_topic(topic, received_ns).await
+                }
+                Category::Spot => {
+                    let topic = topic.decode(ticker_data)?;
+                    self.handle_spot_ticker(topic, received_ns).await
+                }
+                Category::Option => {
+                    let topic = topic.decode(ticker_data)?;
+                    self.handle_option_ticker(topic, received_ns).await
                 }
             }
         }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to process topic: {:?}", e);
+        }
     }
 
-    info!("Parser channel closed. Exiting task.");
-    Ok(())
-}
+    async fn handle_spot_ticker(
+        &mut self,
+        topic: BybitTopics<BybitSpotTickerData>,
+        received_ns: i64,
+    ) -> Result<()> {
+        let (server_timestamp, received_timestamp) = get_time(&topic, received_ns)
+            .await
+            .context("Failed to calculate timestamps")?;
 
-async fn handle_topic(
-    topic: BybitTopics,
-    tx: &Sender<String>,
-    writer_tx: &Sender<BybitOTT>,
-    orderbook_cache: &mut OrderbookCache,
-    ticker_cache: &mut TickerCache,
-) -> Result<()> {
-    let (server_timestamp, received_timestamp) = get_time(&topic)
+        let to_write = BybitSpotTicker::parse_bybit_spot_ticker(
+            server_timestamp,
+            received_timestamp,
+            topic.data,
+            topic.cross_sequence,
+            &topic.ttype,
+            self.ticker_config.strict,
+            &mut self.ticker_cache,
+        )
+        .await
+        .context("Spot ticker parse error")?;
+
+        self.writer_tx
+            .send(BybitOTT::TickerSpot(Box::new(to_write)))
+            .await
+            .context("Writer channel closed (Spot ticker)")?;
+        Ok(())
+    }
+
+    async fn handle_option_ticker(
+        &mut self,
+        topic: BybitTopics<BybitOptionTickerData>,
+        received_ns: i64,
+    ) -> Result<()> {
+        let (server_timestamp, received_timestamp) = get_time(&topic, received_ns)
+            .await
+            .context("Failed to calculate timestamps")?;
+
+        let to_write = BybitOptionTicker::parse_bybit_option_ticker(
+            server_timestamp,
+            received_timestamp,
+            topic.data,
+            topic.cross_sequence,
+            &topic.ttype,
+            self.ticker_config.strict,
+            &mut self.ticker_cache,
+        )
         .await
-        .context("Failed to calculate timestamps")?;
-
-    match topic.data {
-        BybitData::Orderbook(orderbook) => {
-            let to_write = BybitOrderbook::parse_bybit_orderbook(
-                server_timestamp,
-                received_timestamp,
-                topic.client_timestamp,
-                orderbook,
-                &topic.ttype,
-                tx.clone(),
-                orderbook_cache,
-            )
+        .context("Option ticker parse error")?;
+
+        self.writer_tx
+            .send(BybitOTT::TickerOption(Box::new(to_write)))
+            .await
+            .context("Writer channel closed (Option ticker)")?;
+        Ok(())
+    }
+
+    async fn handle_topic(
+        &mut self,
+        topic: BybitTopics<BybitData<'_>>,
+        received_ns: i64,
+    ) -> Result<()> {
+        let (server_timestamp, received_timestamp) = get_time(&topic, received_ns)
             .await
-            .context("Orderbook parse error")?;
+            .context("Failed to calculate timestamps")?;
 
-            writer_tx
-                .send(BybitOTT::BybitOrderbook(to_write))
+        match topic.data {
+            BybitData::Orderbook(orderbook) => {
+                let to_write = BybitOrderbook::parse_bybit_orderbook(
+                    server_timestamp,
+                    received_timestamp,
+                    topic.client_timestamp,
+                    &topic.topic,
+                    orderbook,
+                    &topic.ttype,
+                    &self.tx,
+                    &mut self.orderbook_cache,
+                    self.orderbook_config.layout,
+                )
                 .await
-                .context("Writer channel closed (Orderbook)")?;
-        }
+                .context("Orderbook parse error")?;
+
+                if let Some(to_write) = to_write {
+                    self.writer_tx
+                        .send(to_write)
+                        .await
+                        .context("Writer channel closed (Orderbook)")?;
+                }
+            }
+
+            BybitData::Trades(trades) => {
+                let to_write =
+                    BybitTrades::parse_bybit_trades(server_timestamp, received_timestamp, trades)
+                        .await
+                        .context("Trades parse error")?;
 
-        BybitData::Trades(trades) => {
-            let to_write =
-                BybitTrades::parse_bybit_trades(server_timestamp, received_timestamp, trades)
+                self.writer_tx
+                    .send(BybitOTT::Trades(to_write))
                     .await
-                    .context("Trades parse error")?;
+                    .context("Writer channel closed (Trades)")?;
+            }
 
-            writer_tx
-                .send(BybitOTT::BybitTrades(to_write))
+            BybitData::Liquidations(liquidations) => {
+                let to_write = BybitLiquidations::parse_bybit_liquidations(
+                    server_timestamp,
+                    received_timestamp,
+                    liquidations,
+                )
                 .await
-                .context("Writer channel closed (Trades)")?;
-        }
+                .context("Liquidations parse error")?;
 
-        BybitData::Ticker(ticker) => {
-            let cross_sequence = topic.cross_sequence.ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Missing cross_sequence for ticker in topic: {}",
-                    topic.topic
+                self.writer_tx
+                    .send(BybitOTT::Liquidations(to_write))
+                    .await
+                    .context("Writer channel closed (Liquidations)")?;
+            }
+
+            BybitData::Klines(klines) => {
+                let to_write = BybitKline::parse_bybit_kline(
+                    server_timestamp,
+                    received_timestamp,
+                    &topic.topic,
+                    klines,
                 )
-            })?;
-
-            let to_write = BybitTicker::parse_bybit_ticker(
-                server_timestamp,
-                received_timestamp,
-                ticker,
-                cross_sequence,
-                &topic.ttype,
-                ticker_cache,
-            )
-            .await
-            .context("Ticker parse error")?;
+                .await
+                .context("Kline parse error")?;
+
+                self.writer_tx
+                    .send(BybitOTT::Klines(to_write))
+                    .await
+                    .context("Writer channel closed (Klines)")?;
+            }
 
-            writer_tx
-                .send(BybitOTT::BybitTicker(to_write))
+            BybitData::Ticker(ticker) => {
+                let cross_sequence = topic.cross_sequence.ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Missing cross_sequence for ticker in topic: {}",
+                        topic.topic
+                    )
+                })?;
+
+                let sparse = self.ticker_config.sparse.then(|| {
+                    BybitTickerSparse::from_data(
+                        server_timestamp,
+                        received_timestamp,
+                        &ticker,
+                        cross_sequence,
+                        &topic.ttype,
+                    )
+                });
+
+                let pending_funding = self
+                    .ticker_cache
+                    .ticker
+                    .get(&ticker.symbol)
+                    .and_then(|previous| BybitFundingEvent::pending(previous, &ticker));
+
+                let to_write = BybitTicker::parse_bybit_ticker(
+                    server_timestamp,
+                    received_timestamp,
+                    *ticker,
+                    cross_sequence,
+                    &topic.ttype,
+                    self.ticker_config.strict,
+                    &mut self.ticker_cache,
+                )
                 .await
-                .context("Writer channel closed (Ticker)")?;
+                .context("Ticker parse error")?;
+
+                if let Some(funding) = pending_funding.and_then(|f| f.settled_by(&to_write)) {
+                    info!(
+                        symbol = %funding.symbol,
+                        funding_rate = %funding.funding_rate,
+                        "Funding settlement detected"
+                    );
+                    self.writer_tx
+                        .send(BybitOTT::Funding(funding))
+                        .await
+                        .context("Writer channel closed (Funding)")?;
+                }
+
+                let to_write = match sparse {
+                    Some(sparse) => BybitOTT::TickerSparse(sparse),
+                    None => BybitOTT::Ticker(Box::new(to_write)),
+                };
+                self.writer_tx
+                    .send(to_write)
+                    .await
+                    .context("Writer channel closed (Ticker)")?;
+            }
         }
+
+        Ok(())
     }
+}
 
+/// One parser worker. Logs its throughput every `stats_interval` and once more
+/// when its channel closes.
+pub async fn async_parse(
+    mut ctx: ParserContext,
+    parser_rx: &mut queue::Receiver<Frame>,
+) -> Result<()> {
+    info!("Starting parser worker {}...", ctx.worker);
+
+    let mut stats = WorkerStats::new();
+    let report = tokio::time::sleep(ctx.stats_interval);
+    tokio::pin!(report);
+    loop {
+        tokio::select! {
+            frame = parser_rx.recv() => {
+                let Some(frame) = frame else { break };
+                let started = Instant::now();
+                ctx.handle_frame(&frame).await;
+                stats.record(frame.message.len(), started.elapsed());
+            }
+            () = &mut report => {
+                stats.report(ctx.worker, parser_rx.depth());
+                report.set(tokio::time::sleep(ctx.stats_interval));
+            }
+        }
+    }
+
+    stats.report(ctx.worker, 0);
+    info!("Parser channel closed. Exiting worker {}.", ctx.worker);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(message: &str) -> String {
+        format!("{:#}", parse_message(message).unwrap_err())
+    }
+
+    #[test]
+    fn json_strings_borrow_unless_escaped() {
+        let [plain, escaped]: [JsonStr; 2] =
+            serde_json::from_str(r#"["65000.5","6500\u0030.5"]"#).unwrap();
+        assert!(matches!(plain.0, Cow::Borrowed("65000.5")));
+        assert!(matches!(escaped.0, Cow::Owned(ref s) if s == "65000.5"));
+    }
+
+    #[test]
+    fn interning_gives_one_copy_per_value() {
+        let symbol = intern("INTERNUSDT");
+        let other_thread = std::thread::spawn(|| intern("INTERNUSDT")).join().unwrap();
+        assert!(std::ptr::eq(symbol, other_thread));
+        assert!(std::ptr::eq(symbol, intern(&format!("INTERN{}", "USDT"))));
+    }
+
+    #[tokio::test]
+    async fn worker_parses_until_its_channel_closes() {
+        let channel = crate::config::ChannelConfig::default();
+        let (parser_tx, mut parser_rx) = queue::channel::<Frame>("parser_test", &channel).unwrap();
+        let (writer_tx, mut writer_rx) =
+            queue::channel::<BybitOTT>("writer_test", &channel).unwrap();
+        let (tx, _resync_rx) = tokio::sync::mpsc::channel(1);
+        for message in [
+            r#"{"success":true,"ret_msg":"","op":"subscribe"}"#,
+            "not json",
+            r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1700000000000,"data":[{"T":1700000000000,"s":"BTCUSDT","S":"Sell","v":"0.5","p":"65000","L":"MinusTick","i":"t1","BT":false,"RPI":false,"seq":7}]}"#,
+        ] {
+            parser_tx
+                .send(Frame::received_now(Category::Linear, message.into()))
+                .await
+                .unwrap();
+        }
+        drop(parser_tx);
+        let ctx = ParserContext::new(
+            0,
+            Duration::from_millis(1),
+            tx,
+            writer_tx,
+            TickerConfig::default(),
+            OrderbookConfig::default(),
+        );
+        async_parse(ctx, &mut parser_rx).await.unwrap();
+        let Some(BybitOTT::Trades(trades)) = writer_rx.recv().await else {
+            panic!("expected the trade");
+        };
+        assert_eq!((trades[0].side.as_ref(), trades[0].seq), ("Sell", 7));
+        assert!(writer_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn op_replies_give_nothing() {
+        for message in [
+            r#"{"success":true,"ret_msg":"","conn_id":"c1","req_id":"","op":"subscribe"}"#,
+            r#"{"success":false,"ret_msg":"error:handler not found","conn_id":"c1","op":"subscribe"}"#,
+            r#"{"success":true,"ret_msg":"pong","conn_id":"c1","op":"ping"}"#,
+            r#"{"op":"pong","args":["1700000000000"],"conn_id":"c1"}"#,
+        ] {
+            assert!(parse_message(message).unwrap().is_none(), "{}", message);
+        }
+    }
+
+    #[test]
+    fn topic_messages_keep_their_envelope() {
+        let message = r#"{"topic":"tickers.BTCUSDT","type":"delta","data":{"symbol":"BTCUSDT"},"cs":42,"ts":1700000000123,"cts":1700000000100}"#;
+        let topic = parse_message(message).unwrap().unwrap();
+        assert_eq!(topic.topic, "tickers.BTCUSDT");
+        assert_eq!(topic.ttype, "delta");
+        assert_eq!(topic.server_timestamp, 1_700_000_000_123);
+        assert_eq!(topic.data.get(), r#"{"symbol":"BTCUSDT"}"#);
+        assert_eq!(topic.cross_sequence, Some(42));
+        assert_eq!(topic.client_timestamp, Some(1_700_000_000_100));
+    }
+
+    #[test]
+    fn topic_messages_need_ts_type_and_data() {
+        assert_eq!(
+            error(r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","data":[]}"#),
+            "Message for topic publicTrade.BTCUSDT has no ts"
+        );
+        assert_eq!(
+            error(r#"{"topic":"publicTrade.BTCUSDT","ts":1,"data":[]}"#),
+            "Message for topic publicTrade.BTCUSDT has no type"
+        );
+        assert_eq!(
+            error(r#"{"topic":"publicTrade.BTCUSDT","ts":1,"type":"snapshot"}"#),
+            "Message for topic publicTrade.BTCUSDT has no data"
+        );
+    }
+
+    #[test]
+    fn messages_need_an_op_or_a_topic() {
+        assert_eq!(
+            error(r#"{"ts":1,"type":"snapshot","data":[]}"#),
+            "Message has neither op nor topic"
+        );
+        assert!(error("not json").starts_with("Invalid message"));
+    }
+}
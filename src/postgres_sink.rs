@@ -0,0 +1,824 @@
+//! PostgreSQL / TimescaleDB tables mirroring `trades_raw_ml`, `orderbook_raw_ml`,
+//! `orderbook_levels_raw_ml` and `ticker_raw_ml`, written with binary `COPY`.
+
+use crate::bybit_orderbook::{BybitOrderbook, BybitOrderbookLevels};
+use crate::bybit_ticker::BybitTicker;
+use crate::bybit_trades::BybitTrades;
+use crate::parser::{BybitOTT, Decimal128};
+use crate::sink::Sink;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use futures_util::pin_mut;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{IsNull, ToSql, Type, to_sql_checked};
+use tokio_postgres::{Client, NoTls};
+use tracing::{error, info};
+
+/// Rows buffered per table before they are copied.
+const BATCH_ROWS: usize = 10_000;
+const COPY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `Decimal128(18)` is `NUMERIC(38, 18)`, `DateTime64(3, 'UTC')` is
+/// `TIMESTAMPTZ` and `UInt64` is `BIGINT`.
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS trades_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ NOT NULL,
+        received_timestamp      TIMESTAMPTZ NOT NULL,
+        trade_timestamp         TIMESTAMPTZ NOT NULL,
+        symbol                  TEXT NOT NULL,
+        trade_id                TEXT NOT NULL,
+        side                    TEXT NOT NULL,
+        price                   NUMERIC(38, 18) NOT NULL,
+        volume                  NUMERIC(38, 18) NOT NULL,
+        tick_direction          TEXT NOT NULL,
+        is_block_trade          BOOLEAN NOT NULL,
+        is_rpi                  BOOLEAN NOT NULL,
+        seq                     BIGINT NOT NULL,
+        exchange                TEXT NOT NULL DEFAULT 'bybit'
+    );
+    CREATE INDEX IF NOT EXISTS trades_raw_ml_symbol_idx
+        ON trades_raw_ml (symbol, trade_timestamp DESC);
+
+    CREATE TABLE IF NOT EXISTS orderbook_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ NOT NULL,
+        received_timestamp      TIMESTAMPTZ NOT NULL,
+        client_timestamp        TIMESTAMPTZ NOT NULL,
+        symbol                  TEXT NOT NULL,
+        side                    TEXT NOT NULL,
+        price                   NUMERIC(38, 18) NOT NULL,
+        volume                  NUMERIC(38, 18) NOT NULL,
+        update                  BIGINT NOT NULL,
+        exchange                TEXT NOT NULL DEFAULT 'bybit'
+    );
+    CREATE INDEX IF NOT EXISTS orderbook_raw_ml_symbol_idx
+        ON orderbook_raw_ml (symbol, server_timestamp DESC);
+
+    CREATE TABLE IF NOT EXISTS orderbook_levels_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ NOT NULL,
+        received_timestamp      TIMESTAMPTZ NOT NULL,
+        client_timestamp        TIMESTAMPTZ NOT NULL,
+        symbol                  TEXT NOT NULL,
+        update                  BIGINT NOT NULL,
+        bid_prices              NUMERIC(38, 18)[] NOT NULL,
+        bid_sizes               NUMERIC(38, 18)[] NOT NULL,
+        ask_prices              NUMERIC(38, 18)[] NOT NULL,
+        ask_sizes               NUMERIC(38, 18)[] NOT NULL,
+        exchange                TEXT NOT NULL DEFAULT 'bybit'
+    );
+    CREATE INDEX IF NOT EXISTS orderbook_levels_raw_ml_symbol_idx
+        ON orderbook_levels_raw_ml (symbol, server_timestamp DESC);
+
+    CREATE TABLE IF NOT EXISTS ticker_raw_ml
+    (
+        server_timestamp        TIMESTAMPTZ NOT NULL,
+        received_timestamp      TIMESTAMPTZ NOT NULL,
+        cross_sequence          BIGINT NOT NULL,
+        symbol                  TEXT NOT NULL,
+        tick_direction          TEXT NOT NULL,
+        price_24h_pcnt          NUMERIC(38, 18) NOT NULL,
+        last_price              NUMERIC(38, 18) NOT NULL,
+        prev_price_24h          NUMERIC(38, 18) NOT NULL,
+        high_price_24h          NUMERIC(38, 18) NOT NULL,
+        low_price_24h           NUMERIC(38, 18) NOT NULL,
+        prev_price_1h           NUMERIC(38, 18) NOT NULL,
+        mark_price              NUMERIC(38, 18) NOT NULL,
+        index_price             NUMERIC(38, 18) NOT NULL,
+        open_interest           NUMERIC(38, 18) NOT NULL,
+        open_interest_value     NUMERIC(38, 18) NOT NULL,
+        turnover_24h            NUMERIC(38, 18) NOT NULL,
+        volume_24h              NUMERIC(38, 18) NOT NULL,
+        next_funding_time       TIMESTAMPTZ NOT NULL,
+        funding_rate            NUMERIC(38, 18) NOT NULL,
+        bid1_price              NUMERIC(38, 18) NOT NULL,
+        bid1_size               NUMERIC(38, 18) NOT NULL,
+        ask1_price              NUMERIC(38, 18) NOT NULL,
+        ask1_size               NUMERIC(38, 18) NOT NULL,
+        delivery_time           TIMESTAMPTZ,
+        basis_rate              NUMERIC(38, 18),
+        delivery_fee_rate       NUMERIC(38, 18),
+        predicted_delivery_price NUMERIC(38, 18),
+        pre_open_price          NUMERIC(38, 18),
+        pre_qty                 NUMERIC(38, 18),
+        cur_pre_listing_phase   TEXT,
+        funding_interval_hour   TEXT,
+        funding_cap             NUMERIC(38, 18),
+        basis_rate_year         NUMERIC(38, 18),
+        is_stale                BOOLEAN NOT NULL DEFAULT false,
+        stale_fields            TEXT[] NOT NULL,
+        exchange                TEXT NOT NULL DEFAULT 'bybit'
+    );
+    CREATE INDEX IF NOT EXISTS ticker_raw_ml_symbol_idx
+        ON ticker_raw_ml (symbol, server_timestamp DESC);
+    -- delivery_fee_rate used to be a BIGINT.
+    ALTER TABLE ticker_raw_ml ALTER COLUMN delivery_fee_rate TYPE NUMERIC(38, 18);
+"#;
+
+/// Turns the tables into hypertables chunked by day on their event time.
+const HYPERTABLES: &str = r#"
+    CREATE EXTENSION IF NOT EXISTS timescaledb;
+    SELECT create_hypertable('trades_raw_ml', 'trade_timestamp',
+        chunk_time_interval => INTERVAL '1 day', if_not_exists => TRUE);
+    SELECT create_hypertable('orderbook_raw_ml', 'server_timestamp',
+        chunk_time_interval => INTERVAL '1 day', if_not_exists => TRUE);
+    SELECT create_hypertable('orderbook_levels_raw_ml', 'server_timestamp',
+        chunk_time_interval => INTERVAL '1 day', if_not_exists => TRUE);
+    SELECT create_hypertable('ticker_raw_ml', 'server_timestamp',
+        chunk_time_interval => INTERVAL '1 day', if_not_exists => TRUE);
+"#;
+
+/// A `Decimal128` encoded as a binary `NUMERIC`, keeping all 38 digits.
+#[derive(Debug)]
+struct Numeric(Decimal128);
+
+impl ToSql for Numeric {
+    /// The binary format is a list of base-10000 digits, most significant
+    /// first, where the first one is worth `10000^weight`.
+    fn to_sql(&self, _: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        const SCALE: u32 = 18;
+        let bits = self.0.into_bits();
+        let abs = bits.unsigned_abs();
+        let (mut integer, fraction) = (abs / 10u128.pow(SCALE), abs % 10u128.pow(SCALE));
+
+        let mut digits = Vec::with_capacity(16);
+        while integer > 0 {
+            digits.push((integer % 10_000) as i16);
+            integer /= 10_000;
+        }
+        digits.reverse();
+        let mut weight = digits.len() as i16 - 1;
+        // 18 fractional digits padded to 20 make five base-10000 digits.
+        let mut fraction = fraction * 100;
+        let mut fraction_digits = [0i16; 5];
+        for digit in fraction_digits.iter_mut().rev() {
+            *digit = (fraction % 10_000) as i16;
+            fraction /= 10_000;
+        }
+        digits.extend(fraction_digits);
+
+        let leading = digits.iter().take_while(|d| **d == 0).count();
+        digits.drain(..leading);
+        weight -= leading as i16;
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        if digits.is_empty() {
+            weight = 0;
+        }
+
+        out.put_i16(digits.len() as i16);
+        out.put_i16(weight);
+        out.put_u16(if bits < 0 { 0x4000 } else { 0x0000 });
+        out.put_i16(SCALE as i16);
+        for digit in digits {
+            out.put_i16(digit);
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+
+    to_sql_checked!();
+}
+
+type Value = Box<dyn ToSql + Sync + Send>;
+
+fn numeric(value: Decimal128) -> Value {
+    Box::new(Numeric(value))
+}
+
+fn opt_numeric(value: Option<Decimal128>) -> Value {
+    Box::new(value.map(Numeric))
+}
+
+fn numeric_array(values: &[Decimal128]) -> Value {
+    Box::new(values.iter().copied().map(Numeric).collect::<Vec<_>>())
+}
+
+/// `UInt64` as `BIGINT`. Values past `i64::MAX` are rejected rather than wrapped.
+fn bigint(column: &str, value: u64) -> Result<Value> {
+    let value = i64::try_from(value)
+        .with_context(|| format!("{} {} does not fit a BIGINT", column, value))?;
+    Ok(Box::new(value))
+}
+
+/// A record type with a matching Postgres table.
+trait PgRecord: Clone {
+    const TABLE: &'static str;
+    /// Column names and types in `values` order.
+    fn columns() -> Vec<(&'static str, Type)>;
+    fn values(&self) -> Result<Vec<Value>>;
+}
+
+impl PgRecord for BybitTrades {
+    const TABLE: &'static str = "trades_raw_ml";
+
+    fn columns() -> Vec<(&'static str, Type)> {
+        vec![
+            ("server_timestamp", Type::TIMESTAMPTZ),
+            ("received_timestamp", Type::TIMESTAMPTZ),
+            ("trade_timestamp", Type::TIMESTAMPTZ),
+            ("symbol", Type::TEXT),
+            ("trade_id", Type::TEXT),
+            ("side", Type::TEXT),
+            ("price", Type::NUMERIC),
+            ("volume", Type::NUMERIC),
+            ("tick_direction", Type::TEXT),
+            ("is_block_trade", Type::BOOL),
+            ("is_rpi", Type::BOOL),
+            ("seq", Type::INT8),
+            ("exchange", Type::TEXT),
+        ]
+    }
+
+    fn values(&self) -> Result<Vec<Value>> {
+        Ok(vec![
+            Box::new(self.server_timestamp),
+            Box::new(self.received_timestamp),
+            Box::new(self.trade_timestamp),
+            Box::new(self.symbol.clone()),
+            Box::new(self.trade_id.clone()),
+            Box::new(self.side.clone()),
+            numeric(self.price),
+            numeric(self.volume),
+            Box::new(self.tick_direction.clone()),
+            Box::new(self.is_block_trade),
+            Box::new(self.is_rpi),
+            bigint("seq", self.seq)?,
+            Box::new(self.exchange.clone()),
+        ])
+    }
+}
+
+impl PgRecord for BybitOrderbook {
+    const TABLE: &'static str = "orderbook_raw_ml";
+
+    fn columns() -> Vec<(&'static str, Type)> {
+        vec![
+            ("server_timestamp", Type::TIMESTAMPTZ),
+            ("received_timestamp", Type::TIMESTAMPTZ),
+            ("client_timestamp", Type::TIMESTAMPTZ),
+            ("symbol", Type::TEXT),
+            ("side", Type::TEXT),
+            ("price", Type::NUMERIC),
+            ("volume", Type::NUMERIC),
+            ("update", Type::INT8),
+            ("exchange", Type::TEXT),
+        ]
+    }
+
+    fn values(&self) -> Result<Vec<Value>> {
+        Ok(vec![
+            Box::new(self.server_timestamp),
+            Box::new(self.received_timestamp),
+            Box::new(self.client_timestamp),
+            Box::new(self.symbol.clone()),
+            Box::new(self.side.to_string()),
+            numeric(self.price),
+            numeric(self.volume),
+            bigint("update", self.update)?,
+            Box::new(self.exchange.to_string()),
+        ])
+    }
+}
+
+impl PgRecord for BybitOrderbookLevels {
+    const TABLE: &'static str = "orderbook_levels_raw_ml";
+
+    fn columns() -> Vec<(&'static str, Type)> {
+        vec![
+            ("server_timestamp", Type::TIMESTAMPTZ),
+            ("received_timestamp", Type::TIMESTAMPTZ),
+            ("client_timestamp", Type::TIMESTAMPTZ),
+            ("symbol", Type::TEXT),
+            ("update", Type::INT8),
+            ("bid_prices", Type::NUMERIC_ARRAY),
+            ("bid_sizes", Type::NUMERIC_ARRAY),
+            ("ask_prices", Type::NUMERIC_ARRAY),
+            ("ask_sizes", Type::NUMERIC_ARRAY),
+            ("exchange", Type::TEXT),
+        ]
+    }
+
+    fn values(&self) -> Result<Vec<Value>> {
+        Ok(vec![
+            Box::new(self.server_timestamp),
+            Box::new(self.received_timestamp),
+            Box::new(self.client_timestamp),
+            Box::new(self.symbol.clone()),
+            bigint("update", self.update)?,
+            numeric_array(&self.bid_prices),
+            numeric_array(&self.bid_sizes),
+            numeric_array(&self.ask_prices),
+            numeric_array(&self.ask_sizes),
+            Box::new(self.exchange.to_string()),
+        ])
+    }
+}
+
+impl PgRecord for BybitTicker {
+    const TABLE: &'static str = "ticker_raw_ml";
+
+    fn columns() -> Vec<(&'static str, Type)> {
+        vec![
+            ("server_timestamp", Type::TIMESTAMPTZ),
+            ("received_timestamp", Type::TIMESTAMPTZ),
+            ("cross_sequence", Type::INT8),
+            ("symbol", Type::TEXT),
+            ("tick_direction", Type::TEXT),
+            ("price_24h_pcnt", Type::NUMERIC),
+            ("last_price", Type::NUMERIC),
+            ("prev_price_24h", Type::NUMERIC),
+            ("high_price_24h", Type::NUMERIC),
+            ("low_price_24h", Type::NUMERIC),
+            ("prev_price_1h", Type::NUMERIC),
+            ("mark_price", Type::NUMERIC),
+            ("index_price", Type::NUMERIC),
+            ("open_interest", Type::NUMERIC),
+            ("open_interest_value", Type::NUMERIC),
+            ("turnover_24h", Type::NUMERIC),
+            ("volume_24h", Type::NUMERIC),
+            ("next_funding_time", Type::TIMESTAMPTZ),
+            ("funding_rate", Type::NUMERIC),
+            ("bid1_price", Type::NUMERIC),
+            ("bid1_size", Type::NUMERIC),
+            ("ask1_price", Type::NUMERIC),
+            ("ask1_size", Type::NUMERIC),
+            ("delivery_time", Type::TIMESTAMPTZ),
+            ("basis_rate", Type::NUMERIC),
+            ("delivery_fee_rate", Type::NUMERIC),
+            ("predicted_delivery_price", Type::NUMERIC),
+            ("pre_open_price", Type::NUMERIC),
+            ("pre_qty", Type::NUMERIC),
+            ("cur_pre_listing_phase", Type::TEXT),
+            ("funding_interval_hour", Type::TEXT),
+            ("funding_cap", Type::NUMERIC),
+            ("basis_rate_year", Type::NUMERIC),
+            ("is_stale", Type::BOOL),
+            ("stale_fields", Type::TEXT_ARRAY),
+        ]
+    }
+
+    fn values(&self) -> Result<Vec<Value>> {
+        Ok(vec![
+            Box::new(self.server_timestamp),
+            Box::new(self.received_timestamp),
+            bigint("cross_sequence", self.cross_sequence)?,
+            Box::new(self.symbol.clone()),
+            Box::new(self.tick_direction.clone()),
+            numeric(self.price_24h_pcnt),
+            numeric(self.last_price),
+            numeric(self.prev_price_24h),
+            numeric(self.high_price_24h),
+            numeric(self.low_price_24h),
+            numeric(self.prev_price_1h),
+            numeric(self.mark_price),
+            numeric(self.index_price),
+            numeric(self.open_interest),
+            numeric(self.open_interest_value),
+            numeric(self.turnover_24h),
+            numeric(self.volume_24h),
+            Box::new(self.next_funding_time),
+            numeric(self.funding_rate),
+            numeric(self.bid1_price),
+            numeric(self.bid1_size),
+            numeric(self.ask1_price),
+            numeric(self.ask1_size),
+            Box::new(self.delivery_time),
+            opt_numeric(self.basis_rate),
+            opt_numeric(self.delivery_fee_rate),
+            opt_numeric(self.predicted_delivery_price),
+            opt_numeric(self.pre_open_price),
+            opt_numeric(self.pre_qty),
+            Box::new(self.cur_pre_listing_phase.clone()),
+            Box::new(self.funding_interval_hour.clone()),
+            opt_numeric(self.funding_cap),
+            opt_numeric(self.basis_rate_year),
+            Box::new(self.is_stale),
+            Box::new(self.stale_fields.clone()),
+        ])
+    }
+}
+
+/// Streams `rows` into their table with one binary `COPY`.
+async fn copy<T: PgRecord>(client: &Client, rows: &mut Vec<T>) -> Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let (names, types): (Vec<&str>, Vec<Type>) = T::columns().into_iter().unzip();
+    let statement = format!("COPY {} ({}) FROM STDIN BINARY", T::TABLE, names.join(", "));
+    let sink = client.copy_in(statement.as_str()).await?;
+    let writer = BinaryCopyInWriter::new(sink, &types);
+    pin_mut!(writer);
+    for row in rows.iter() {
+        writer.as_mut().write_raw(row.values()?).await?;
+    }
+    writer
+        .finish()
+        .await
+        .with_context(|| format!("COPY into {} failed", T::TABLE))?;
+    rows.clear();
+    Ok(())
+}
+
+pub struct PostgresSink {
+    url: String,
+    hypertables: bool,
+    client: Option<Client>,
+    trades: Vec<BybitTrades>,
+    orderbook: Vec<BybitOrderbook>,
+    orderbook_levels: Vec<BybitOrderbookLevels>,
+    ticker: Vec<BybitTicker>,
+    last_copy: Instant,
+}
+
+impl PostgresSink {
+    /// Connects lazily on the first write, so building the sink never blocks.
+    pub fn new(url: String, hypertables: bool) -> Self {
+        Self {
+            url,
+            hypertables,
+            client: None,
+            trades: Vec::new(),
+            orderbook: Vec::new(),
+            orderbook_levels: Vec::new(),
+            ticker: Vec::new(),
+            last_copy: Instant::now(),
+        }
+    }
+
+    async fn connect(&mut self) -> Result<&Client> {
+        if self.client.as_ref().is_some_and(|c| c.is_closed()) {
+            self.client = None;
+        }
+        if self.client.is_none() {
+            let (client, connection) = tokio_postgres::connect(&self.url, NoTls)
+                .await
+                .context("Failed to connect to Postgres")?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    error!("Postgres connection failed: {:?}", e);
+                }
+            });
+            client
+                .batch_execute(SCHEMA)
+                .await
+                .context("Failed to create Postgres tables")?;
+            if self.hypertables {
+                client
+                    .batch_execute(HYPERTABLES)
+                    .await
+                    .context("Failed to create hypertables")?;
+            }
+            info!("Postgres sink connected.");
+            self.client = Some(client);
+        }
+        Ok(self.client.as_ref().expect("client was just connected"))
+    }
+
+    async fn copy_all(&mut self) -> Result<()> {
+        let (mut trades, mut orderbook, mut orderbook_levels, mut ticker) = (
+            std::mem::take(&mut self.trades),
+            std::mem::take(&mut self.orderbook),
+            std::mem::take(&mut self.orderbook_levels),
+            std::mem::take(&mut self.ticker),
+        );
+        let result = async {
+            let client = self.connect().await?;
+            copy(client, &mut trades).await?;
+            copy(client, &mut orderbook).await?;
+            copy(client, &mut orderbook_levels).await?;
+            copy(client, &mut ticker).await
+        }
+        .await;
+        // Rows that were not copied stay buffered for the next attempt.
+        self.trades = trades;
+        self.orderbook = orderbook;
+        self.orderbook_levels = orderbook_levels;
+        self.ticker = ticker;
+        self.last_copy = Instant::now();
+        result
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()> {
+        match record.as_ref() {
+            BybitOTT::Trades(trades) => self.trades.extend_from_slice(trades),
+            BybitOTT::Orderbook(orderbook) => self.orderbook.extend_from_slice(orderbook),
+            BybitOTT::OrderbookLevels(levels) => {
+                self.orderbook_levels.push(levels.as_ref().clone())
+            }
+            BybitOTT::Ticker(ticker) => self.ticker.push(ticker.as_ref().clone()),
+            _ => return Ok(()),
+        }
+        let buffered = self
+            .trades
+            .len()
+            .max(self.orderbook.len())
+            .max(self.orderbook_levels.len())
+            .max(self.ticker.len());
+        if buffered >= BATCH_ROWS || self.last_copy.elapsed() >= COPY_INTERVAL {
+            self.copy_all().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.copy_all().await
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<()> {
+        self.copy_all().await?;
+        info!("Postgres sink closed.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use time::OffsetDateTime;
+
+    fn decimal(value: &str) -> Decimal128 {
+        Decimal128::from_str(value).unwrap()
+    }
+
+    /// The header (ndigits, weight, sign, dscale) and digits of `value`.
+    fn encode(value: &str) -> (i16, i16, u16, i16, Vec<i16>) {
+        let mut out = BytesMut::new();
+        Numeric(decimal(value))
+            .to_sql(&Type::NUMERIC, &mut out)
+            .unwrap();
+        let mut words = out.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]));
+        let mut next = || words.next().unwrap();
+        let (ndigits, weight, sign, dscale) = (next() as i16, next() as i16, next(), next() as i16);
+        let digits = (0..ndigits).map(|_| next() as i16).collect();
+        (ndigits, weight, sign, dscale, digits)
+    }
+
+    /// Reads the encoding back the way Postgres does.
+    fn decode(value: &str) -> Decimal128 {
+        let (_, weight, sign, _, digits) = encode(value);
+        let mut bits: i128 = 0;
+        for (i, digit) in digits.iter().enumerate() {
+            let exponent = 18 + 4 * (weight as i32 - i as i32);
+            bits += match exponent >= 0 {
+                true => *digit as i128 * 10i128.pow(exponent as u32),
+                false => *digit as i128 / 10i128.pow(exponent.unsigned_abs()),
+            };
+        }
+        Decimal128::from_bits(if sign == 0x4000 { -bits } else { bits })
+    }
+
+    #[test]
+    fn numeric_zero_has_no_digits() {
+        assert_eq!(encode("0"), (0, 0, 0, 18, vec![]));
+        assert_eq!(decode("0"), decimal("0"));
+    }
+
+    #[test]
+    fn numeric_keeps_the_sign() {
+        assert_eq!(encode("-1.5"), (2, 0, 0x4000, 18, vec![1, 5000]));
+        for value in ["-1.5", "-0.000000000000000001", "-65000.25"] {
+            assert_eq!(decode(value), decimal(value));
+        }
+    }
+
+    #[test]
+    fn numeric_encodes_the_smallest_fraction() {
+        assert_eq!(encode("0.000000000000000001"), (1, -5, 0, 18, vec![100]));
+        assert_eq!(
+            decode("0.000000000000000001"),
+            decimal("0.000000000000000001")
+        );
+    }
+
+    #[test]
+    fn numeric_encodes_many_integer_digits() {
+        let max = Decimal128::from_bits(i128::MAX).to_string();
+        let (ndigits, weight, ..) = encode(&max);
+        assert_eq!((ndigits, weight), (11, 5));
+        assert_eq!(decode(&max), decimal(&max));
+        let min = Decimal128::from_bits(-i128::MAX).to_string();
+        assert_eq!(decode(&min), decimal(&min));
+    }
+
+    #[test]
+    fn numeric_drops_zero_digits_of_multiples_of_10000() {
+        assert_eq!(encode("10000"), (1, 1, 0, 18, vec![1]));
+        assert_eq!(encode("100000000"), (1, 2, 0, 18, vec![1]));
+        assert_eq!(encode("0.0001"), (1, -1, 0, 18, vec![1]));
+        assert_eq!(encode("20000.0003"), (3, 1, 0, 18, vec![2, 0, 3]));
+    }
+
+    fn trade(symbol: &str, seq: u64) -> BybitTrades {
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        BybitTrades {
+            server_timestamp: time,
+            received_timestamp: time,
+            trade_timestamp: time,
+            symbol: symbol.to_string().into(),
+            trade_id: "t-1".to_string(),
+            side: "Buy".into(),
+            price: decimal("65000.5"),
+            volume: decimal("0.000000000000000001"),
+            tick_direction: "PlusTick".into(),
+            is_block_trade: false,
+            is_rpi: true,
+            seq,
+            exchange: "bybit".into(),
+        }
+    }
+
+    #[test]
+    fn bigint_rejects_values_past_i64_max() {
+        assert!(trade("BTCUSDT", i64::MAX as u64).values().is_ok());
+        assert!(trade("BTCUSDT", i64::MAX as u64 + 1).values().is_err());
+    }
+
+    /// Copies one record of every type into the database at
+    /// `POSTGRES_TEST_URL` and reads them back. Skipped when it is not set.
+    #[tokio::test]
+    async fn copies_every_record_type() {
+        let Ok(url) = std::env::var("POSTGRES_TEST_URL") else {
+            eprintln!("POSTGRES_TEST_URL is not set, skipping.");
+            return;
+        };
+        let symbol = format!("TEST{}", std::process::id());
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let trade = trade(&symbol, 42);
+        let orderbook = BybitOrderbook {
+            server_timestamp: time,
+            received_timestamp: time,
+            client_timestamp: time,
+            symbol: symbol.clone().into(),
+            side: "Bid".into(),
+            price: decimal("64999.9"),
+            volume: decimal("-0.5"),
+            update: 7,
+            exchange: "bybit".into(),
+        };
+        let levels = BybitOrderbookLevels {
+            server_timestamp: time,
+            received_timestamp: time,
+            client_timestamp: time,
+            symbol: symbol.clone().into(),
+            update: 8,
+            bid_prices: vec![decimal("64999.9"), decimal("64999.8")],
+            bid_sizes: vec![decimal("1"), decimal("10000")],
+            ask_prices: vec![decimal("65000")],
+            ask_sizes: vec![decimal("0.0001")],
+            exchange: "bybit".into(),
+        };
+        let ticker = BybitTicker {
+            server_timestamp: time,
+            received_timestamp: time,
+            cross_sequence: 9,
+            symbol: symbol.clone(),
+            tick_direction: "ZeroPlusTick".to_string(),
+            price_24h_pcnt: decimal("-0.0123"),
+            last_price: decimal("65000.5"),
+            prev_price_24h: decimal("64000"),
+            high_price_24h: decimal("66000"),
+            low_price_24h: decimal("63000"),
+            prev_price_1h: decimal("64900"),
+            mark_price: decimal("65000.1"),
+            index_price: decimal("65000.2"),
+            open_interest: decimal("12345.678"),
+            open_interest_value: decimal("802469070.123"),
+            turnover_24h: decimal("1000000000"),
+            volume_24h: decimal("15384.6"),
+            next_funding_time: time,
+            funding_rate: decimal("0.0001"),
+            bid1_price: decimal("64999.9"),
+            bid1_size: decimal("1"),
+            ask1_price: decimal("65000"),
+            ask1_size: decimal("2"),
+            delivery_time: None,
+            basis_rate: Some(decimal("0.01")),
+            delivery_fee_rate: Some(decimal("0.0005")),
+            predicted_delivery_price: None,
+            pre_open_price: None,
+            pre_qty: None,
+            cur_pre_listing_phase: Some("NotStarted".to_string()),
+            funding_interval_hour: Some("8".to_string()),
+            funding_cap: None,
+            basis_rate_year: Some(decimal("0.1")),
+            is_stale: true,
+            stale_fields: vec!["mark_price".to_string()],
+        };
+
+        let mut sink = PostgresSink::new(url, false);
+        for record in [
+            BybitOTT::Trades(vec![trade.clone()]),
+            BybitOTT::Orderbook(vec![orderbook.clone()]),
+            BybitOTT::OrderbookLevels(Box::new(levels.clone())),
+            BybitOTT::Ticker(Box::new(ticker.clone())),
+        ] {
+            sink.write(Arc::new(record)).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+        let client = sink.connect().await.unwrap();
+
+        let row = client
+            .query_one(
+                "SELECT trade_timestamp, price::text, volume::text, seq, is_rpi \
+                 FROM trades_raw_ml WHERE symbol = $1",
+                &[&symbol],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, OffsetDateTime>(0), trade.trade_timestamp);
+        assert_eq!(decimal(row.get(1)), trade.price);
+        assert_eq!(decimal(row.get(2)), trade.volume);
+        assert_eq!(row.get::<_, i64>(3), 42);
+        assert!(row.get::<_, bool>(4));
+
+        let row = client
+            .query_one(
+                "SELECT side, price::text, volume::text, update \
+                 FROM orderbook_raw_ml WHERE symbol = $1",
+                &[&symbol],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, &str>(0), "Bid");
+        assert_eq!(decimal(row.get(1)), orderbook.price);
+        assert_eq!(decimal(row.get(2)), orderbook.volume);
+        assert_eq!(row.get::<_, i64>(3), 7);
+
+        let row = client
+            .query_one(
+                "SELECT update, bid_prices::text[], bid_sizes::text[], ask_prices::text[], \
+                 ask_sizes::text[] FROM orderbook_levels_raw_ml WHERE symbol = $1",
+                &[&symbol],
+            )
+            .await
+            .unwrap();
+        let column = |i: usize| -> Vec<Decimal128> {
+            row.get::<_, Vec<&str>>(i)
+                .into_iter()
+                .map(decimal)
+                .collect()
+        };
+        assert_eq!(row.get::<_, i64>(0), 8);
+        assert_eq!(column(1), levels.bid_prices);
+        assert_eq!(column(2), levels.bid_sizes);
+        assert_eq!(column(3), levels.ask_prices);
+        assert_eq!(column(4), levels.ask_sizes);
+
+        let row = client
+            .query_one(
+                "SELECT cross_sequence, last_price::text, price_24h_pcnt::text, delivery_time, \
+                 basis_rate::text, funding_cap::text, delivery_fee_rate::text, stale_fields \
+                 FROM ticker_raw_ml WHERE symbol = $1",
+                &[&symbol],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i64>(0), 9);
+        assert_eq!(decimal(row.get(1)), ticker.last_price);
+        assert_eq!(decimal(row.get(2)), ticker.price_24h_pcnt);
+        assert_eq!(row.get::<_, Option<OffsetDateTime>>(3), None);
+        assert_eq!(
+            row.get::<_, Option<&str>>(4).map(decimal),
+            ticker.basis_rate
+        );
+        assert_eq!(row.get::<_, Option<&str>>(5), None);
+        assert_eq!(
+            row.get::<_, Option<&str>>(6).map(decimal),
+            ticker.delivery_fee_rate
+        );
+        assert_eq!(row.get::<_, Vec<String>>(7), ticker.stale_fields);
+
+        for table in [
+            "trades_raw_ml",
+            "orderbook_raw_ml",
+            "orderbook_levels_raw_ml",
+            "ticker_raw_ml",
+        ] {
+            client
+                .execute(
+                    &format!("DELETE FROM {} WHERE symbol = $1", table),
+                    &[&symbol],
+                )
+                .await
+                .unwrap();
+        }
+    }
+}
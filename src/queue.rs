@@ -0,0 +1,817 @@
+//! Bounded channels between the pipeline stages, with a configurable overflow
+//! policy.
+//!
+//! `block` makes the sender wait for room like a plain channel. `drop_oldest`
+//! and `drop_newest` never wait and count what they discard. `spill` hands
+//! what does not fit to a thread that appends it to segment files
+//! `{spill_dir}/{channel}.{seq}.spill` and reads it back in order once the
+//! receiver catches up; everything sent while items are spilled goes there
+//! too, so order is kept. A segment is deleted once read. Senders wait only
+//! when the spilled items not received yet reach `spill_max_bytes`. The
+//! segments are a buffer, not a journal: they are deleted on startup.
+//!
+//! Depth, drops and spilled items of every channel are kept in a registry
+//! served by the health endpoint under `/channels`.
+
+use crate::config::{ChannelConfig, OverflowPolicy};
+use crate::parser::{BybitOTT, Frame};
+use crate::recorder::RawFrame;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, MutexGuard};
+use tokio::sync::Notify;
+use tokio::task::coop::consume_budget;
+use tracing::{error, warn};
+
+const SPILL_EXTENSION: &str = "spill";
+/// Spilled items the spill thread reads ahead of the receiver.
+const READ_AHEAD: usize = 1_024;
+
+/// Items that can be spilled to disk.
+pub trait Spill: Sized {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()>;
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+impl Spill for Frame {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let raw = RawFrame {
+            received_ns: self.received_ns,
+            connection_id: 0,
+            category: self.category,
+            message: self.message.clone(),
+        };
+        Ok(raw.write_to(buf)?)
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        Ok(RawFrame::read_from(&mut bytes)?
+            .context("Empty spilled frame")?
+            .into_frame())
+    }
+}
+
+impl Spill for BybitOTT {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        Ok(serde_json::to_writer(buf, self)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Counters of one channel, as served under `/channels`.
+#[derive(Debug, Serialize)]
+pub struct ChannelMetrics {
+    pub name: String,
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// Items waiting in memory and on disk.
+    pub depth: AtomicU64,
+    pub dropped: AtomicU64,
+    /// Spilled items not received yet.
+    pub spilled: AtomicU64,
+    pub spilled_total: AtomicU64,
+}
+
+static REGISTRY: LazyLock<Mutex<Vec<Arc<ChannelMetrics>>>> = LazyLock::new(Default::default);
+
+/// Metrics of every channel created so far, as a JSON array.
+pub fn metrics_json() -> String {
+    let registry = REGISTRY.lock().expect("channel registry poisoned");
+    let metrics: Vec<&ChannelMetrics> = registry.iter().map(Arc::as_ref).collect();
+    serde_json::to_string(&metrics).unwrap_or_default()
+}
+
+struct Segment {
+    seq: u64,
+    /// Items not read yet.
+    items: u64,
+}
+
+/// Length-prefixed items in segment files, appended to the newest and read
+/// from the oldest. Only the spill thread touches them.
+struct SpillFile {
+    dir: PathBuf,
+    name: String,
+    segment_bytes: u64,
+    segments: VecDeque<Segment>,
+    next_seq: u64,
+    /// Open handle on the newest segment and its size, `None` until the next
+    /// append.
+    tail: Option<(BufWriter<File>, u64)>,
+    /// Open handle on the oldest segment, `None` until the next read.
+    head: Option<BufReader<File>>,
+}
+
+impl SpillFile {
+    /// Deletes the segments left by an earlier run.
+    fn open(dir: &Path, name: &str, segment_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create spill dir {}", dir.display()))?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let stale = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(|f| f.strip_prefix(name)?.strip_prefix('.'))
+                .and_then(|f| f.strip_suffix(SPILL_EXTENSION)?.strip_suffix('.'))
+                .is_some_and(|seq| seq.parse::<u64>().is_ok());
+            if stale {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+            }
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            segment_bytes,
+            segments: VecDeque::new(),
+            next_seq: 0,
+            tail: None,
+            head: None,
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}.{:020}.{}", self.name, seq, SPILL_EXTENSION))
+    }
+
+    /// Appends encoded items and flushes them, so they can be read back.
+    fn append(&mut self, items: &[Vec<u8>]) -> Result<()> {
+        for item in items {
+            let tail_full = self
+                .tail
+                .as_ref()
+                .is_none_or(|(_, bytes)| *bytes >= self.segment_bytes);
+            if tail_full {
+                if let Some((tail, _)) = self.tail.as_mut() {
+                    tail.flush()?;
+                }
+                let seq = self.next_seq;
+                let path = self.path(seq);
+                let file = File::create(&path).with_context(|| {
+                    format!("Failed to create spill segment {}", path.display())
+                })?;
+                self.next_seq += 1;
+                self.tail = Some((BufWriter::new(file), 0));
+                self.segments.push_back(Segment { seq, items: 0 });
+            }
+
+            let (tail, bytes) = self.tail.as_mut().expect("tail segment is open");
+            tail.write_all(&(item.len() as u32).to_le_bytes())?;
+            tail.write_all(item)?;
+            *bytes += 4 + item.len() as u64;
+            self.segments.back_mut().expect("tail segment exists").items += 1;
+        }
+        if let Some((tail, _)) = self.tail.as_mut() {
+            tail.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `max` items, deleting every segment read completely
+    /// except the one still being appended to.
+    fn read(&mut self, max: usize) -> Result<Vec<Vec<u8>>> {
+        let mut items = Vec::new();
+        loop {
+            while self.segments.len() > 1 && self.segments[0].items == 0 {
+                let seq = self.segments.pop_front().expect("segment exists").seq;
+                self.head = None;
+                let path = self.path(seq);
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+            }
+            let Some(segment) = self.segments.front() else {
+                break;
+            };
+            if items.len() >= max || segment.items == 0 {
+                break;
+            }
+            if self.head.is_none() {
+                let path = self.path(segment.seq);
+                let file = File::open(&path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                self.head = Some(BufReader::new(file));
+            }
+            let head = self.head.as_mut().expect("head segment is open");
+            let mut len = [0u8; 4];
+            head.read_exact(&mut len)?;
+            let mut item = vec![0u8; u32::from_le_bytes(len) as usize];
+            head.read_exact(&mut item)?;
+            items.push(item);
+            self.segments[0].items -= 1;
+        }
+        Ok(items)
+    }
+
+    /// Deletes every segment, dropping whatever was not read.
+    fn clear(&mut self) -> Result<()> {
+        self.tail = None;
+        self.head = None;
+        while let Some(segment) = self.segments.pop_front() {
+            let path = self.path(segment.seq);
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Spilled items of a channel, in the order they are delivered: read back by
+/// the spill thread, in the files, being written, waiting to be written.
+struct Spilled {
+    from_disk: VecDeque<Vec<u8>>,
+    on_disk: u64,
+    writing: u64,
+    to_disk: VecDeque<Vec<u8>>,
+    /// Items and encoded bytes in all of the above.
+    queued: u64,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl Spilled {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            from_disk: VecDeque::new(),
+            on_disk: 0,
+            writing: 0,
+            to_disk: VecDeque::new(),
+            queued: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Items the spill thread should read ahead of the receiver.
+    fn wanted(&self) -> usize {
+        READ_AHEAD
+            .saturating_sub(self.from_disk.len())
+            .min(self.on_disk as usize)
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    spill: Option<Spilled>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    metrics: Arc<ChannelMetrics>,
+    state: Mutex<State<T>>,
+    /// Woken when an item arrives or the last sender is gone.
+    readable: Notify,
+    /// Woken when room frees up or the receiver is gone.
+    writable: Notify,
+    /// Wakes the spill thread when it has something to write or read, or
+    /// when either side is gone.
+    spill_work: Condvar,
+}
+
+impl<T: Spill> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().expect("channel lock poisoned")
+    }
+
+    fn update_depth(&self, state: &State<T>) {
+        let spilled = state.spill.as_ref().map_or(0, |s| s.queued);
+        let metrics = &self.metrics;
+        metrics
+            .depth
+            .store(state.items.len() as u64 + spilled, Ordering::Relaxed);
+        metrics.spilled.store(spilled, Ordering::Relaxed);
+    }
+
+    fn dropped(&self, items: u64) {
+        let dropped = self.metrics.dropped.fetch_add(items, Ordering::Relaxed) + items;
+        if items > 1 || dropped.is_power_of_two() {
+            warn!(
+                channel = %self.metrics.name,
+                dropped,
+                "Channel is full, dropping items"
+            );
+        }
+    }
+
+    /// Queues `item`, or hands it back when the sender has to wait.
+    fn offer(&self, state: &mut State<T>, item: T) -> Result<Option<T>> {
+        let capacity = self.metrics.capacity;
+        if let Some(spill) = state.spill.as_mut()
+            && (spill.queued > 0 || state.items.len() >= capacity)
+        {
+            if spill.bytes >= spill.max_bytes {
+                return Ok(Some(item));
+            }
+            let mut buf = Vec::new();
+            item.encode(&mut buf)?;
+            spill.queued += 1;
+            spill.bytes += buf.len() as u64;
+            spill.to_disk.push_back(buf);
+            self.metrics.spilled_total.fetch_add(1, Ordering::Relaxed);
+            self.spill_work.notify_one();
+            return Ok(None);
+        }
+        if state.items.len() < capacity {
+            state.items.push_back(item);
+            return Ok(None);
+        }
+        match self.metrics.overflow {
+            OverflowPolicy::Block | OverflowPolicy::Spill => Ok(Some(item)),
+            OverflowPolicy::DropNewest => {
+                self.dropped(1);
+                Ok(None)
+            }
+            OverflowPolicy::DropOldest => {
+                state.items.pop_front();
+                state.items.push_back(item);
+                self.dropped(1);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Next spilled item, still encoded. Taken straight from the write queue
+    /// when nothing older is on its way through the files.
+    fn take_spilled(&self, state: &mut State<T>) -> Option<Vec<u8>> {
+        let spill = state.spill.as_mut()?;
+        let item = match spill.from_disk.pop_front() {
+            Some(item) => item,
+            None if spill.on_disk == 0 && spill.writing == 0 => spill.to_disk.pop_front()?,
+            None => return None,
+        };
+        spill.queued -= 1;
+        spill.bytes -= item.len() as u64;
+        self.spill_work.notify_one();
+        Some(item)
+    }
+}
+
+/// Writes and reads the spill files of one channel, so their I/O never runs
+/// on the runtime. Stops with the receiver, or once every sender is gone and
+/// nothing is left in the files.
+fn run_spill<T: Spill>(shared: Arc<Shared<T>>, mut file: SpillFile) {
+    loop {
+        let (writes, wanted) = {
+            let mut state = shared.lock();
+            loop {
+                let spill = state.spill.as_ref().expect("channel spills");
+                if !state.receiver_alive || (state.senders == 0 && spill.on_disk == 0) {
+                    drop(state);
+                    if let Err(e) = file.clear() {
+                        warn!(channel = %shared.metrics.name, "Failed to delete spill files: {:?}", e);
+                    }
+                    return;
+                }
+                if !spill.to_disk.is_empty() || spill.wanted() > 0 {
+                    break;
+                }
+                state = shared
+                    .spill_work
+                    .wait(state)
+                    .expect("channel lock poisoned");
+            }
+            let spill = state.spill.as_mut().expect("channel spills");
+            let writes: Vec<Vec<u8>> = spill.to_disk.drain(..).collect();
+            spill.writing = writes.len() as u64;
+            (writes, spill.wanted())
+        };
+
+        let result = file.append(&writes).and_then(|()| file.read(wanted));
+
+        let mut state = shared.lock();
+        let spill = state.spill.as_mut().expect("channel spills");
+        spill.writing = 0;
+        let lost = match result {
+            Ok(items) => {
+                spill.on_disk += writes.len() as u64;
+                spill.on_disk -= items.len() as u64;
+                spill.from_disk.extend(items);
+                0
+            }
+            Err(e) => {
+                let queued = spill.queued;
+                spill.on_disk = 0;
+                spill.queued = (spill.from_disk.len() + spill.to_disk.len()) as u64;
+                spill.bytes = spill
+                    .from_disk
+                    .iter()
+                    .chain(&spill.to_disk)
+                    .map(|item| item.len() as u64)
+                    .sum();
+                let lost = queued - spill.queued;
+                error!(
+                    channel = %shared.metrics.name,
+                    lost,
+                    "Failed to use spill files, dropping their items: {:?}",
+                    e
+                );
+                if let Err(e) = file.clear() {
+                    warn!(channel = %shared.metrics.name, "Failed to delete spill files: {:?}", e);
+                }
+                lost
+            }
+        };
+        shared.update_depth(&state);
+        drop(state);
+        if lost > 0 {
+            shared.dropped(lost);
+            shared.writable.notify_waiters();
+        }
+        shared.readable.notify_waiters();
+    }
+}
+
+pub struct Sender<T: Spill> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T: Spill> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates the channel `name`, registering its metrics.
+pub fn channel<T: Spill + Send + 'static>(
+    name: &str,
+    config: &ChannelConfig,
+) -> Result<(Sender<T>, Receiver<T>)> {
+    let file = match (config.overflow, &config.spill_dir) {
+        (OverflowPolicy::Spill, Some(dir)) => {
+            Some(SpillFile::open(dir, name, config.spill_segment_bytes)?)
+        }
+        (OverflowPolicy::Spill, None) => anyhow::bail!("Channel {} spills without spill_dir", name),
+        _ => None,
+    };
+    let metrics = Arc::new(ChannelMetrics {
+        name: name.to_string(),
+        capacity: config.capacity,
+        overflow: config.overflow,
+        depth: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        spilled: AtomicU64::new(0),
+        spilled_total: AtomicU64::new(0),
+    });
+    REGISTRY
+        .lock()
+        .expect("channel registry poisoned")
+        .push(metrics.clone());
+    let shared = Arc::new(Shared {
+        metrics,
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            spill: file.is_some().then(|| Spilled::new(config.spill_max_bytes)),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        spill_work: Condvar::new(),
+    });
+    if let Some(file) = file {
+        let shared = shared.clone();
+        std::thread::Builder::new()
+            .name(format!("{}-spill", name))
+            .spawn(move || run_spill(shared, file))
+            .with_context(|| format!("Failed to start the {} spill thread", name))?;
+    }
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    ))
+}
+
+impl<T: Spill> Sender<T> {
+    /// Queues `item` according to the overflow policy, waiting for room only
+    /// with `block` or a full spill file. Fails once the receiver is gone.
+    pub async fn send(&self, item: T) -> Result<()> {
+        // Like tokio's channels, yield now and then so a busy loop cannot
+        // starve the other tasks.
+        consume_budget().await;
+        let shared = &self.shared;
+        let mut item = item;
+        loop {
+            let writable = shared.writable.notified();
+            tokio::pin!(writable);
+            {
+                let mut state = shared.lock();
+                anyhow::ensure!(
+                    state.receiver_alive,
+                    "Channel {} is closed",
+                    shared.metrics.name
+                );
+                match shared.offer(&mut state, item)? {
+                    None => {
+                        shared.update_depth(&state);
+                        drop(state);
+                        shared.readable.notify_waiters();
+                        return Ok(());
+                    }
+                    Some(back) => item = back,
+                }
+                // Registered while the lock is held, so no wakeup is missed.
+                writable.as_mut().enable();
+            }
+            writable.await;
+        }
+    }
+}
+
+impl<T: Spill> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Spill> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.spill_work.notify_one();
+            drop(state);
+            self.shared.readable.notify_waiters();
+        }
+    }
+}
+
+impl<T: Spill> Receiver<T> {
+    /// Next item, `None` once every sender is gone and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        consume_budget().await;
+        let shared = &self.shared;
+        loop {
+            let readable = shared.readable.notified();
+            tokio::pin!(readable);
+            {
+                let mut state = shared.lock();
+                if let Some(item) = state.items.pop_front() {
+                    shared.update_depth(&state);
+                    drop(state);
+                    shared.writable.notify_waiters();
+                    return Some(item);
+                }
+                if let Some(bytes) = shared.take_spilled(&mut state) {
+                    shared.update_depth(&state);
+                    drop(state);
+                    shared.writable.notify_waiters();
+                    match T::decode(&bytes) {
+                        Ok(item) => return Some(item),
+                        Err(e) => {
+                            error!(
+                                channel = %shared.metrics.name,
+                                "Dropping unreadable spilled item: {:?}",
+                                e
+                            );
+                            shared.dropped(1);
+                            continue;
+                        }
+                    }
+                }
+                let spilled = state.spill.as_ref().map_or(0, |s| s.queued);
+                if state.senders == 0 && spilled == 0 {
+                    return None;
+                }
+                readable.as_mut().enable();
+            }
+            readable.await;
+        }
+    }
+
+    /// Items waiting in memory and on disk.
+    pub fn depth(&self) -> usize {
+        self.shared.metrics.depth.load(Ordering::Relaxed) as usize
+    }
+}
+
+impl<T: Spill> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_alive = false;
+        self.shared.spill_work.notify_one();
+        self.shared.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[derive(Debug, PartialEq)]
+    struct Item(u64);
+
+    impl Spill for Item {
+        fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+            buf.extend_from_slice(&self.0.to_le_bytes());
+            Ok(())
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self> {
+            Ok(Item(u64::from_le_bytes(bytes.try_into()?)))
+        }
+    }
+
+    fn config(capacity: usize, overflow: OverflowPolicy) -> ChannelConfig {
+        ChannelConfig {
+            capacity,
+            overflow,
+            ..ChannelConfig::default()
+        }
+    }
+
+    /// Spills into a fresh directory, with segments of a few items.
+    fn spill_config(name: &str, capacity: usize, max_bytes: u64) -> (ChannelConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("queue-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = ChannelConfig {
+            capacity,
+            overflow: OverflowPolicy::Spill,
+            spill_dir: Some(dir.clone()),
+            spill_max_bytes: max_bytes,
+            spill_segment_bytes: 48,
+        };
+        (config, dir)
+    }
+
+    fn files(dir: &Path) -> usize {
+        fs::read_dir(dir).map_or(0, |entries| entries.count())
+    }
+
+    async fn recv_all(rx: &mut Receiver<Item>) -> Vec<u64> {
+        let mut items = Vec::new();
+        while let Some(Item(n)) = rx.recv().await {
+            items.push(n);
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (tx, mut rx) = channel("test-block", &config(2, OverflowPolicy::Block)).unwrap();
+        tx.send(Item(1)).await.unwrap();
+        tx.send(Item(2)).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(50), tx.send(Item(3)))
+                .await
+                .is_err()
+        );
+
+        let sender = tokio::spawn(async move {
+            tx.send(Item(3)).await.unwrap();
+            tx
+        });
+        assert_eq!(rx.recv().await, Some(Item(1)));
+        drop(sender.await.unwrap());
+        assert_eq!(recv_all(&mut rx).await, [2, 3]);
+        assert_eq!(rx.shared.metrics.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_discards_the_front() {
+        let (tx, mut rx) =
+            channel("test-drop-oldest", &config(2, OverflowPolicy::DropOldest)).unwrap();
+        for n in 1..=3 {
+            tx.send(Item(n)).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(recv_all(&mut rx).await, [2, 3]);
+        assert_eq!(rx.shared.metrics.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_discards_the_new_item() {
+        let (tx, mut rx) =
+            channel("test-drop-newest", &config(2, OverflowPolicy::DropNewest)).unwrap();
+        for n in 1..=3 {
+            tx.send(Item(n)).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(recv_all(&mut rx).await, [1, 2]);
+        assert_eq!(rx.shared.metrics.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_sender_wakes_the_receiver() {
+        let (tx, mut rx) =
+            channel::<Item>("test-close", &config(2, OverflowPolicy::Block)).unwrap();
+        let other = tx.clone();
+        tx.send(Item(1)).await.unwrap();
+        let receiver = tokio::spawn(async move { recv_all(&mut rx).await });
+        tokio::task::yield_now().await;
+        drop(tx);
+        tokio::task::yield_now().await;
+        assert!(!receiver.is_finished());
+        drop(other);
+        let items = timeout(Duration::from_secs(5), receiver)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(items, [1]);
+    }
+
+    #[tokio::test]
+    async fn sends_fail_once_the_receiver_is_gone() {
+        let (tx, rx) = channel::<Item>("test-closed", &config(2, OverflowPolicy::Block)).unwrap();
+        drop(rx);
+        assert!(tx.send(Item(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn spill_keeps_order_across_memory_and_disk() {
+        let (config, dir) = spill_config("order", 4, 1 << 20);
+        let (tx, mut rx) = channel("test-spill-order", &config).unwrap();
+        for n in 0..100 {
+            tx.send(Item(n)).await.unwrap();
+        }
+        for n in 0..50 {
+            assert_eq!(rx.recv().await, Some(Item(n)));
+        }
+        for n in 100..150 {
+            tx.send(Item(n)).await.unwrap();
+        }
+        for n in 50..150 {
+            assert_eq!(rx.recv().await, Some(Item(n)));
+        }
+        let spilled = rx.shared.metrics.spilled_total.load(Ordering::Relaxed);
+        assert!(spilled > 0);
+        assert_eq!(rx.depth(), 0);
+
+        // Memory is used again once the spilled items are received.
+        tx.send(Item(150)).await.unwrap();
+        drop(tx);
+        assert_eq!(recv_all(&mut rx).await, [150]);
+        assert_eq!(
+            rx.shared.metrics.spilled_total.load(Ordering::Relaxed),
+            spilled
+        );
+        drop(rx);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn spill_waits_until_received_items_free_room() {
+        // One item in memory, four spilled.
+        let (config, dir) = spill_config("full", 1, 32);
+        let (tx, mut rx) = channel("test-spill-full", &config).unwrap();
+        for n in 0..5 {
+            tx.send(Item(n)).await.unwrap();
+        }
+        let blocked = || timeout(Duration::from_millis(50), tx.send(Item(5)));
+        assert!(blocked().await.is_err());
+        assert_eq!(rx.recv().await, Some(Item(0)));
+        assert!(blocked().await.is_err());
+        assert_eq!(rx.recv().await, Some(Item(1)));
+        blocked().await.unwrap().unwrap();
+        drop(tx);
+        assert_eq!(recv_all(&mut rx).await, [2, 3, 4, 5]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn spill_segments_are_deleted_once_read() {
+        let dir = std::env::temp_dir().join(format!("queue-segments-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.00000000000000000007.spill"), b"stale").unwrap();
+        fs::write(dir.join("other.00000000000000000000.spill"), b"kept").unwrap();
+
+        // Segments of four 8-byte items.
+        let mut file = SpillFile::open(&dir, "test", 48).unwrap();
+        assert_eq!(files(&dir), 1);
+        let items: Vec<Vec<u8>> = (0u64..10).map(|n| n.to_le_bytes().to_vec()).collect();
+        file.append(&items).unwrap();
+        assert_eq!(files(&dir), 4);
+
+        assert_eq!(file.read(5).unwrap(), items[..5]);
+        assert_eq!(files(&dir), 3);
+        assert_eq!(file.read(100).unwrap(), items[5..]);
+        // The segment being appended to stays until a newer one is started.
+        assert_eq!(files(&dir), 2);
+        file.append(&items[..1]).unwrap();
+        assert_eq!(file.read(100).unwrap(), items[..1]);
+        assert_eq!(files(&dir), 2);
+        file.append(&items[..4]).unwrap();
+        assert_eq!(files(&dir), 3);
+        assert_eq!(file.read(100).unwrap(), items[..4]);
+        assert_eq!(files(&dir), 2);
+
+        file.clear().unwrap();
+        assert_eq!(files(&dir), 1);
+        assert!(file.read(100).unwrap().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,265 @@
+//! Archive of raw WebSocket text frames, exactly as the exchange sent them.
+//!
+//! A recording file is a single zstd stream of length-delimited frames:
+//!
+//! ```text
+//! received_ns    i64 LE   unix nanoseconds when the frame was read off the socket
+//! connection_id  u64 LE   WebSocket connection within the recording session
+//! category       u8       0 = linear, 1 = spot, 2 = option
+//! len            u32 LE
+//! payload        [u8; len] UTF-8 text frame
+//! ```
+//!
+//! Files are written to `.{name}.tmp` and renamed once rotated, after which a line
+//! describing the file is appended to `index.jsonl` in the same directory.
+
+use crate::config::{Category, RecorderConfig};
+use crate::parser::Frame;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tracing::{info, warn};
+
+pub const INDEX_FILE: &str = "index.jsonl";
+const FILE_EXTENSION: &str = "frames.zst";
+const CHANNEL_SIZE: usize = 100_000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub received_ns: i64,
+    pub connection_id: u64,
+    pub category: Category,
+    pub message: Utf8Bytes,
+}
+
+impl RawFrame {
+    pub fn new(connection_id: u64, frame: &Frame) -> Self {
+        Self {
+            received_ns: frame.received_ns,
+            connection_id,
+            category: frame.category,
+            message: frame.message.clone(),
+        }
+    }
+
+    pub fn into_frame(self) -> Frame {
+        Frame {
+            category: self.category,
+            received_ns: self.received_ns,
+            message: self.message,
+        }
+    }
+
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let code = Category::ALL
+            .iter()
+            .position(|c| *c == self.category)
+            .expect("category is listed in Category::ALL") as u8;
+        w.write_all(&self.received_ns.to_le_bytes())?;
+        w.write_all(&self.connection_id.to_le_bytes())?;
+        w.write_all(&[code])?;
+        w.write_all(&(self.message.len() as u32).to_le_bytes())?;
+        w.write_all(self.message.as_bytes())
+    }
+
+    /// Reads the next frame, `None` at a clean end of stream.
+    pub fn read_from(r: &mut impl Read) -> Result<Option<Self>> {
+        let mut received_ns = [0u8; 8];
+        match r.read_exact(&mut received_ns) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut connection_id = [0u8; 8];
+        let mut code = [0u8; 1];
+        let mut len = [0u8; 4];
+        r.read_exact(&mut connection_id)
+            .and_then(|_| r.read_exact(&mut code))
+            .and_then(|_| r.read_exact(&mut len))
+            .context("Truncated frame header")?;
+        let category = *Category::ALL
+            .get(code[0] as usize)
+            .with_context(|| format!("Unknown category code {}", code[0]))?;
+        let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+        r.read_exact(&mut payload)
+            .context("Truncated frame payload")?;
+        Ok(Some(Self {
+            received_ns: i64::from_le_bytes(received_ns),
+            connection_id: u64::from_le_bytes(connection_id),
+            category,
+            message: String::from_utf8(payload)
+                .context("Frame payload is not UTF-8")?
+                .into(),
+        }))
+    }
+}
+
+/// One line of `index.jsonl`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// File name relative to the recorder directory.
+    pub file: String,
+    /// Recorder start time in unix nanoseconds. Connection ids are unique within a session.
+    pub session: i64,
+    pub first_received_ns: i64,
+    pub last_received_ns: i64,
+    pub frames: u64,
+    pub payload_bytes: u64,
+}
+
+struct Recording {
+    encoder: zstd::stream::write::Encoder<'static, BufWriter<File>>,
+    tmp_path: PathBuf,
+    opened: Instant,
+    entry: IndexEntry,
+}
+
+struct Recorder {
+    config: RecorderConfig,
+    session: i64,
+    sequence: u64,
+    current: Option<Recording>,
+}
+
+impl Recorder {
+    fn new(config: RecorderConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create {}", config.dir.display()))?;
+        warn_unfinished(&config.dir)?;
+        Ok(Self {
+            config,
+            session: Frame::now_ns(),
+            sequence: 0,
+            current: None,
+        })
+    }
+
+    fn open(&mut self, first: &RawFrame) -> Result<Recording> {
+        self.sequence += 1;
+        let name = format!(
+            "{}-{}.{}",
+            first.received_ns / 1_000_000_000,
+            self.sequence,
+            FILE_EXTENSION
+        );
+        let tmp_path = self.config.dir.join(format!(".{}.tmp", name));
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        let encoder = zstd::stream::write::Encoder::new(BufWriter::new(file), self.config.level)?;
+        Ok(Recording {
+            encoder,
+            tmp_path,
+            opened: Instant::now(),
+            entry: IndexEntry {
+                file: name,
+                session: self.session,
+                first_received_ns: first.received_ns,
+                last_received_ns: first.received_ns,
+                frames: 0,
+                payload_bytes: 0,
+            },
+        })
+    }
+
+    fn write(&mut self, frame: &RawFrame) -> Result<()> {
+        let recording = match self.current.take() {
+            Some(r) => r,
+            None => self.open(frame)?,
+        };
+        let recording = self.current.insert(recording);
+        frame.write_to(&mut recording.encoder)?;
+        recording.entry.frames += 1;
+        recording.entry.payload_bytes += frame.message.len() as u64;
+        recording.entry.last_received_ns = frame.received_ns;
+
+        if recording.entry.payload_bytes >= self.config.max_file_bytes
+            || recording.opened.elapsed() >= Duration::from_secs(self.config.rotate_secs)
+        {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(recording) = self.current.as_mut() {
+            recording.encoder.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the zstd stream, moves the file into place and indexes it.
+    fn finish(&mut self) -> Result<()> {
+        let Some(recording) = self.current.take() else {
+            return Ok(());
+        };
+        let file = recording
+            .encoder
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        let final_path = self.config.dir.join(&recording.entry.file);
+        fs::rename(&recording.tmp_path, &final_path)
+            .with_context(|| format!("Failed to finalize {}", final_path.display()))?;
+
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.config.dir.join(INDEX_FILE))
+            .context("Failed to open recorder index")?;
+        writeln!(index, "{}", serde_json::to_string(&recording.entry)?)?;
+        info!(
+            file = %recording.entry.file,
+            frames = recording.entry.frames,
+            "Raw recording finalized"
+        );
+        Ok(())
+    }
+}
+
+/// A crash leaves the last file as `.tmp`. It stays readable up to the last
+/// flushed block but is not indexed, so point it out instead of guessing.
+fn warn_unfinished(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') && name.ends_with(".tmp") {
+            warn!(
+                "Found unfinished raw recording {}, it is not indexed.",
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn run_recorder(mut recorder: Recorder, mut rx: Receiver<RawFrame>) -> Result<()> {
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            frame = rx.recv() => match frame {
+                Some(frame) => recorder.write(&frame)?,
+                None => break,
+            },
+            _ = flush.tick() => recorder.flush()?,
+        }
+    }
+    recorder.finish()
+}
+
+/// Opens the recorder. Frames sent to the returned channel are archived by the
+/// returned future, with backpressure so the archive stays complete even when
+/// the disk is slow.
+pub fn open_recorder(
+    config: RecorderConfig,
+) -> Result<(Sender<RawFrame>, impl Future<Output = Result<()>>)> {
+    let recorder = Recorder::new(config)?;
+    let (tx, rx) = channel::<RawFrame>(CHANNEL_SIZE);
+    Ok((tx, run_recorder(recorder, rx)))
+}
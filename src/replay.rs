@@ -0,0 +1,99 @@
+use crate::config::ReplayConfig;
+use crate::parser::Frame;
+use crate::queue::Sender;
+use crate::recorder::{INDEX_FILE, IndexEntry, RawFrame};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+
+/// Frames read ahead of the parser channel.
+const REPLAY_QUEUE: usize = 1_024;
+
+/// Recordings to replay, oldest first. Uses `files` when given, otherwise every
+/// file in the recorder index.
+pub fn replay_files(config: &ReplayConfig) -> Result<Vec<PathBuf>> {
+    if !config.files.is_empty() {
+        return Ok(config.files.iter().map(|f| config.dir.join(f)).collect());
+    }
+    let index_path = config.dir.join(INDEX_FILE);
+    let index = File::open(&index_path)
+        .with_context(|| format!("Failed to open {}", index_path.display()))?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(index).lines() {
+        let entry: IndexEntry = serde_json::from_str(&line?).context("Invalid index entry")?;
+        entries.push(entry);
+    }
+    entries.sort_by_key(|e| e.first_received_ns);
+    Ok(entries
+        .into_iter()
+        .map(|e| config.dir.join(e.file))
+        .collect())
+}
+
+/// Paces frames by their recorded receive time. `speed` of 0 disables pacing.
+struct Pacer {
+    speed: f64,
+    start: Option<(Instant, i64)>,
+}
+
+impl Pacer {
+    fn wait(&mut self, received_ns: i64) {
+        if self.speed <= 0.0 {
+            return;
+        }
+        let (wall, first_ns) = *self.start.get_or_insert((Instant::now(), received_ns));
+        let offset = (received_ns - first_ns).max(0) as f64 / self.speed;
+        let due = wall + Duration::from_nanos(offset as u64);
+        if let Some(delay) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+fn replay_blocking(config: ReplayConfig, frame_tx: mpsc::Sender<Frame>) -> Result<()> {
+    let files = replay_files(&config)?;
+    info!(
+        "Replaying {} recordings at speed {}.",
+        files.len(),
+        config.speed
+    );
+    let mut pacer = Pacer {
+        speed: config.speed,
+        start: None,
+    };
+    for path in files {
+        let file =
+            File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut reader = zstd::stream::read::Decoder::new(file)?;
+        let mut frames = 0u64;
+        while let Some(raw) = RawFrame::read_from(&mut reader)
+            .with_context(|| format!("Failed to read frame {} of {}", frames, path.display()))?
+        {
+            pacer.wait(raw.received_ns);
+            frame_tx
+                .blocking_send(raw.into_frame())
+                .map_err(|_| anyhow::anyhow!("Parser channel closed during replay"))?;
+            frames += 1;
+        }
+        info!("Replayed {} frames from {}.", frames, path.display());
+    }
+    Ok(())
+}
+
+/// Feeds recorded frames to the parser in place of the live sockets. The
+/// recordings are read on a blocking task.
+pub async fn run_replay(config: ReplayConfig, parser_tx: Sender<Frame>) -> Result<()> {
+    let (frame_tx, mut frame_rx) = mpsc::channel(REPLAY_QUEUE);
+    let reader = tokio::task::spawn_blocking(move || replay_blocking(config, frame_tx));
+    while let Some(frame) = frame_rx.recv().await {
+        parser_tx
+            .send(frame)
+            .await
+            .context("Parser channel closed during replay")?;
+    }
+    reader.await.context("Replay task panicked")?
+}
@@ -0,0 +1,169 @@
+//! Per-table TTL and storage policy from `[retention]`, applied on startup.
+//!
+//! The TTL last applied to each table is kept in `retention_policies`, so a
+//! table is only altered when its policy changes. Changing a TTL materializes it
+//! on existing parts in a background mutation.
+
+use crate::config::RetentionPolicy;
+use crate::migrations::{on_cluster, storage_table};
+use anyhow::{Context, Result};
+use clickhouse::{Client, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use time::OffsetDateTime;
+use tracing::info;
+
+/// Tables that take a retention policy and the column their age is counted from.
+const TABLES: &[(&str, &str)] = &[
+    ("trades_raw_ml", "trade_timestamp"),
+    ("liquidations_raw_ml", "liquidation_timestamp"),
+    ("kline_raw_ml", "start_timestamp"),
+    ("orderbook_raw_ml", "server_timestamp"),
+    ("orderbook_levels_raw_ml", "server_timestamp"),
+    ("ticker_raw_ml", "server_timestamp"),
+    ("ticker_sparse_raw_ml", "server_timestamp"),
+    ("ticker_spot_raw_ml", "server_timestamp"),
+    ("ticker_option_raw_ml", "server_timestamp"),
+    ("open_interest_history", "timestamp"),
+    ("long_short_ratio", "timestamp"),
+    ("historical_volatility", "timestamp"),
+    ("funding_events", "funding_time"),
+];
+
+#[derive(Row, Serialize, Deserialize)]
+struct AppliedRetention {
+    table_name: String,
+    ttl: String,
+    #[serde(with = "clickhouse::serde::time::datetime64::millis")]
+    applied_at: OffsetDateTime,
+}
+
+/// The TTL clause for `policy`, empty when data is kept forever.
+fn ttl_expression(column: &str, policy: &RetentionPolicy) -> Result<String> {
+    let age = format!("toDateTime({})", column);
+    let mut rules = Vec::new();
+    match (policy.move_after_days, &policy.move_to_volume) {
+        (Some(days), Some(volume)) => rules.push(format!(
+            "{} + INTERVAL {} DAY TO VOLUME '{}'",
+            age, days, volume
+        )),
+        (None, None) => {}
+        _ => anyhow::bail!("move_after_days and move_to_volume must be set together"),
+    }
+    if let Some(days) = policy.recompress_after_days {
+        rules.push(format!(
+            "{} + INTERVAL {} DAY RECOMPRESS CODEC({})",
+            age, days, policy.recompress_codec
+        ));
+    }
+    if let Some(days) = policy.delete_after_days {
+        rules.push(format!("{} + INTERVAL {} DAY DELETE", age, days));
+    }
+    Ok(rules.join(", "))
+}
+
+async fn applied_ttls(client: &Client) -> Result<HashMap<String, String>> {
+    let exists = client
+        .query("EXISTS TABLE retention_policies")
+        .fetch_one::<u8>()
+        .await?;
+    if exists == 0 {
+        return Ok(HashMap::new());
+    }
+    let applied = client
+        .query("SELECT ?fields FROM retention_policies FINAL")
+        .fetch_all::<AppliedRetention>()
+        .await?;
+    Ok(applied.into_iter().map(|a| (a.table_name, a.ttl)).collect())
+}
+
+async fn current_storage_policy(client: &Client, table: &str) -> Result<String> {
+    Ok(client
+        .query("SELECT storage_policy FROM system.tables WHERE database = currentDatabase() AND name = ?")
+        .bind(table)
+        .fetch_optional::<String>()
+        .await?
+        .unwrap_or_default())
+}
+
+/// Brings TTL and storage policy of every table in line with `retention` and
+/// logs the active policies. With `dry_run` the changes are only logged.
+pub async fn apply_retention(
+    client: &Client,
+    retention: &BTreeMap<String, RetentionPolicy>,
+    cluster: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    for table in retention.keys() {
+        anyhow::ensure!(
+            TABLES.iter().any(|(t, _)| t == table),
+            "Unknown table {} in [retention]",
+            table
+        );
+    }
+    let applied = applied_ttls(client)
+        .await
+        .context("Failed to read retention_policies")?;
+
+    for &(table, column) in TABLES {
+        let default = RetentionPolicy::default();
+        let policy = retention.get(table).unwrap_or(&default);
+        let ttl = ttl_expression(column, policy)
+            .with_context(|| format!("Invalid retention for {}", table))?;
+        let target = storage_table(table, cluster);
+        let alter = format!("ALTER TABLE {}{}", target, on_cluster(cluster));
+
+        let mut statements = Vec::new();
+        if let Some(storage_policy) = &policy.storage_policy
+            && current_storage_policy(client, &target).await? != *storage_policy
+        {
+            statements.push(format!(
+                "{} MODIFY SETTING storage_policy = '{}'",
+                alter, storage_policy
+            ));
+        }
+        let current_ttl = applied.get(table).map(String::as_str).unwrap_or("");
+        if ttl != current_ttl {
+            statements.push(match ttl.is_empty() {
+                true => format!("{} REMOVE TTL", alter),
+                false => format!("{} MODIFY TTL {}", alter, ttl),
+            });
+        }
+
+        match ttl.is_empty() {
+            true => info!(table, "Retention: keep forever."),
+            false => info!(table, "Retention: {}", ttl),
+        }
+        if statements.is_empty() {
+            continue;
+        }
+        if dry_run {
+            for statement in &statements {
+                info!(table, "Would apply: {}", statement);
+            }
+            continue;
+        }
+        for statement in &statements {
+            client
+                .query(statement)
+                .execute()
+                .await
+                .with_context(|| format!("Failed to apply retention to {}", table))?;
+        }
+        // A plain insert, so the next start sees the policy as applied.
+        let mut insert = client
+            .insert::<AppliedRetention>("retention_policies")
+            .await?
+            .with_option("async_insert", "0");
+        insert
+            .write(&AppliedRetention {
+                table_name: table.to_string(),
+                ttl,
+                applied_at: OffsetDateTime::now_utc(),
+            })
+            .await?;
+        insert.end().await?;
+        info!(table, "Retention applied.");
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! Materialized rollups over the raw tables.
+//!
+//! Every configured rollup and interval gets an `AggregatingMergeTree` table
+//! named `{rollup}_{interval}`, keyed by `(symbol, bucket)`, and a view
+//! `{rollup}_{interval}_mv` that fills it on insert. Columns hold aggregate
+//! states, so read them with the `-Merge` combinator:
+//!
+//! ```sql
+//! SELECT symbol, bucket, countMerge(trades), argMaxMerge(close_price)
+//! FROM trade_stats_1m GROUP BY symbol, bucket ORDER BY bucket
+//! ```
+//!
+//! Aliases must not reuse source column names, ClickHouse would substitute
+//! them into the other aggregates. Removing a rollup from the config leaves
+//! its table and view in place.
+
+use crate::config::{Config, RollupsConfig};
+use crate::migrations::{TableDef, on_cluster, storage_table};
+use anyhow::{Context, Result};
+use clickhouse::{Client, Row};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+struct Rollup {
+    name: &'static str,
+    source: &'static str,
+    /// Event time the buckets are cut from.
+    time: &'static str,
+    aggregates: &'static str,
+}
+
+const ROLLUPS: &[Rollup] = &[
+    Rollup {
+        name: "trade_stats",
+        source: "trades_raw_ml",
+        time: "trade_timestamp",
+        aggregates: r#"
+            countState() AS trades,
+            sumState(volume) AS total_volume,
+            sumState(if(side = 'Buy', volume, 0)) AS buy_volume,
+            sumState(multiplyDecimal(price, volume, 18)) AS turnover,
+            argMinState(price, (trade_timestamp, seq)) AS open_price,
+            maxState(price) AS high_price,
+            minState(price) AS low_price,
+            argMaxState(price, (trade_timestamp, seq)) AS close_price"#,
+    },
+    // Every update writes the whole book, so the best level of the last update
+    // in a bucket is the row with the highest (update, side, price) key.
+    Rollup {
+        name: "top_of_book",
+        source: "orderbook_raw_ml",
+        time: "server_timestamp",
+        aggregates: r#"
+            argMaxState(price, (update, side = 'Bid', price)) AS bid_price,
+            argMaxState(volume, (update, side = 'Bid', price)) AS bid_size,
+            argMaxState(price, (update, side = 'Ask', negate(price))) AS ask_price,
+            argMaxState(volume, (update, side = 'Ask', negate(price))) AS ask_size,
+            uniqState(update) AS updates"#,
+    },
+    Rollup {
+        name: "funding",
+        source: "ticker_raw_ml",
+        time: "server_timestamp",
+        aggregates: r#"
+            argMaxState(funding_rate, server_timestamp) AS last_funding_rate,
+            minState(funding_rate) AS min_funding_rate,
+            maxState(funding_rate) AS max_funding_rate,
+            argMaxState(next_funding_time, server_timestamp) AS funding_time,
+            argMaxState(mark_price, server_timestamp) AS last_mark_price,
+            argMaxState(open_interest, server_timestamp) AS last_open_interest"#,
+    },
+];
+
+/// One row of `DESCRIBE`. Only name and type are used, the rest is there
+/// because rows have to match the result columns.
+#[allow(dead_code)]
+#[derive(Row, Deserialize)]
+struct DescribedColumn {
+    name: String,
+    #[serde(rename = "type")]
+    column_type: String,
+    default_type: String,
+    default_expression: String,
+    comment: String,
+    codec_expression: String,
+    ttl_expression: String,
+}
+
+fn intervals<'a>(config: &'a RollupsConfig, rollup: &Rollup) -> &'a [String] {
+    match rollup.name {
+        "trade_stats" => &config.trade_stats,
+        "top_of_book" => &config.top_of_book,
+        "funding" => &config.funding,
+        name => unreachable!("rollup {} has no config", name),
+    }
+}
+
+/// "5m" as `INTERVAL 5 MINUTE`.
+fn interval_sql(interval: &str) -> Result<String> {
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let (count, unit) = interval.split_at(split);
+    let count: u32 = count
+        .parse()
+        .with_context(|| format!("Invalid rollup interval {}", interval))?;
+    let unit = match unit {
+        "s" => "SECOND",
+        "m" => "MINUTE",
+        "h" => "HOUR",
+        "d" => "DAY",
+        _ => anyhow::bail!("Invalid rollup interval {}, use s, m, h or d", interval),
+    };
+    anyhow::ensure!(count > 0, "Invalid rollup interval {}", interval);
+    Ok(format!("INTERVAL {} {}", count, unit))
+}
+
+fn select(rollup: &Rollup, interval: &str, source: &str, filter: &str) -> String {
+    format!(
+        "SELECT symbol, toStartOfInterval({}, {}) AS bucket,{} FROM {} {} GROUP BY symbol, bucket",
+        rollup.time, interval, rollup.aggregates, source, filter
+    )
+}
+
+/// Creates the target table and view of every configured rollup. Column types
+/// are taken from the view's query so the two always match.
+pub async fn create_rollups(
+    client: &Client,
+    config: &RollupsConfig,
+    cluster: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    for rollup in ROLLUPS {
+        for interval in intervals(config, rollup) {
+            let interval_sql = interval_sql(interval)?;
+            let target = format!("{}_{}", rollup.name, interval);
+            let view_select = select(
+                rollup,
+                &interval_sql,
+                &storage_table(rollup.source, cluster),
+                "",
+            );
+            if dry_run {
+                info!("Would create rollup {}: {}", target, view_select);
+                continue;
+            }
+
+            let columns = client
+                .query(&format!(
+                    "DESCRIBE TABLE ({})",
+                    select(rollup, &interval_sql, rollup.source, "")
+                ))
+                .fetch_all::<DescribedColumn>()
+                .await
+                .with_context(|| format!("Failed to describe rollup {}", target))?
+                .into_iter()
+                .map(|c| format!("{} {}", c.name, c.column_type))
+                .collect();
+            let table = TableDef {
+                name: target.clone(),
+                columns,
+                engine: "AggregatingMergeTree".to_string(),
+                engine_args: String::new(),
+                clauses: "PARTITION BY toYYYYMM(bucket)\nORDER BY (symbol, bucket)".to_string(),
+            };
+            for statement in table.render(cluster)? {
+                client
+                    .query(&statement)
+                    .execute()
+                    .await
+                    .with_context(|| format!("Failed to create rollup {}", target))?;
+            }
+            client
+                .query(&format!(
+                    "CREATE MATERIALIZED VIEW IF NOT EXISTS {}_mv{} TO {} AS {}",
+                    target,
+                    on_cluster(cluster),
+                    storage_table(&target, cluster),
+                    view_select
+                ))
+                .execute()
+                .await
+                .with_context(|| format!("Failed to create rollup view {}_mv", target))?;
+            info!("Rollup {} is active.", target);
+        }
+    }
+    Ok(())
+}
+
+/// Bucket bounds of a backfill, as unix seconds.
+#[derive(Row, Deserialize)]
+struct Bounds {
+    /// Oldest raw row, 0 when the raw table is empty.
+    first: u32,
+    first_bucket: u32,
+    second_bucket: u32,
+    created_bucket: u32,
+    created_bucket_end: u32,
+    now: u32,
+}
+
+/// Rebuilds a rollup from the raw table, from the oldest raw row up to and
+/// including the bucket its view was created in. That bucket is only rebuilt
+/// once it has closed, until then the view keeps adding to it. When the raw
+/// table has a delete TTL, its oldest bucket may have lost rows already and is
+/// left alone, as is everything before it. Buckets in the range are deleted
+/// first, so running it again gives the same result.
+pub async fn backfill(client: &Client, config: &Config, target: &str) -> Result<()> {
+    let cluster = config.clickhouse.cluster.as_deref();
+    let (rollup, interval) = ROLLUPS
+        .iter()
+        .flat_map(|r| intervals(&config.rollups, r).iter().map(move |i| (r, i)))
+        .find(|(r, i)| format!("{}_{}", r.name, i) == target)
+        .with_context(|| format!("{} is not a configured rollup", target))?;
+    let interval_sql = interval_sql(interval)?;
+
+    let created = client
+        .query(
+            "SELECT toUnixTimestamp(metadata_modification_time) FROM system.tables \
+             WHERE database = currentDatabase() AND name = ?",
+        )
+        .bind(format!("{}_mv", target))
+        .fetch_optional::<u32>()
+        .await?
+        .with_context(|| format!("{}_mv does not exist yet, start the fetcher once", target))?;
+    let bounds = client
+        .query(&format!(
+            "SELECT
+                toUnixTimestamp(min({time})) AS first,
+                toUnixTimestamp(toStartOfInterval(min({time}), {i})) AS first_bucket,
+                toUnixTimestamp(toStartOfInterval(min({time}), {i}) + {i}) AS second_bucket,
+                toUnixTimestamp(toStartOfInterval(toDateTime({created}, 'UTC'), {i})) AS created_bucket,
+                toUnixTimestamp(toStartOfInterval(toDateTime({created}, 'UTC'), {i}) + {i}) AS created_bucket_end,
+                toUnixTimestamp(now()) AS now
+            FROM {source}",
+            time = rollup.time,
+            i = interval_sql,
+            created = created,
+            source = rollup.source,
+        ))
+        .fetch_one::<Bounds>()
+        .await
+        .with_context(|| format!("Failed to find the raw rows of {}", target))?;
+    if bounds.first == 0 {
+        info!("{} is empty, nothing to backfill.", rollup.source);
+        return Ok(());
+    }
+
+    let deletes_raw = config
+        .retention
+        .get(rollup.source)
+        .is_some_and(|p| p.delete_after_days.is_some());
+    let start = match deletes_raw && bounds.first > bounds.first_bucket {
+        true => bounds.second_bucket,
+        false => bounds.first_bucket,
+    };
+    let end = match bounds.now >= bounds.created_bucket_end {
+        true => bounds.created_bucket_end,
+        false => {
+            warn!(
+                "The bucket {}_mv was created in is still open, run the backfill again after {} to complete it.",
+                target,
+                OffsetDateTime::from_unix_timestamp(bounds.created_bucket_end.into())?
+            );
+            bounds.created_bucket
+        }
+    };
+    if start >= end {
+        info!("Nothing to backfill in {}.", target);
+        return Ok(());
+    }
+    let range = |column: &str| {
+        format!("{column} >= toDateTime({start}, 'UTC') AND {column} < toDateTime({end}, 'UTC')")
+    };
+
+    info!("Backfilling {} where {}.", target, range("bucket"));
+    client
+        .query(&format!(
+            "ALTER TABLE {}{} DELETE WHERE {}",
+            storage_table(target, cluster),
+            on_cluster(cluster),
+            range("bucket")
+        ))
+        .with_option("mutations_sync", "2")
+        .execute()
+        .await
+        .with_context(|| format!("Failed to clear {}", target))?;
+    client
+        .query(&format!(
+            "INSERT INTO {} {}",
+            target,
+            select(
+                rollup,
+                &interval_sql,
+                rollup.source,
+                &format!("WHERE {}", range(rollup.time))
+            )
+        ))
+        .execute()
+        .await
+        .with_context(|| format!("Failed to backfill {}", target))?;
+    info!("Backfilled {}.", target);
+    Ok(())
+}
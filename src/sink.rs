@@ -0,0 +1,187 @@
+use crate::clickhouse_sink::ClickhouseSink;
+use crate::config::{SinkConfig, SinkKind};
+use crate::duckdb_sink::DuckdbSink;
+use crate::parquet_sink::ParquetSink;
+use crate::parser::BybitOTT;
+use crate::postgres_sink::PostgresSink;
+use crate::spool::{SinkFactory, SpoolingSink};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use clickhouse::Client;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+/// Records queued between a `ThreadSink` and its thread.
+const THREAD_QUEUE: usize = 64;
+
+/// Destination for parsed records. Each sink runs in its own writer task.
+#[async_trait]
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    /// Buffers the record. Sinks may commit on their own size or time policy.
+    /// The record is shared with the other sinks; take it with
+    /// `Arc::unwrap_or_clone` to own its rows.
+    async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()>;
+
+    /// Forces buffered records out.
+    async fn flush(&mut self) -> Result<()>;
+
+    /// Called every few seconds for time-based work such as retries.
+    async fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Flushes and releases the sink. Called once when the pipeline shuts down.
+    async fn close(self: Box<Self>) -> Result<()>;
+}
+
+/// A sink doing blocking file or database I/O. Runs on its own thread behind
+/// a `ThreadSink`, so it never stalls the runtime.
+pub trait BlockingSink: Send + 'static {
+    fn write(&mut self, record: &BybitOTT) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+}
+
+enum Command {
+    Write(Arc<BybitOTT>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+fn run_thread(mut sink: impl BlockingSink, mut rx: mpsc::Receiver<Command>) -> Result<()> {
+    while let Some(command) = rx.blocking_recv() {
+        match command {
+            Command::Write(record) => sink.write(&record)?,
+            Command::Flush(done) => {
+                if let Err(e) = sink.flush() {
+                    let _ = done.send(Err(anyhow::anyhow!("{:#}", e)));
+                    return Err(e);
+                }
+                let _ = done.send(Ok(()));
+            }
+        }
+    }
+    sink.flush()
+}
+
+/// Forwards records to a `BlockingSink` running on a dedicated thread.
+pub struct ThreadSink {
+    name: &'static str,
+    tx: Option<mpsc::Sender<Command>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl ThreadSink {
+    pub fn spawn(name: &'static str, sink: impl BlockingSink) -> Result<Self> {
+        let (tx, rx) = mpsc::channel(THREAD_QUEUE);
+        let thread = std::thread::Builder::new()
+            .name(format!("{}-sink", name))
+            .spawn(move || run_thread(sink, rx))
+            .with_context(|| format!("Failed to start the {} sink thread", name))?;
+        Ok(Self {
+            name,
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Waits for the thread and returns how it ended.
+    async fn join(&mut self) -> Result<()> {
+        self.tx = None;
+        let Some(thread) = self.thread.take() else {
+            anyhow::bail!("{} sink failed earlier", self.name);
+        };
+        let name = self.name;
+        tokio::task::spawn_blocking(move || thread.join())
+            .await?
+            .map_err(|_| anyhow::anyhow!("{} sink panicked", name))?
+            .with_context(|| format!("{} sink failed", name))
+    }
+
+    /// The error the thread stopped with.
+    async fn failure(&mut self) -> anyhow::Error {
+        match self.join().await {
+            Ok(()) => anyhow::anyhow!("{} sink stopped", self.name),
+            Err(e) => e,
+        }
+    }
+
+    async fn send(&mut self, command: Command) -> Result<()> {
+        let sent = match &self.tx {
+            Some(tx) => tx.send(command).await.is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(self.failure().await);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for ThreadSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()> {
+        self.send(Command::Write(record)).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.send(Command::Flush(done_tx)).await?;
+        match done_rx.await {
+            Ok(result) => result,
+            Err(_) => Err(self.failure().await),
+        }
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<()> {
+        self.join().await?;
+        info!("{} sink closed.", self.name);
+        Ok(())
+    }
+}
+
+fn build_sink(kind: &SinkKind, client: &Client) -> Result<Box<dyn Sink>> {
+    Ok(match kind {
+        SinkKind::Clickhouse { batch } => Box::new(ClickhouseSink::new(client, batch)),
+        SinkKind::Parquet {
+            dir,
+            rotation,
+            max_file_bytes,
+        } => Box::new(ThreadSink::spawn(
+            "parquet",
+            ParquetSink::open(dir.clone(), *rotation, *max_file_bytes)?,
+        )?),
+        SinkKind::Duckdb { path } => Box::new(ThreadSink::spawn("duckdb", DuckdbSink::new(path)?)?),
+        SinkKind::Postgres { url, hypertables } => {
+            Box::new(PostgresSink::new(url.clone(), *hypertables))
+        }
+    })
+}
+
+/// Builds the configured sinks with their queue sizes, primary first.
+pub fn build_sinks(configs: &[SinkConfig], client: &Client) -> Result<Vec<(Box<dyn Sink>, usize)>> {
+    let mut sinks: Vec<(Box<dyn Sink>, usize)> = Vec::new();
+    for config in configs {
+        let sink: Box<dyn Sink> = match &config.spool {
+            None => build_sink(&config.kind, client)?,
+            Some(spool) => {
+                let kind = config.kind.clone();
+                let client = client.clone();
+                let factory: SinkFactory = Box::new(move || build_sink(&kind, &client));
+                Box::new(SpoolingSink::new(spool, factory)?)
+            }
+        };
+        sinks.push((sink, config.queue));
+    }
+    if sinks.is_empty() {
+        anyhow::bail!("At least one sink must be configured");
+    }
+    Ok(sinks)
+}
@@ -0,0 +1,653 @@
+//! Durable on-disk queue in front of a sink.
+//!
+//! While the sink is healthy, records go straight to it and are kept in memory
+//! until the next checkpoint (`Sink::flush`) confirms them. When a write or a
+//! checkpoint fails, the unconfirmed records and everything after them are
+//! appended to JSON-lines segment files. Segments are drained oldest first once
+//! the sink is rebuilt, with exponential backoff between attempts, also while
+//! no new records arrive. The read position in the oldest segment is kept in a
+//! `head` file, so a restart resumes where draining stopped. Delivery is
+//! at-least-once: records committed just before a failure may be written twice.
+
+use crate::config::SpoolConfig;
+use crate::parser::BybitOTT;
+use crate::sink::Sink;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+/// Holds `{seq} {offset}`, the read position in the oldest segment.
+const HEAD_FILE: &str = "head";
+/// Records sent to the sink per drain step before a checkpoint.
+const DRAIN_BATCH: usize = 1_000;
+/// Drain steps per incoming record, so draining makes progress without
+/// stalling the writer channel for the whole backlog.
+const DRAIN_STEPS_PER_WRITE: usize = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Rebuilds the wrapped sink after a failure.
+pub type SinkFactory = Box<dyn Fn() -> Result<Box<dyn Sink>> + Send>;
+
+#[derive(Debug, Default)]
+struct SpoolMetrics {
+    spooled_records: u64,
+    drained_records: u64,
+    dropped_records: u64,
+    corrupt_records: u64,
+    failures: u64,
+}
+
+struct Segment {
+    seq: u64,
+    bytes: u64,
+}
+
+/// Copy of the spool's state taken on the blocking thread after each operation.
+#[derive(Debug, Clone, Copy)]
+struct SpoolStatus {
+    empty: bool,
+    bytes: u64,
+    segments: usize,
+}
+
+/// Append-only segment files with a read offset into the oldest one.
+struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    segments: VecDeque<Segment>,
+    total_bytes: u64,
+    head_offset: u64,
+    /// Open handle on the newest segment, `None` until the next append.
+    tail: Option<BufWriter<File>>,
+}
+
+impl Spool {
+    fn open(config: &SpoolConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create spool dir {}", config.dir.display()))?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            segments.push(Segment {
+                seq,
+                bytes: entry.metadata()?.len(),
+            });
+        }
+        segments.sort_by_key(|s| s.seq);
+        let total_bytes = segments.iter().map(|s| s.bytes).sum();
+        let mut spool = Self {
+            dir: config.dir.clone(),
+            max_bytes: config.max_bytes,
+            segment_bytes: config.segment_bytes,
+            segments: segments.into(),
+            total_bytes,
+            head_offset: 0,
+            tail: None,
+        };
+        spool.head_offset = spool.read_head()?;
+        Ok(spool)
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, SEGMENT_EXTENSION))
+    }
+
+    /// Saved read position, if it still points into the oldest segment.
+    fn read_head(&self) -> Result<u64> {
+        let path = self.dir.join(HEAD_FILE);
+        let raw = match fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let saved = raw.split_once(' ').and_then(|(seq, offset)| {
+            Some((seq.parse::<u64>().ok()?, offset.trim().parse::<u64>().ok()?))
+        });
+        Ok(match (saved, self.segments.front()) {
+            (Some((seq, offset)), Some(head)) if seq == head.seq => offset.min(head.bytes),
+            (None, _) => {
+                warn!("Ignoring unreadable spool head file {}", path.display());
+                0
+            }
+            _ => 0,
+        })
+    }
+
+    /// Saves the read position, replacing the old file atomically.
+    fn write_head(&self) -> Result<()> {
+        let seq = self.segments.front().map_or(0, |s| s.seq);
+        let path = self.dir.join(HEAD_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", HEAD_FILE));
+        fs::write(&tmp, format!("{} {}\n", seq, self.head_offset))
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.segments.len() {
+            0 => true,
+            1 => self.head_offset >= self.segments[0].bytes,
+            _ => false,
+        }
+    }
+
+    fn status(&self) -> SpoolStatus {
+        SpoolStatus {
+            empty: self.is_empty(),
+            bytes: self.total_bytes,
+            segments: self.segments.len(),
+        }
+    }
+
+    /// Appends encoded records, one per line. Returns how many were dropped
+    /// because the spool is full.
+    fn append(&mut self, lines: &[Vec<u8>]) -> Result<u64> {
+        let mut dropped = 0;
+        for line in lines {
+            let len = line.len() as u64;
+            if self.total_bytes + len > self.max_bytes {
+                dropped += 1;
+                continue;
+            }
+
+            let tail_full = self
+                .segments
+                .back()
+                .is_none_or(|s| s.bytes >= self.segment_bytes);
+            if self.tail.is_none() || tail_full {
+                if let Some(tail) = self.tail.as_mut() {
+                    tail.flush()?;
+                }
+                let seq = self.segments.back().map_or(0, |s| s.seq + 1);
+                let path = self.path(seq);
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| {
+                        format!("Failed to create spool segment {}", path.display())
+                    })?;
+                self.tail = Some(BufWriter::new(file));
+                self.segments.push_back(Segment { seq, bytes: 0 });
+            }
+
+            let tail = self.tail.as_mut().expect("tail segment is open");
+            tail.write_all(line)?;
+            self.segments.back_mut().expect("tail segment exists").bytes += len;
+            self.total_bytes += len;
+        }
+        if let Some(tail) = self.tail.as_mut() {
+            tail.flush()?;
+        }
+        Ok(dropped)
+    }
+
+    /// Reads up to `max` records from the head without consuming them. Returns
+    /// the records, the number of unreadable lines skipped and the offset to
+    /// pass to [`Spool::ack`].
+    fn peek(&mut self, max: usize) -> Result<(Vec<BybitOTT>, u64, u64)> {
+        let Some(head) = self.segments.front() else {
+            return Ok((Vec::new(), 0, 0));
+        };
+        let path = self.path(head.seq);
+        let mut file = File::open(&path)
+            .with_context(|| format!("Failed to open spool segment {}", path.display()))?;
+        file.seek(SeekFrom::Start(self.head_offset))?;
+        let mut reader = BufReader::new(file);
+
+        let mut records = Vec::new();
+        let mut corrupt = 0;
+        let mut offset = self.head_offset;
+        let mut line = String::new();
+        while records.len() < max && offset < head.bytes {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                warn!(
+                    "Spool segment {} ends early, skipping its tail",
+                    path.display()
+                );
+                offset = head.bytes;
+                break;
+            }
+            offset += read as u64;
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!(
+                        "Skipping unreadable spooled record in {}: {}",
+                        path.display(),
+                        e
+                    );
+                    corrupt += 1;
+                }
+            }
+        }
+        Ok((records, corrupt, offset))
+    }
+
+    /// Consumes the head up to `offset`, deleting segments that were fully drained.
+    fn ack(&mut self, offset: u64) -> Result<()> {
+        self.head_offset = offset;
+        while let Some(head) = self.segments.front() {
+            if self.head_offset < head.bytes {
+                break;
+            }
+            if self.segments.len() == 1 {
+                self.tail = None;
+            }
+            let path = self.path(head.seq);
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove spool segment {}", path.display()))?;
+            self.total_bytes -= head.bytes;
+            self.segments.pop_front();
+            self.head_offset = 0;
+        }
+        self.write_head()
+    }
+}
+
+/// Wraps a sink with a disk spool, see the module docs.
+pub struct SpoolingSink {
+    name: String,
+    factory: SinkFactory,
+    inner: Option<Box<dyn Sink>>,
+    /// Only locked on blocking threads by `spool_io`, one call at a time.
+    spool: Arc<Mutex<Spool>>,
+    /// What the runtime side reads instead of locking `spool`.
+    status: SpoolStatus,
+    /// Records handed to `inner` since its last successful flush.
+    unconfirmed: Vec<Arc<BybitOTT>>,
+    checkpoint_interval: Duration,
+    last_checkpoint: Instant,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_retry: Instant,
+    metrics: SpoolMetrics,
+    last_metrics: Instant,
+}
+
+impl SpoolingSink {
+    pub fn new(config: &SpoolConfig, factory: SinkFactory) -> Result<Self> {
+        let inner = factory()?;
+        let spool = Spool::open(config)?;
+        if !spool.is_empty() {
+            info!(
+                sink = inner.name(),
+                bytes = spool.total_bytes,
+                "Found spooled records from a previous run, draining them first"
+            );
+        }
+        Ok(Self {
+            name: inner.name().to_string(),
+            factory,
+            inner: Some(inner),
+            status: spool.status(),
+            spool: Arc::new(Mutex::new(spool)),
+            unconfirmed: Vec::new(),
+            checkpoint_interval: Duration::from_secs(config.checkpoint_secs),
+            last_checkpoint: Instant::now(),
+            max_backoff: Duration::from_secs(config.max_backoff_secs),
+            backoff: INITIAL_BACKOFF,
+            next_retry: Instant::now(),
+            metrics: SpoolMetrics::default(),
+            last_metrics: Instant::now(),
+        })
+    }
+
+    /// Runs segment file I/O on a blocking thread and refreshes `status`.
+    async fn spool_io<R: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut Spool) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let spool = self.spool.clone();
+        let (result, status) = tokio::task::spawn_blocking(move || {
+            let mut spool = spool.lock().expect("spool lock poisoned");
+            let result = f(&mut spool);
+            (result, spool.status())
+        })
+        .await
+        .context("Spool I/O panicked")?;
+        self.status = status;
+        result
+    }
+
+    async fn spool_records(&mut self, records: &[Arc<BybitOTT>]) -> Result<()> {
+        let lines = records
+            .iter()
+            .map(|record| {
+                let mut line = serde_json::to_vec(record.as_ref())?;
+                line.push(b'\n');
+                Ok(line)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let count = lines.len() as u64;
+        let dropped = self.spool_io(move |spool| spool.append(&lines)).await?;
+        self.metrics.spooled_records += count - dropped;
+        if dropped > 0 {
+            let before = self.metrics.dropped_records;
+            self.metrics.dropped_records += dropped;
+            if (before + 1).next_power_of_two() <= self.metrics.dropped_records {
+                error!(
+                    sink = %self.name,
+                    dropped = self.metrics.dropped_records,
+                    "Spool is full, dropping records"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the broken sink and moves everything it did not confirm to the spool.
+    async fn fail(&mut self, e: anyhow::Error) -> Result<()> {
+        self.metrics.failures += 1;
+        error!(
+            sink = %self.name,
+            retry_in = ?self.backoff,
+            "Sink failed, spooling records: {:?}", e
+        );
+        self.inner = None;
+        self.next_retry = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        let unconfirmed = std::mem::take(&mut self.unconfirmed);
+        self.spool_records(&unconfirmed).await
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(());
+        };
+        match inner.flush().await {
+            Ok(()) => {
+                self.unconfirmed.clear();
+                self.last_checkpoint = Instant::now();
+                Ok(())
+            }
+            Err(e) => self.fail(e).await,
+        }
+    }
+
+    /// Sends spooled records to a (rebuilt) sink, a few batches at a time.
+    async fn drain(&mut self, max_steps: usize) -> Result<()> {
+        if Instant::now() < self.next_retry {
+            return Ok(());
+        }
+        if self.inner.is_none() {
+            match (self.factory)() {
+                Ok(inner) => self.inner = Some(inner),
+                Err(e) => return self.fail(e).await,
+            }
+        }
+        if self.status.empty {
+            return Ok(());
+        }
+
+        for _ in 0..max_steps {
+            let (records, corrupt, offset) = self.spool_io(|spool| spool.peek(DRAIN_BATCH)).await?;
+            self.metrics.corrupt_records += corrupt;
+            let count = records.len() as u64;
+            let inner = self.inner.as_mut().expect("sink was rebuilt");
+            let mut result = Ok(());
+            for record in records {
+                result = inner.write(Arc::new(record)).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+            if result.is_ok() {
+                result = inner.flush().await;
+            }
+            if let Err(e) = result {
+                // The batch is still at the head of the spool and will be retried.
+                return self.fail(e).await;
+            }
+            self.spool_io(move |spool| spool.ack(offset)).await?;
+            self.metrics.drained_records += count;
+
+            if self.status.empty {
+                info!(sink = %self.name, metrics = ?self.metrics, "Spool drained, sink recovered");
+                self.backoff = INITIAL_BACKOFF;
+                self.last_checkpoint = Instant::now();
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn log_metrics(&mut self) {
+        if self.last_metrics.elapsed() < METRICS_INTERVAL {
+            return;
+        }
+        self.last_metrics = Instant::now();
+        if !self.status.empty || self.metrics.dropped_records > 0 {
+            info!(
+                sink = %self.name,
+                spool_bytes = self.status.bytes,
+                spool_segments = self.status.segments,
+                metrics = ?self.metrics,
+                "Spool status"
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for SpoolingSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()> {
+        self.log_metrics();
+        let spooling = !self.status.empty;
+        if !spooling && let Some(inner) = self.inner.as_mut() {
+            let result = inner.write(record.clone()).await;
+            self.unconfirmed.push(record);
+            if let Err(e) = result {
+                return self.fail(e).await;
+            }
+            if self.last_checkpoint.elapsed() >= self.checkpoint_interval {
+                self.checkpoint().await?;
+            }
+            return Ok(());
+        }
+
+        // Keep order: once anything is spooled, new records queue behind it.
+        self.spool_records(std::slice::from_ref(&record)).await?;
+        self.drain(DRAIN_STEPS_PER_WRITE).await
+    }
+
+    /// Confirms or drains on time, so a quiet stream does not leave records
+    /// unconfirmed or the spool undrained.
+    async fn tick(&mut self) -> Result<()> {
+        self.log_metrics();
+        if !self.status.empty {
+            return self.drain(DRAIN_STEPS_PER_WRITE).await;
+        }
+        if self.last_checkpoint.elapsed() >= self.checkpoint_interval {
+            self.checkpoint().await?;
+        }
+        // Rebuilds a failed sink even when nothing was spooled.
+        self.drain(0).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.checkpoint().await?;
+        // Last chance before shutdown; whatever is left is drained on the next start.
+        self.next_retry = Instant::now();
+        self.drain(usize::MAX).await
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<()> {
+        self.flush().await?;
+        if !self.status.empty {
+            warn!(
+                sink = %self.name,
+                bytes = self.status.bytes,
+                "Closing with records left in the spool"
+            );
+        }
+        match self.inner.take() {
+            Some(inner) => inner.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bybit_rest::BybitOpenInterest;
+    use crate::parser::Decimal128;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use time::OffsetDateTime;
+
+    fn config(name: &str) -> SpoolConfig {
+        let dir = std::env::temp_dir().join(format!("spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        SpoolConfig {
+            dir,
+            max_bytes: 1 << 20,
+            segment_bytes: 1 << 20,
+            checkpoint_secs: 5,
+            max_backoff_secs: 60,
+        }
+    }
+
+    fn lines(count: usize) -> Vec<Vec<u8>> {
+        let mut line = serde_json::to_vec(&BybitOTT::OpenInterest(Vec::new())).unwrap();
+        line.push(b'\n');
+        vec![line; count]
+    }
+
+    #[test]
+    fn reopening_resumes_after_the_acked_records() {
+        let config = config("head");
+        let mut spool = Spool::open(&config).unwrap();
+        spool.append(&lines(10)).unwrap();
+        let (records, _, offset) = spool.peek(4).unwrap();
+        assert_eq!(records.len(), 4);
+        spool.ack(offset).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&config).unwrap();
+        assert_eq!(spool.head_offset, offset);
+        let (records, corrupt, _) = spool.peek(100).unwrap();
+        assert_eq!((records.len(), corrupt), (6, 0));
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn head_of_a_removed_segment_is_ignored() {
+        let config = SpoolConfig {
+            segment_bytes: 1,
+            ..config("rotated")
+        };
+        let mut spool = Spool::open(&config).unwrap();
+        spool.append(&lines(3)).unwrap();
+        let (_, _, offset) = spool.peek(1).unwrap();
+        spool.ack(offset).unwrap();
+        assert_eq!(spool.segments.len(), 2);
+        drop(spool);
+
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(spool.head_offset, 0);
+        assert_eq!(spool.segments.front().map(|s| s.seq), Some(1));
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    /// Records the symbols written to it and fails while `failing` is set.
+    struct MockSink {
+        failing: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Sink for MockSink {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        async fn write(&mut self, record: Arc<BybitOTT>) -> Result<()> {
+            anyhow::ensure!(!self.failing.load(Ordering::SeqCst), "mock sink is down");
+            let BybitOTT::OpenInterest(rows) = record.as_ref() else {
+                unreachable!("only open interest is written");
+            };
+            let mut written = self.written.lock().unwrap();
+            written.extend(rows.iter().map(|row| row.symbol.clone()));
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<()> {
+            anyhow::ensure!(!self.failing.load(Ordering::SeqCst), "mock sink is down");
+            Ok(())
+        }
+
+        async fn close(self: Box<Self>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(symbol: &str) -> Arc<BybitOTT> {
+        Arc::new(BybitOTT::OpenInterest(vec![BybitOpenInterest {
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            received_timestamp: OffsetDateTime::UNIX_EPOCH,
+            symbol: symbol.to_string(),
+            interval_time: "5min".to_string(),
+            open_interest: Decimal128::default(),
+        }]))
+    }
+
+    #[tokio::test]
+    async fn failed_records_are_drained_in_order_after_recovery() {
+        let config = config("recovery");
+        let failing = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let factory: SinkFactory = {
+            let (failing, written) = (failing.clone(), written.clone());
+            Box::new(move || {
+                Ok(Box::new(MockSink {
+                    failing: failing.clone(),
+                    written: written.clone(),
+                }) as Box<dyn Sink>)
+            })
+        };
+        let mut sink = SpoolingSink::new(&config, factory).unwrap();
+
+        sink.write(record("a")).await.unwrap();
+        failing.store(true, Ordering::SeqCst);
+        sink.write(record("b")).await.unwrap();
+        sink.write(record("c")).await.unwrap();
+        assert!(!sink.status.empty);
+        assert_eq!(sink.metrics.spooled_records, 3);
+
+        failing.store(false, Ordering::SeqCst);
+        sink.flush().await.unwrap();
+        assert!(sink.status.empty);
+        sink.write(record("d")).await.unwrap();
+
+        // "a" was never confirmed, so it is written again ahead of the rest.
+        assert_eq!(*written.lock().unwrap(), ["a", "a", "b", "c", "d"]);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+use crate::config::SupervisorConfig;
+use crate::queue;
+use anyhow::Result;
+use futures_util::FutureExt;
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::{AbortHandle, Id, JoinError, JoinSet};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts a fresh instance of a task, rebuilding whatever state it owns.
+pub type TaskFactory = Box<dyn FnMut() -> BoxFuture<'static, Result<()>> + Send>;
+
+/// Factory for a task that only ever runs once, like one holding the receiving
+/// end of a channel.
+pub fn once(future: impl Future<Output = Result<()>> + Send + 'static) -> TaskFactory {
+    let mut future = Some(future.boxed());
+    Box::new(move || future.take().unwrap_or_else(|| async { Ok(()) }.boxed()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Restarted with backoff after a failure; too many restarts is fatal.
+    Restart,
+    /// Restarted with backoff after every failure, never fatal. For sources
+    /// whose failures come from outside, like a network outage.
+    Reconnect,
+    /// Expected to finish; failing is fatal.
+    RunOnce,
+    /// Failures are reported but leave the rest of the pipeline running.
+    BestEffort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskHealth {
+    pub state: TaskState,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+pub type Health = Arc<Mutex<BTreeMap<String, TaskHealth>>>;
+
+struct Task {
+    name: String,
+    /// Shutdown order: stage 0 (the sources) is aborted first, later stages are
+    /// left to drain once everything upstream of them is gone.
+    stage: usize,
+    policy: Policy,
+    factory: Option<TaskFactory>,
+    abort: Option<AbortHandle>,
+    recent_restarts: Vec<Instant>,
+}
+
+/// Owns every pipeline task, restarts the ones that may be restarted and
+/// shuts the pipeline down in stage order when something fatal happens.
+pub struct Supervisor {
+    config: SupervisorConfig,
+    tasks: Vec<Task>,
+    ids: HashMap<Id, usize>,
+    set: JoinSet<Result<()>>,
+    health: Health,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> Self {
+        Self {
+            config,
+            tasks: Vec::new(),
+            ids: HashMap::new(),
+            set: JoinSet::new(),
+            health: Health::default(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        stage: usize,
+        policy: Policy,
+        factory: TaskFactory,
+    ) {
+        self.tasks.push(Task {
+            name: name.into(),
+            stage,
+            policy,
+            factory: Some(factory),
+            abort: None,
+            recent_restarts: Vec::new(),
+        });
+    }
+
+    fn set_health(&self, index: usize, state: TaskState, error: Option<String>) {
+        set_health(&self.health, &self.tasks[index].name, state, error);
+    }
+
+    fn spawn(&mut self, index: usize, delay: Duration) {
+        let Some(factory) = self.tasks[index].factory.as_mut() else {
+            return;
+        };
+        let future = factory();
+        let (health, name) = (self.health.clone(), self.tasks[index].name.clone());
+        let handle = self.set.spawn(async move {
+            tokio::time::sleep(delay).await;
+            set_health(&health, &name, TaskState::Running, None);
+            future.await
+        });
+        self.ids.insert(handle.id(), index);
+        self.tasks[index].abort = Some(handle);
+    }
+
+    /// Handles a finished task. Returns the error when the failure is fatal.
+    fn on_exit(&mut self, joined: Result<(Id, Result<()>), JoinError>) -> Option<anyhow::Error> {
+        let (id, outcome) = match joined {
+            Ok((id, outcome)) => (id, outcome),
+            Err(e) if e.is_cancelled() => (e.id(), Ok(())),
+            Err(e) => (e.id(), Err(anyhow::anyhow!("panicked: {}", e))),
+        };
+        let index = self.ids.remove(&id)?;
+        self.tasks[index].abort = None;
+        let name = self.tasks[index].name.clone();
+
+        let e = match outcome {
+            Ok(()) => {
+                info!(task = %name, "Task finished.");
+                self.set_health(index, TaskState::Finished, None);
+                return None;
+            }
+            Err(e) => e,
+        };
+        error!(task = %name, "Task failed: {:?}", e);
+
+        let task = &mut self.tasks[index];
+        match task.policy {
+            Policy::BestEffort => {
+                self.set_health(index, TaskState::Failed, Some(format!("{:#}", e)));
+                None
+            }
+            Policy::Restart if task.factory.is_some() => {
+                let window = Duration::from_secs(self.config.restart_window_secs);
+                task.recent_restarts.retain(|t| t.elapsed() < window);
+                if task.recent_restarts.len() >= self.config.max_restarts {
+                    self.set_health(index, TaskState::Failed, Some(format!("{:#}", e)));
+                    return Some(e.context(format!(
+                        "{} failed {} times within {:?}",
+                        name,
+                        self.config.max_restarts + 1,
+                        window
+                    )));
+                }
+                let delay = Duration::from_secs(1 << task.recent_restarts.len()).min(MAX_BACKOFF);
+                task.recent_restarts.push(Instant::now());
+                warn!(task = %name, ?delay, "Restarting task.");
+                self.set_health(index, TaskState::Restarting, Some(format!("{:#}", e)));
+                self.spawn(index, delay);
+                None
+            }
+            Policy::Reconnect if task.factory.is_some() => {
+                let window = Duration::from_secs(self.config.restart_window_secs);
+                task.recent_restarts.retain(|t| t.elapsed() < window);
+                let delay =
+                    Duration::from_secs(1 << task.recent_restarts.len().min(16)).min(MAX_BACKOFF);
+                task.recent_restarts.push(Instant::now());
+                warn!(task = %name, ?delay, "Reconnecting task.");
+                self.set_health(index, TaskState::Restarting, Some(format!("{:#}", e)));
+                self.spawn(index, delay);
+                None
+            }
+            Policy::Restart | Policy::Reconnect | Policy::RunOnce => {
+                self.set_health(index, TaskState::Failed, Some(format!("{:#}", e)));
+                Some(e.context(format!("{} failed", name)))
+            }
+        }
+    }
+
+    fn running(&self, stage: usize) -> bool {
+        self.tasks
+            .iter()
+            .any(|t| t.stage == stage && t.abort.is_some())
+    }
+
+    fn sources_finished(&self) -> bool {
+        self.tasks
+            .iter()
+            .filter(|t| t.stage == 0)
+            .all(|t| t.abort.is_none())
+    }
+
+    /// Runs until every source finished, a fatal failure or Ctrl-C, then shuts
+    /// the pipeline down. Returns the first fatal error.
+    pub async fn run(mut self) -> Result<()> {
+        if let Some(addr) = self.config.health_addr.clone() {
+            tokio::spawn(serve_health(addr, self.health.clone()));
+        }
+        for index in 0..self.tasks.len() {
+            self.spawn(index, Duration::ZERO);
+        }
+
+        let mut result = loop {
+            tokio::select! {
+                joined = self.set.join_next_with_id() => {
+                    let Some(joined) = joined else { break Ok(()) };
+                    if let Some(fatal) = self.on_exit(joined) {
+                        break Err(fatal);
+                    }
+                    if self.sources_finished() {
+                        info!("All sources finished.");
+                        break Ok(());
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Interrupted.");
+                    break Ok(());
+                }
+            }
+        };
+
+        info!("Shutting down pipeline.");
+        let last_stage = self.tasks.iter().map(|t| t.stage).max().unwrap_or(0);
+        for stage in 0..=last_stage {
+            for task in self.tasks.iter_mut().filter(|t| t.stage == stage) {
+                // Dropping the factory releases the channel ends it holds.
+                task.factory = None;
+                if stage == 0
+                    && let Some(abort) = &task.abort
+                {
+                    abort.abort();
+                }
+            }
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while self.running(stage) {
+                match tokio::time::timeout_at(deadline, self.set.join_next_with_id()).await {
+                    Ok(Some(joined)) => {
+                        if let Some(e) = self.on_exit(joined)
+                            && result.is_ok()
+                        {
+                            result = Err(e);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        warn!(stage, "Tasks did not stop in time, aborting them.");
+                        for task in self.tasks.iter().filter(|t| t.stage == stage) {
+                            if let Some(abort) = &task.abort {
+                                abort.abort();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        info!("Pipeline stopped.");
+        result
+    }
+}
+
+fn set_health(health: &Health, name: &str, state: TaskState, error: Option<String>) {
+    let mut health = health.lock().expect("health lock poisoned");
+    let entry = health.entry(name.to_string()).or_insert(TaskHealth {
+        state,
+        restarts: 0,
+        last_error: None,
+    });
+    entry.state = state;
+    if state == TaskState::Restarting {
+        entry.restarts += 1;
+    }
+    if error.is_some() {
+        entry.last_error = error;
+    }
+}
+
+/// Answers requests on `addr` with the task health as JSON: 200 while every
+/// task is running or finished, 503 otherwise. `GET /channels` gives the depth
+/// and drop counters of the pipeline channels instead.
+async fn serve_health(addr: String, health: Health) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind health endpoint {}: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("Health endpoint listening on {}.", addr);
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let health = health.clone();
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let read = stream.read(&mut request).await.unwrap_or(0);
+            let (healthy, body) = if request[..read].starts_with(b"GET /channels") {
+                (true, queue::metrics_json())
+            } else {
+                let health = health.lock().expect("health lock poisoned");
+                let healthy = health
+                    .values()
+                    .all(|h| matches!(h.state, TaskState::Running | TaskState::Finished));
+                (healthy, serde_json::to_string(&*health).unwrap_or_default())
+            };
+            let status = match healthy {
+                true => "200 OK",
+                false => "503 Service Unavailable",
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -1,60 +1,104 @@
-use crate::bybit_orderbook::BybitOrderbook;
-use crate::bybit_ticker::BybitTicker;
-use crate::bybit_trades::BybitTrades;
 use crate::parser::BybitOTT;
-use anyhow::Result;
-use clickhouse::{self, inserter::Inserter};
-use tokio::sync::mpsc::Receiver;
-use tracing::info;
+use crate::queue;
+use crate::sink::Sink;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, warn};
 
+const SINK_TICK: Duration = Duration::from_secs(1);
+
+struct SinkHandle {
+    name: String,
+    tx: Sender<Arc<BybitOTT>>,
+    task: JoinHandle<Result<()>>,
+    dropped: u64,
+}
+
+async fn run_sink(mut sink: Box<dyn Sink>, mut rx: Receiver<Arc<BybitOTT>>) -> Result<()> {
+    info!("Sink {} started.", sink.name());
+    let mut tick = tokio::time::interval(SINK_TICK);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            record = rx.recv() => match record {
+                Some(record) => sink.write(record).await?,
+                None => break,
+            },
+            _ = tick.tick() => sink.tick().await?,
+        }
+    }
+    sink.flush().await?;
+    sink.close().await
+}
+
+fn spawn_sink(sink: Box<dyn Sink>, queue: usize) -> SinkHandle {
+    let (tx, rx) = mpsc::channel(queue);
+    SinkHandle {
+        name: sink.name().to_string(),
+        tx,
+        task: tokio::spawn(run_sink(sink, rx)),
+        dropped: 0,
+    }
+}
+
+/// Fans records out to every sink. The first sink is primary: it applies
+/// backpressure and its failure stops the writer. Secondary sinks get records
+/// only while their queue has room, so a slow or failed one cannot stall the primary.
 pub async fn async_write(
-    mut writer_rx: Receiver<BybitOTT>,
-    mut orderbook_inserter: Inserter<BybitOrderbook>,
-    mut trades_inserter: Inserter<BybitTrades>,
-    mut ticker_inserter: Inserter<BybitTicker>,
+    writer_rx: &mut queue::Receiver<BybitOTT>,
+    sinks: Vec<(Box<dyn Sink>, usize)>,
 ) -> Result<()> {
     info!("Writer task started.");
 
-    while let Some(to_insert) = writer_rx.recv().await {
-        match to_insert {
-            BybitOTT::BybitTicker(ticker) => {
-                ticker_inserter.write(&ticker).await?;
-                let stats = ticker_inserter.commit().await?;
-                if stats.rows > 0 {
-                    info!(
-                        target_db = "ticker_raw_ml",
-                        rows = stats.rows,
-                        "Data committed:"
-                    );
-                }
-            }
-            BybitOTT::BybitOrderbook(orderbook) => {
-                for order in orderbook {
-                    orderbook_inserter.write(&order).await?;
-                }
-                let stats = orderbook_inserter.commit().await?;
-                if stats.rows > 0 {
-                    info!(
-                        target_db = "orderbook_raw_ml",
-                        rows = stats.rows,
-                        "Data committed:"
+    let mut sinks = sinks.into_iter();
+    let (primary, primary_queue) = sinks.next().context("No sinks configured")?;
+    let primary = spawn_sink(primary, primary_queue);
+    let mut secondaries: Vec<SinkHandle> =
+        sinks.map(|(sink, queue)| spawn_sink(sink, queue)).collect();
+
+    while let Some(record) = writer_rx.recv().await {
+        let record = Arc::new(record);
+
+        secondaries.retain_mut(|sink| match sink.tx.try_send(record.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                sink.dropped += 1;
+                if sink.dropped.is_power_of_two() {
+                    warn!(
+                        sink = %sink.name,
+                        dropped = sink.dropped,
+                        "Secondary sink is falling behind, dropping records"
                     );
                 }
+                true
             }
-            BybitOTT::BybitTrades(trades) => {
-                for trade in trades {
-                    trades_inserter.write(&trade).await?;
-                }
-                let stats = trades_inserter.commit().await?;
-                if stats.rows > 0 {
-                    info!(
-                        target_db = "trades_raw_ml",
-                        rows = stats.rows,
-                        "Data committed:"
-                    );
-                }
+            Err(TrySendError::Closed(_)) => {
+                error!(sink = %sink.name, "Secondary sink stopped, detaching it");
+                false
             }
+        });
+
+        if primary.tx.send(record).await.is_err() {
+            break;
+        }
+    }
+
+    for sink in secondaries {
+        drop(sink.tx);
+        match sink.task.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(sink = %sink.name, "Secondary sink failed: {:?}", e),
+            Err(e) => error!(sink = %sink.name, "Secondary sink panicked: {:?}", e),
         }
     }
-    Ok(())
+    drop(primary.tx);
+    primary
+        .task
+        .await
+        .with_context(|| format!("Primary sink {} panicked", primary.name))?
+        .with_context(|| format!("Primary sink {} failed", primary.name))
 }